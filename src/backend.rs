@@ -0,0 +1,270 @@
+//! Transport-independent handling of everything the backend receives and publishes.
+//! The `lidar2d-backend` executable is essentially a loop around [`handle_incoming`] and
+//! [`update_smoothed_outputs`].
+
+use log::{error, info};
+
+pub use crate::systems::SmoothedOutputs;
+
+use crate::{
+    backend_config::BackendConfig,
+    encoding::encode,
+    systems::{automasking::handle_automask_message, presence::publish_presence_change, Systems},
+    transport::{Incoming, Outgoing, Transport},
+    Point2D,
+};
+
+/// Handle a single message received via the Transport
+pub fn handle_incoming(
+    message: Incoming,
+    config: &mut BackendConfig,
+    transport: &mut dyn Transport,
+    systems: &mut Systems,
+    config_file_path: &str,
+) {
+    match message {
+        Incoming::Scans { serial, samples } => handle_scans_message(
+            &serial,
+            &samples,
+            config,
+            transport,
+            systems,
+            config_file_path,
+        ),
+        Incoming::SaveConfig(payload) => {
+            // Commands may come from untrusted clients (e.g. via WebSocket), so a bad
+            // config is ignored rather than being fatal
+            match config.handle_save_message(
+                transport,
+                &payload,
+                &mut systems.position_remapping,
+                config_file_path,
+            ) {
+                Ok(()) => {
+                    info!("New config was received and saved; must update systems now...");
+                    systems.reset(config);
+                }
+                Err(e) => error!("Ignoring config that failed to update and save: {}", e),
+            }
+        }
+        Incoming::RequestAutoMask(payload) => {
+            info!("requestAutoMask message");
+            if let Ok(should_update_config) =
+                handle_automask_message(&payload, &mut systems.automask_samplers, config)
+            {
+                if should_update_config {
+                    if let Err(e) = config.save_and_republish(transport, config_file_path) {
+                        error!("Failed to save and republish config: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn handle_scans_message(
+    serial: &str,
+    scans: &[Point2D],
+    config: &mut BackendConfig,
+    transport: &mut dyn Transport,
+    systems: &mut Systems,
+    config_file_path: &str,
+) {
+    let update = systems.process_scan(serial, scans, config);
+
+    if !config.skip_some_outputs {
+        let clusters = systems.clustering_system.clusters();
+        let payload = encode(clusters, config.encoding_for(Outgoing::Clusters))
+            .expect("failed to serialize clusters");
+        transport
+            .send(Outgoing::Clusters, &payload)
+            .expect("failed to publish clusters");
+
+        if let Some(tracked_points) = &update.tracked_points {
+            let raw_points: Vec<Point2D> = tracked_points.iter().map(|c| (c.x, c.y)).collect();
+
+            let payload = encode(&raw_points, config.encoding_for(Outgoing::TrackedPoints))
+                .expect("failed to serialize tracked points");
+            transport
+                .send(Outgoing::TrackedPoints, &payload)
+                .expect("failed to publish tracked points");
+        }
+    }
+
+    // If an unknown device was found (and added), or masking was updated, re-publish the config
+    if update.config_changed() {
+        config
+            .save_and_republish(transport, config_file_path)
+            .expect("failed to save and republish config");
+    }
+}
+
+/// If it is time to do so, update smoothing (see [`Systems::update_smoothed`]) and publish
+/// the smoothed points, plus anything that depends on them (movement, presence, etc.).
+/// Returns None if there was nothing to do.
+pub fn update_smoothed_outputs(
+    config: &BackendConfig,
+    transport: &mut dyn Transport,
+    systems: &mut Systems,
+) -> Option<SmoothedOutputs> {
+    if config.smoothing_disable
+        || systems.smoothing_system.get_elapsed().as_millis()
+            <= config.smoothing_update_interval as u128
+    {
+        return None;
+    }
+
+    let outputs = systems.update_smoothed(config, config.smoothing_update_interval);
+    publish_smoothed_outputs(&outputs, config, transport);
+    Some(outputs)
+}
+
+/// Publish everything in the given outputs that is due to be sent
+pub fn publish_smoothed_outputs(
+    outputs: &SmoothedOutputs,
+    config: &BackendConfig,
+    transport: &mut dyn Transport,
+) {
+    if let Some(active_smoothed_points) = &outputs.smoothed_points {
+        let payload = encode(
+            active_smoothed_points,
+            config.encoding_for(Outgoing::SmoothedTrackedPoints),
+        )
+        .expect("failed to serialize smoothed tracking points");
+        transport
+            .send(Outgoing::SmoothedTrackedPoints, &payload)
+            .expect("failed to publish smoothed tracking points");
+    }
+
+    if let Some(remapped_points) = &outputs.remapped_points {
+        let payload = encode(
+            remapped_points,
+            config.encoding_for(Outgoing::SmoothedRemappedPoints),
+        )
+        .expect("failed to serialize smoothed remapped points");
+        transport
+            .send(Outgoing::SmoothedRemappedPoints, &payload)
+            .expect("failed to publish smoothed remapped points");
+    }
+
+    if !outputs.reacquired.is_empty() {
+        let payload = encode(
+            &outputs.reacquired,
+            config.encoding_for(Outgoing::TrackReacquired),
+        )
+        .expect("failed to serialize re-acquired tracks");
+        transport
+            .send(Outgoing::TrackReacquired, &payload)
+            .expect("failed to publish re-acquired tracks");
+    }
+
+    for (region_name, points) in outputs.region_points.iter() {
+        let output = Outgoing::RegionSmoothedTrackedPoints(region_name.clone());
+        let payload = encode(points, config.encoding_for(output.clone()))
+            .expect("failed to serialize region smoothed points");
+        transport
+            .send(output, &payload)
+            .expect("failed to publish region smoothed points");
+    }
+
+    if let Some(movement_vector) = &outputs.movement {
+        let payload = encode(movement_vector, config.encoding_for(Outgoing::Movement))
+            .expect("failed to serialize movement vector");
+        transport
+            .send(Outgoing::Movement, &payload)
+            .expect("failed to publish movement vector");
+    }
+
+    if !outputs.behaviour_changes.is_empty() {
+        let payload = encode(
+            &outputs.behaviour_changes,
+            config.encoding_for(Outgoing::Behaviour),
+        )
+        .expect("failed to serialize behaviour changes");
+        transport
+            .send(Outgoing::Behaviour, &payload)
+            .expect("failed to publish behaviour changes");
+    }
+
+    if let Some(groups) = &outputs.groups {
+        let payload = encode(groups, config.encoding_for(Outgoing::Groups))
+            .expect("failed to serialize groups");
+        transport
+            .send(Outgoing::Groups, &payload)
+            .expect("failed to publish groups");
+    }
+
+    if let Some(stats) = &outputs.crowd_stats {
+        let payload = encode(stats, config.encoding_for(Outgoing::CrowdStats))
+            .expect("failed to serialize crowd stats");
+        transport
+            .send(Outgoing::CrowdStats, &payload)
+            .expect("failed to publish crowd stats");
+    }
+
+    if let Some(grid) = &outputs.heatmap {
+        let payload = encode(grid, config.encoding_for(Outgoing::Heatmap))
+            .expect("failed to serialize heatmap");
+        transport
+            .send(Outgoing::Heatmap, &payload)
+            .expect("failed to publish heatmap");
+    }
+
+    for changed_zone in outputs.changed_zones.iter() {
+        publish_presence_change(changed_zone, transport);
+    }
+}
+
+// pub fn handle_external_tracking_message(
+//     serial: &str,
+//     points: &[Point2D],
+//     config: &mut BackendConfig,
+//     tether_agent: &TetherAgent,
+//     systems: &mut Systems,
+//     outputs: &Outputs,
+//     config_file_path: &str,
+// ) {
+//     let Systems {
+//         clustering_system,
+//         perspective_transformer,
+//         smoothing_system,
+//         ..
+//     } = systems;
+
+//     let Outputs {
+//         config_output,
+//         clusters_output,
+//         tracking_output,
+//         ..
+//     } = outputs;
+
+//     // If an unknown device was found (and added), re-publish the Device config
+//     if let Some(()) = config.check_or_create_external_tracker(serial) {
+//         config
+//             .save_and_republish(tether_agent, config_output, config_file_path)
+//             .expect("failed to save and republish config");
+//     }
+
+//     if let Some(tracker) = config.get_external_tracker(serial) {
+//         clustering_system.update_from_external_tracker(points, tracker);
+//         let clusters = clustering_system.clusters();
+//         tether_agent
+//             .encode_and_publish(clusters_output, clusters)
+//             .expect("failed to publish clusters");
+
+//         if perspective_transformer.is_ready() {
+//             let points: Vec<Point2D> = clusters
+//                 .iter()
+//                 .map(|c| perspective_transformer.transform(&(c.x, c.y)).unwrap())
+//                 .collect();
+
+//             if let Ok(tracked_points) = perspective_transformer.filter_points_inside(&points) {
+//                 // Normal (unsmoothed) tracked points...
+//                 tether_agent
+//                     .encode_and_publish(tracking_output, &tracked_points)
+//                     .expect("failed to publish tracked points");
+//                 smoothing_system.update_tracked_points(&tracked_points);
+//             }
+//         }
+//     }
+// }
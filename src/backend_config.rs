@@ -1,16 +1,28 @@
 use indexmap::IndexMap;
 use log::{debug, error, info, warn};
 use std::{fmt::Error, fs};
-use tether_agent::{ChannelDefinition, TetherAgent};
 
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::systems::{
-    automasking::MaskThresholdMap,
-    position_remapping::{OriginLocation, PositionRemapping},
-    presence::Zone,
-    smoothing::EmptyListSendMode,
+use crate::{
+    encoding::{decode_any, encode, Encoding},
+    geometry_utils::point_in_polygon,
+    systems::{
+        automasking::MaskThresholdMap,
+        behaviour::BehaviourSettings,
+        clustering::LegPairSettings,
+        crowd_stats::CrowdStatsSettings,
+        groups::GroupSettings,
+        heatmap::HeatmapSettings,
+        movement::MovementMode,
+        occlusion::OcclusionSettings,
+        position_remapping::{OriginLocation, PositionRemapping, RemappedFrame},
+        presence::Zone,
+        smoothing::{EmptyListSendMode, IdPolicy, ReidentifySettings, SmoothingFilter},
+    },
+    transport::{Outgoing, Transport},
+    Point2D,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -63,6 +75,46 @@ pub type CornerPoints = (
     ConfigRectCornerPoint,
 );
 
+/// A polygon in world space (mm, i.e. the same coordinates as devices and the ROI corners)
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AreaPolygon {
+    #[serde(default)]
+    pub name: String,
+    /// Vertices, in order; the polygon is closed automatically
+    pub points: Vec<Point2D>,
+}
+
+impl AreaPolygon {
+    pub fn contains(&self, point: &Point2D) -> bool {
+        point_in_polygon(point, &self.points)
+    }
+}
+
+/// A control point for calibration: where a known marker on the floor appears in world
+/// space (source), and where it should be in the output (target), in the same units and
+/// frame as "smoothedTrackedPoints"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CalibrationPoint {
+    pub source: Point2D,
+    pub target: Point2D,
+}
+
+/// An additional region of interest, e.g. a second stage, with its own coordinate
+/// frame and its own "regionSmoothedTrackedPoints" output
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NamedRegion {
+    /// Used as the ID in the output topic; should be unique
+    pub name: String,
+    pub corners: CornerPoints,
+    pub origin_location: OriginLocation,
+    /// As per smoothingUseRealUnits, but for this region only
+    pub use_real_units: bool,
+    /// Drop points beyond this distance from the edges of the region (in its own units);
+    /// if not set, points outside are never dropped
+    pub ignore_outside_margin: Option<f32>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct BackendConfig {
@@ -71,6 +123,21 @@ pub struct BackendConfig {
     pub region_of_interest: Option<CornerPoints>,
     pub zones: Option<Vec<Zone>>,
 
+    /// If any are defined, clusters must be inside at least one of these areas to be tracked
+    #[serde(default)]
+    pub include_areas: Vec<AreaPolygon>,
+
+    /// Clusters inside any of these areas (e.g. pillars, a bar or a DJ booth) are never
+    /// tracked
+    #[serde(default)]
+    pub exclude_areas: Vec<AreaPolygon>,
+
+    /// Additional named regions of interest (within the main ROI), each with its own output.
+    /// These are the main smoothed points remapped into each region, so the same person has
+    /// the same ID everywhere.
+    #[serde(default)]
+    pub regions: Vec<NamedRegion>,
+
     /// Default min distance threshold (in mm) to use for unconfigured new devices
     pub default_min_distance_threshold: f32,
 
@@ -84,6 +151,11 @@ pub struct BackendConfig {
     /// Exclude clusters above this size (where size is bigger of height/width bounds, in mm)
     pub clustering_max_cluster_size: f32,
 
+    /// If set, pairs of leg-sized clusters (e.g. with LIDARs at ankle height) within a
+    /// stride of each other are merged into a single cluster, i.e. one person
+    #[serde(default)]
+    pub clustering_leg_pairing: Option<LegPairSettings>,
+
     // -------- SMOOTHING SETTINGS
     /// Flag to disable integrated time-based "smoothed tracking" output. Note that this will
     /// also disable presence detection + movement analysis.
@@ -113,6 +185,32 @@ pub struct BackendConfig {
     /// (1.0 is immediate, i.e. no smoothing, 0 is invalid)
     pub smoothing_lerp_factor: f32,
 
+    /// How smoothed points follow their targets: "Lerp" (default; uses
+    /// smoothingLerpFactor on every update), or a time-based filter which behaves the
+    /// same however often updates happen, e.g. `{ "Exponential": { "timeConstantMs": 100 } }`
+    /// or `{ "OneEuro": { "minCutoff": 1.0, "beta": 0.01 } }`
+    #[serde(default)]
+    pub smoothing_filter: SmoothingFilter,
+
+    /// How IDs are picked for new smoothed points: "LowestFree" (default), "Monotonic",
+    /// or e.g. `{ "Cooldown": { "cooldownMs": 10000 } }` so that IDs are not reused straight away
+    #[serde(default)]
+    pub smoothing_id_policy: IdPolicy,
+
+    /// If set, remember expired smoothed points for a while, so that someone who was
+    /// briefly lost (e.g. occluded) and reappears nearby keeps their ID; each time this
+    /// happens, it is published on "trackReacquired". The distance is in smoothing units,
+    /// so must be set accordingly if not using real units (see
+    /// [`ReidentifySettings::for_units`])
+    #[serde(default)]
+    pub smoothing_reidentify: Option<ReidentifySettings>,
+
+    /// If set, smoothed points that are hidden from every LIDAR (e.g. standing behind
+    /// someone else) are held, or coast along at their last velocity, for up to
+    /// `maxHoldMs` beyond the normal expiry time
+    #[serde(default)]
+    pub occlusion: Option<OcclusionSettings>,
+
     /// How to treat empty smoothed tracking points lists - either send an empty
     /// list "once", "never" or "always"
     pub smoothing_empty_send_mode: EmptyListSendMode,
@@ -127,10 +225,31 @@ pub struct BackendConfig {
 
     pub origin_location: OriginLocation,
 
+    /// If set, smoothed points are also published on "smoothedRemappedPoints", converted
+    /// into this (independent) coordinate frame
+    #[serde(default)]
+    pub smoothed_remapped_frame: Option<RemappedFrame>,
+
     pub enable_velocity: bool,
     pub enable_bearing: bool,
+
+    /// If above zero, each smoothed point also includes a "predicted" position: where it
+    /// will be after this many ms at its current velocity, clamped to the ROI. Useful for
+    /// compensating for rendering latency.
+    #[serde(default)]
+    pub prediction_lookahead_ms: u64,
+
     pub enable_range: bool,
 
+    // -------- CALIBRATION SETTINGS
+    /// Use a non-linear mapping through calibrationPoints (at least 3) instead of the
+    /// perspective transform of the ROI
+    #[serde(default)]
+    pub calibration_enabled: bool,
+
+    #[serde(default)]
+    pub calibration_points: Vec<CalibrationPoint>,
+
     // -------- PERSPECTIVE TRANSFORM SETTINGS
     /// By default, we drop tracking points (resolved clusters) that lie outside of the defined quad
     /// **(with a little margin for error; see perspectiveTransform.ignoreOutsideMargin)**;
@@ -152,9 +271,62 @@ pub struct BackendConfig {
     /// How often (ms) to send movement messages
     pub average_movement_interval: u64,
 
+    /// How the velocities of all smoothed points are combined: "Sum" (default), "Mean"
+    /// or "Median"
+    #[serde(default)]
+    pub average_movement_mode: MovementMode,
+
+    /// Weight each point by its size, for the "Mean" and "Median" modes
+    #[serde(default)]
+    pub average_movement_weight_by_size: bool,
+
+    /// Time constant (ms) for exponential smoothing of the movement over time;
+    /// 0 (default) for no smoothing
+    #[serde(default)]
+    pub average_movement_smoothing_ms: u64,
+
+    /// Movement slower than this speed is sent as zero
+    #[serde(default)]
+    pub average_movement_dead_zone: f32,
+
+    // -------- BEHAVIOUR SETTINGS
+    /// If set, classify each smoothed tracked point as stationary, walking, running or
+    /// loitering, and publish changes on "behaviour"
+    #[serde(default)]
+    pub behaviour: Option<BehaviourSettings>,
+
+    // -------- GROUP DETECTION SETTINGS
+    /// If set, find groups of smoothed tracked points that stay close together and move
+    /// alike, and publish them on "groups"
+    #[serde(default)]
+    pub groups: Option<GroupSettings>,
+
+    // -------- CROWD STATS SETTINGS
+    /// If set, publish aggregate measures (headcount, density, flow field, entry and exit
+    /// rates) on "crowdStats"
+    #[serde(default)]
+    pub crowd_stats: Option<CrowdStatsSettings>,
+
+    // -------- HEATMAP SETTINGS
+    /// If set, accumulate the time spent by smoothed tracked points in each cell of a grid
+    /// over the ROI, and publish it periodically on "heatmap"
+    #[serde(default)]
+    pub heatmap: Option<HeatmapSettings>,
+
     /// If enabled, skip publishing messages that are typically only used by the lidar2d-frontend
     /// Can reduce I/O load and improve broker performance
     pub skip_some_outputs: bool,
+
+    // -------- OUTPUT ENCODING SETTINGS
+    /// Encoding for all outputs (except presence, which is always a single raw byte),
+    /// unless overridden in outputEncodings
+    #[serde(default)]
+    pub output_encoding: Encoding,
+
+    /// Per-output overrides for the encoding, keyed by output name,
+    /// e.g. { "smoothedTrackedPoints": "Json" }
+    #[serde(default)]
+    pub output_encodings: IndexMap<String, Encoding>,
 }
 
 impl Default for BackendConfig {
@@ -164,39 +336,63 @@ impl Default for BackendConfig {
             // external_trackers: Vec::new(),
             region_of_interest: None,
             zones: None,
+            include_areas: Vec::new(),
+            exclude_areas: Vec::new(),
+            regions: Vec::new(),
             smoothing_use_real_units: true,
             default_min_distance_threshold: 20.,
             clustering_neighbourhood_radius: 200.,
             clustering_min_neighbours: 4,
             clustering_max_cluster_size: 2500.,
+            clustering_leg_pairing: None,
             smoothing_disable: false,
             smoothing_merge_radius: 100.,
             enable_auto_merge_radius: false,
             smoothing_wait_before_active_ms: 100,
             smoothing_expire_ms: 3000,
             smoothing_lerp_factor: 0.1,
+            smoothing_filter: SmoothingFilter::Lerp,
+            smoothing_id_policy: IdPolicy::LowestFree,
+            smoothing_reidentify: None,
+            occlusion: None,
             smoothing_empty_send_mode: EmptyListSendMode::Once,
             smoothing_update_interval: 16,
             origin_location: OriginLocation::Centre,
+            smoothed_remapped_frame: None,
+            calibration_enabled: false,
+            calibration_points: Vec::new(),
             transform_include_outside: false,
             transform_ignore_outside_margin: 0.,
             automask_scans_required: 60,
             automask_threshold_margin: 50.,
             enable_average_movement: false,
             average_movement_interval: 250,
+            average_movement_mode: MovementMode::Sum,
+            average_movement_weight_by_size: false,
+            average_movement_smoothing_ms: 0,
+            average_movement_dead_zone: 0.,
+            behaviour: None,
+            groups: None,
+            crowd_stats: None,
+            heatmap: None,
             enable_velocity: false,
             enable_bearing: false,
+            prediction_lookahead_ms: 0,
             enable_range: false,
             skip_some_outputs: false,
             offset_index: 0,
+            output_encoding: Encoding::MessagePack,
+            output_encodings: IndexMap::new(),
         }
     }
 }
 
 impl BackendConfig {
+    /// Replace this config with one received remotely, in any supported encoding
     pub fn parse_remote_config(&mut self, payload: &[u8]) -> Result<()> {
-        match rmp_serde::from_slice::<BackendConfig>(payload) {
-            Ok(config) => {
+        match decode_any::<BackendConfig>(payload) {
+            Ok((config, encoding)) => {
+                debug!("Remote config was encoded as {:?}", encoding);
                 *self = config;
                 Ok(())
             }
@@ -348,14 +544,33 @@ impl BackendConfig {
         self.region_of_interest.as_mut()
     }
 
+    /// Per-point velocity is needed if enabled for output, but also for anything derived
+    /// from it (prediction, re-identification, average movement, groups, crowd stats)
+    pub fn should_calculate_velocity(&self) -> bool {
+        self.enable_velocity
+            || self.prediction_lookahead_ms > 0
+            || self.smoothing_reidentify.is_some()
+            || self.occlusion.is_some_and(|occlusion| occlusion.coast)
+            || self.enable_average_movement
+            || self.groups.is_some()
+            || self.crowd_stats.is_some()
+    }
+
     pub fn zones(&self) -> Option<&[Zone]> {
         self.zones.as_deref()
     }
 
+    /// The encoding to use for the given output
+    pub fn encoding_for(&self, output: Outgoing) -> Encoding {
+        self.output_encodings
+            .get(output.name())
+            .copied()
+            .unwrap_or(self.output_encoding)
+    }
+
     pub fn handle_save_message(
         &mut self,
-        tether_agent: &TetherAgent,
-        config_output: &ChannelDefinition,
+        transport: &mut dyn Transport,
         payload: &[u8],
         position_remapping: &mut PositionRemapping,
         config_file_path: &str,
@@ -372,7 +587,7 @@ impl BackendConfig {
                 }
 
                 info!("Remote-provided config parsed OK; now save to disk and (re) publish");
-                self.save_and_republish(tether_agent, config_output, config_file_path)
+                self.save_and_republish(transport, config_file_path)
                 // Ok(())
             }
             Err(e) => Err(anyhow!("Handle save-message failure: {e}")),
@@ -381,18 +596,15 @@ impl BackendConfig {
 
     pub fn save_and_republish(
         &self,
-        tether_agent: &TetherAgent,
-        config_output: &ChannelDefinition,
+        transport: &mut dyn Transport,
         config_file_path: &str,
     ) -> Result<()> {
-        info!("Saving config to disk and re-publishing via Tether...");
+        info!("Saving config to disk and re-publishing...");
         self.write_config_to_file(config_file_path)
-            .expect("failed to save to disk");
+            .map_err(|_| anyhow!("failed to save to disk"))?;
 
-        let payload = rmp_serde::to_vec(self).expect("failed to serialize config");
-        tether_agent
-            .send(config_output, Some(&payload))
-            .expect("failed to publish config");
+        let payload = encode(self, self.encoding_for(Outgoing::Config))?;
+        transport.send(Outgoing::Config, &payload)?;
         Ok(())
     }
 }
@@ -434,3 +646,143 @@ pub fn load_config_from_file(config_file_path: &str) -> Result<BackendConfig> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_config_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("lidar2d-{}-{}.json", name, std::process::id()))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn example_config() -> BackendConfig {
+        let mut config = BackendConfig {
+            region_of_interest: Some((
+                ConfigRectCornerPoint::new(0, 0., 0.),
+                ConfigRectCornerPoint::new(1, 4000., 0.),
+                ConfigRectCornerPoint::new(2, 4000., 3000.),
+                ConfigRectCornerPoint::new(3, 0., 3000.),
+            )),
+            origin_location: OriginLocation::Corner,
+            smoothing_empty_send_mode: EmptyListSendMode::Always,
+            smoothing_filter: SmoothingFilter::OneEuro {
+                min_cutoff: 1.,
+                beta: 0.01,
+            },
+            smoothing_id_policy: IdPolicy::Cooldown { cooldown_ms: 5000 },
+            offset_index: 1,
+            ..Default::default()
+        };
+        config.check_or_create_device("abc", 50.);
+        let mut mask = IndexMap::new();
+        mask.insert("90".to_string(), 1234.);
+        config.update_device_masking(&mask, "abc").unwrap();
+        config
+    }
+
+    fn as_json(config: &BackendConfig) -> serde_json::Value {
+        serde_json::to_value(config).unwrap()
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let path = temp_config_path("round-trip");
+        let config = example_config();
+        config.write_config_to_file(&path).unwrap();
+        let loaded = load_config_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(as_json(&loaded), as_json(&config));
+        let device = loaded.get_device("abc").unwrap();
+        assert_eq!(device.min_distance_threshold, 50.);
+        assert_eq!(
+            device.scan_mask_thresholds.as_ref().unwrap().get("90"),
+            Some(&1234.)
+        );
+    }
+
+    #[test]
+    fn test_missing_file_gives_default() {
+        let path = temp_config_path("missing");
+        let loaded = load_config_from_file(&path).unwrap();
+        assert_eq!(as_json(&loaded), as_json(&BackendConfig::default()));
+    }
+
+    #[test]
+    fn test_invalid_file_is_error() {
+        let path = temp_config_path("invalid");
+        fs::write(&path, "{ not valid").unwrap();
+        let result = load_config_from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_remote_config_round_trip() {
+        let config = example_config();
+        let payload = rmp_serde::to_vec(&config).unwrap();
+
+        let mut received = BackendConfig::default();
+        received.parse_remote_config(&payload).unwrap();
+        assert_eq!(as_json(&received), as_json(&config));
+
+        assert!(received.parse_remote_config(&[0xc1]).is_err());
+    }
+
+    #[test]
+    fn test_remote_config_any_encoding() {
+        let config = example_config();
+        for encoding in crate::encoding::ALL_ENCODINGS {
+            let payload = encode(&config, encoding).unwrap();
+            let mut received = BackendConfig::default();
+            received.parse_remote_config(&payload).unwrap();
+            assert_eq!(as_json(&received), as_json(&config));
+        }
+    }
+
+    #[test]
+    fn test_smoothing_filter_from_json() {
+        let mut config = as_json(&BackendConfig::default());
+        config["smoothingFilter"] = serde_json::json!({ "Exponential": { "timeConstantMs": 100 } });
+        let config: BackendConfig = serde_json::from_value(config).unwrap();
+        assert_eq!(
+            config.smoothing_filter,
+            SmoothingFilter::Exponential {
+                time_constant_ms: 100.
+            }
+        );
+    }
+
+    #[test]
+    fn test_encoding_for_output() {
+        let mut config = BackendConfig {
+            output_encoding: Encoding::Cbor,
+            ..Default::default()
+        };
+        config
+            .output_encodings
+            .insert("smoothedTrackedPoints".into(), Encoding::Json);
+        assert_eq!(
+            config.encoding_for(Outgoing::SmoothedTrackedPoints),
+            Encoding::Json
+        );
+        assert_eq!(config.encoding_for(Outgoing::Clusters), Encoding::Cbor);
+    }
+
+    #[test]
+    fn test_check_or_create_device() {
+        let mut config = BackendConfig::default();
+        assert!(config.check_or_create_device("one", 10.).is_some());
+        assert!(config.check_or_create_device("one", 10.).is_none());
+        assert!(config.check_or_create_device("two", 10.).is_some());
+        assert_eq!(config.devices().len(), 2);
+        assert_ne!(config.devices()[0].colour, config.devices()[1].colour);
+
+        assert!(config
+            .update_device_masking(&IndexMap::new(), "unknown")
+            .is_err());
+    }
+}
@@ -1,10 +1,16 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
 
-use clap::{command, Parser};
+use clap::Parser;
 
 // Some defaults; some of which can be overriden via CLI args
 const CONFIG_FILE_PATH: &str = "./tracking_config.json";
 const TETHER_HOST: std::net::IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const OSC_HOST: std::net::IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const TUIO_HOST: std::net::IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+const WEBSOCKET_HOST: std::net::IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -33,6 +39,52 @@ pub struct Cli {
     #[arg(long = "tether.password")]
     pub tether_password: Option<String>,
 
+    /// Mirror smoothed tracked points, presence and movement as OSC bundles (UDP)
+    #[arg(long = "osc.enable")]
+    pub osc_enable: bool,
+
+    /// The IP address to send OSC messages to
+    #[arg(long = "osc.host", default_value_t=OSC_HOST)]
+    pub osc_host: std::net::IpAddr,
+
+    /// The UDP port to send OSC messages to
+    #[arg(long = "osc.port", default_value_t = 9000)]
+    pub osc_port: u16,
+
+    /// Prefix for all OSC addresses, e.g. "/lidar" gives "/lidar/point/<id>"
+    #[arg(long="osc.prefix",default_value_t=String::from("/lidar"))]
+    pub osc_prefix: String,
+
+    /// Send smoothed tracked points as TUIO 1.1 cursors ("/tuio/2Dcur")
+    #[arg(long = "tuio.enable")]
+    pub tuio_enable: bool,
+
+    /// The IP address to send TUIO messages to
+    #[arg(long = "tuio.host", default_value_t=TUIO_HOST)]
+    pub tuio_host: std::net::IpAddr,
+
+    /// The UDP port to send TUIO messages to
+    #[arg(long = "tuio.port", default_value_t = 3333)]
+    pub tuio_port: u16,
+
+    /// Host a WebSocket server which streams outputs and scans as JSON, and accepts
+    /// config and automask commands
+    #[arg(long = "websocket.enable")]
+    pub websocket_enable: bool,
+
+    /// The IP address for the WebSocket server to listen on
+    #[arg(long = "websocket.host", default_value_t=WEBSOCKET_HOST)]
+    pub websocket_host: std::net::IpAddr,
+
+    /// The port for the WebSocket server to listen on
+    #[arg(long = "websocket.port", default_value_t = 8080)]
+    pub websocket_port: u16,
+
+    /// Whenever the heatmap is published (if configured), also write it to this file;
+    /// PNG if the path ends in ".png", otherwise CSV
+    #[arg(long = "heatmap.export")]
+    pub heatmap_export: Option<PathBuf>,
+
     #[arg(long = "loglevel",default_value_t=String::from("info"))]
     pub log_level: String,
 }
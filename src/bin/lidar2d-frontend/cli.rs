@@ -1,4 +1,4 @@
-use clap::{command, Parser};
+use clap::Parser;
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -8,12 +8,18 @@ use tether_agent::{
 };
 use tether_lidar2d_consolidation::{
     backend_config::BackendConfig,
-    systems::{clustering::Cluster2D, position_remapping::calculate_dst_quad},
+    encoding::{decode_any, Encoding},
+    systems::{
+        clustering::Cluster2D, heatmap::HeatmapGrid, position_remapping::calculate_dst_quad,
+    },
     tracking::TrackedPoint2D,
     Point2D,
 };
 
-use crate::{cli::Cli, ui::render_ui};
+use crate::{
+    cli::Cli,
+    ui::{render_ui, roi_editing::SnapMode},
+};
 
 // use clap::Parser;
 
@@ -23,6 +29,8 @@ pub struct Inputs {
     pub clusters: ChannelDefinition,
     pub raw_tracked_points: ChannelDefinition,
     pub smoothed_tracked_points: ChannelDefinition,
+    pub heatmap: ChannelDefinition,
+    pub movement: ChannelDefinition,
 }
 
 pub struct Outputs {
@@ -39,20 +47,58 @@ pub enum EditingCorner {
     D,
 }
 
+impl EditingCorner {
+    /// The corner index (A-D as 0-3), if any
+    pub fn index(&self) -> Option<usize> {
+        match self {
+            EditingCorner::None => None,
+            EditingCorner::A => Some(0),
+            EditingCorner::B => Some(1),
+            EditingCorner::C => Some(2),
+            EditingCorner::D => Some(3),
+        }
+    }
+}
+
+/// Which kind of (world-space) tracking area polygon is being edited
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AreaKind {
+    Include,
+    Exclude,
+}
+
 pub struct Model {
     pub tether_agent: TetherAgent,
     pub inputs: Inputs,
     pub outputs: Outputs,
     pub backend_config: Option<BackendConfig>,
+    /// The encoding the backend used for its config; we reply (save config, etc.) using the same
+    pub backend_encoding: Encoding,
     pub calculated_dst_quad: Option<RectCorners>,
     /// Warning: these scan values are (angle,distance) for LIDAR devices, and (x,y) for External Trackers!
     pub scans: HashMap<String, Vec<(f32, f32)>>,
     pub clusters: Vec<Cluster2D>,
     pub raw_tracked_points: Vec<Point2D>,
     pub smoothed_tracked_points: Vec<TrackedPoint2D>,
+    /// The last occupancy heatmap published by the backend, if any
+    pub heatmap: Option<HeatmapGrid>,
+    /// The last average movement vector published by the backend
+    pub movement: Point2D,
     pub editing_corners: EditingCorner,
+    /// The ROI corner (A-D as 0-3) currently being dragged on the scan graph, if any
+    pub dragging_corner: Option<usize>,
+    /// The ROI corner under the pointer (as of the last frame), if any
+    pub hovered_corner: Option<usize>,
+    /// Keep the ROI rectangular while editing corners
+    pub roi_force_rectangle: bool,
+    pub roi_snap_mode: SnapMode,
+    /// The include/exclude area (by index) that clicks on the scan graph add points to
+    pub editing_area: Option<(AreaKind, usize)>,
+    /// The calibration point (by index) whose source is set by clicking on the scan graph
+    pub editing_calibration_point: Option<usize>,
     pub point_size: f32,
     pub show_graph_labels: bool,
+    pub show_heatmap: bool,
     pub is_editing: bool,
 }
 
@@ -86,6 +132,14 @@ impl Model {
                 .build(&mut tether_agent)
                 .expect("failed to create Input Plug");
 
+        let heatmap = ChannelOptionsBuilder::create_receiver("heatmap")
+            .build(&mut tether_agent)
+            .expect("failed to create Input Plug");
+
+        let movement = ChannelOptionsBuilder::create_receiver("movement")
+            .build(&mut tether_agent)
+            .expect("failed to create Input Plug");
+
         let config_output = ChannelOptionsBuilder::create_sender("saveLidarConfig")
             .build(&mut tether_agent)
             .expect("failed to create Output Plug");
@@ -102,20 +156,32 @@ impl Model {
                 clusters,
                 raw_tracked_points,
                 smoothed_tracked_points,
+                heatmap,
+                movement,
             },
             outputs: Outputs {
                 config: config_output,
                 request_automask,
             },
             backend_config: None,
+            backend_encoding: Encoding::MessagePack,
             is_editing: false,
             scans: HashMap::new(),
             clusters: Vec::new(),
             raw_tracked_points: Vec::new(),
             smoothed_tracked_points: Vec::new(),
+            heatmap: None,
+            movement: (0., 0.),
             editing_corners: EditingCorner::None,
+            dragging_corner: None,
+            hovered_corner: None,
+            roi_force_rectangle: false,
+            roi_snap_mode: SnapMode::Off,
+            editing_area: None,
+            editing_calibration_point: None,
             point_size: 2.0,
             show_graph_labels: true,
+            show_heatmap: true,
             calculated_dst_quad: None,
         }
     }
@@ -130,8 +196,9 @@ impl eframe::App for Model {
             work_done = true;
 
             if self.inputs.config.matches(topic) {
-                if let Ok(tracking_config) = rmp_serde::from_slice::<BackendConfig>(payload) {
+                if let Ok((tracking_config, encoding)) = decode_any::<BackendConfig>(payload) {
                     debug!("Got new Tracking Config: {:?}", tracking_config);
+                    self.backend_encoding = encoding;
                     if let Some(roi) = tracking_config.region_of_interest() {
                         self.calculated_dst_quad =
                             Some(calculate_dst_quad(roi, tracking_config.origin_location));
@@ -159,22 +226,34 @@ impl eframe::App for Model {
             }
 
             if self.inputs.clusters.matches(topic) {
-                if let Ok(clusters) = rmp_serde::from_slice::<Vec<Cluster2D>>(payload) {
+                if let Ok((clusters, _)) = decode_any::<Vec<Cluster2D>>(payload) {
                     self.clusters = clusters;
                 }
             }
 
             if self.inputs.raw_tracked_points.matches(topic) {
-                if let Ok(tracked_points) = rmp_serde::from_slice::<Vec<Point2D>>(payload) {
+                if let Ok((tracked_points, _)) = decode_any::<Vec<Point2D>>(payload) {
                     self.raw_tracked_points = tracked_points;
                 }
             }
 
             if self.inputs.smoothed_tracked_points.matches(topic) {
-                if let Ok(tracked_points) = rmp_serde::from_slice::<Vec<TrackedPoint2D>>(payload) {
+                if let Ok((tracked_points, _)) = decode_any::<Vec<TrackedPoint2D>>(payload) {
                     self.smoothed_tracked_points = tracked_points;
                 }
             }
+
+            if self.inputs.movement.matches(topic) {
+                if let Ok((movement, _)) = decode_any::<Point2D>(payload) {
+                    self.movement = movement;
+                }
+            }
+
+            if self.inputs.heatmap.matches(topic) {
+                if let Ok((heatmap, _)) = decode_any::<HeatmapGrid>(payload) {
+                    self.heatmap = Some(heatmap);
+                }
+            }
         }
 
         render_ui(ctx, self);
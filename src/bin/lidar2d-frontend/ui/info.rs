@@ -1,6 +1,6 @@
 use egui::{plot::Plot, Color32, Grid, RichText, Stroke, Ui};
 use nalgebra::Vector2;
-use tether_lidar2d_consolidation::{geometry_utils::distance, systems::movement::calculate};
+use tether_lidar2d_consolidation::geometry_utils::distance;
 
 use crate::model::Model;
 
@@ -32,7 +32,7 @@ pub fn render_info(model: &mut Model, ui: &mut Ui) {
             ui.separator();
             ui.heading("Average Movement");
 
-            let (mx, my) = calculate(&model.smoothed_tracked_points);
+            let (mx, my) = model.movement;
 
             let plot = Plot::new("averageMovement")
                 .height(ui.available_width())
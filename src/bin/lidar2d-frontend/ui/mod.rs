@@ -1,4 +1,5 @@
 mod info;
+pub mod roi_editing;
 mod scan_graph;
 mod tracking_graph;
 mod tracking_settings;
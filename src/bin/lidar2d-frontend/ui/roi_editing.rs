@@ -0,0 +1,258 @@
+//! Geometry helpers for editing the ROI on the scan graph: rectangle constraints and
+//! snapping corners to scan points or walls.
+
+use tether_lidar2d_consolidation::{
+    backend_config::{ConfigRectCornerPoint, CornerPoints},
+    geometry_utils::distance_points,
+    Point2D,
+};
+
+/// Minimum number of scan points near the pointer needed to detect a wall
+const MIN_WALL_POINTS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapMode {
+    Off,
+    /// Snap to the nearest scan point
+    ScanPoints,
+    /// Snap onto a line fitted through nearby scan points
+    Walls,
+}
+
+pub fn corners_to_points(roi: &CornerPoints) -> [Point2D; 4] {
+    let (a, b, c, d) = roi;
+    [a, b, c, d].map(|c| (c.x, c.y))
+}
+
+pub fn points_to_corners(points: [Point2D; 4]) -> CornerPoints {
+    let [a, b, c, d] = points;
+    (
+        ConfigRectCornerPoint::new(0, a.0, a.1),
+        ConfigRectCornerPoint::new(1, b.0, b.1),
+        ConfigRectCornerPoint::new(2, c.0, c.1),
+        ConfigRectCornerPoint::new(3, d.0, d.1),
+    )
+}
+
+/// The index of the corner closest to the point, if within the radius
+pub fn corner_near(corners: &[Point2D; 4], point: Point2D, radius: f32) -> Option<usize> {
+    corners
+        .iter()
+        .enumerate()
+        .map(|(index, c)| (index, distance_points(c, &point)))
+        .filter(|(_, d)| *d <= radius)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(index, _)| index)
+}
+
+fn sub(a: Point2D, b: Point2D) -> Point2D {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn add(a: Point2D, b: Point2D) -> Point2D {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+fn scale(a: Point2D, k: f32) -> Point2D {
+    (a.0 * k, a.1 * k)
+}
+
+fn dot(a: Point2D, b: Point2D) -> f32 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+fn normalise(a: Point2D) -> Point2D {
+    let length = dot(a, a).sqrt();
+    if length <= f32::EPSILON {
+        (1., 0.)
+    } else {
+        scale(a, 1. / length)
+    }
+}
+
+/// Unit vectors along AB, and perpendicular to it towards D
+fn rectangle_axes(corners: &[Point2D; 4]) -> (Point2D, Point2D) {
+    let [a, b, _c, d] = *corners;
+    let u = normalise(sub(b, a));
+    let perpendicular = (-u.1, u.0);
+    let v = if dot(perpendicular, sub(d, a)) >= 0. {
+        perpendicular
+    } else {
+        scale(perpendicular, -1.)
+    };
+    (u, v)
+}
+
+fn rectangle_from(a: Point2D, u: Point2D, v: Point2D, width: f32, height: f32) -> [Point2D; 4] {
+    let b = add(a, scale(u, width));
+    let d = add(a, scale(v, height));
+    [a, b, add(b, scale(v, height)), d]
+}
+
+/// Width (AB) and height (AD) of the ROI
+pub fn rectangle_size(corners: &[Point2D; 4]) -> (f32, f32) {
+    let [a, b, _c, d] = corners;
+    (distance_points(a, b), distance_points(a, d))
+}
+
+/// Turn the quad into a rectangle, keeping A, the direction of AB and the lengths of AB
+/// and AD
+pub fn make_rectangle(corners: &mut [Point2D; 4]) {
+    let (u, v) = rectangle_axes(corners);
+    let (width, height) = rectangle_size(corners);
+    *corners = rectangle_from(corners[0], u, v, width, height);
+}
+
+/// Resize the rectangle from corner A, keeping its orientation
+pub fn set_rectangle_size(corners: &mut [Point2D; 4], width: f32, height: f32) {
+    let (u, v) = rectangle_axes(corners);
+    *corners = rectangle_from(corners[0], u, v, width, height);
+}
+
+/// Move one corner; if `force_rectangle` is set, the opposite corner stays where it is
+/// and the others follow, keeping the current orientation
+pub fn move_corner(
+    corners: &mut [Point2D; 4],
+    index: usize,
+    point: Point2D,
+    force_rectangle: bool,
+) {
+    if !force_rectangle {
+        corners[index] = point;
+        return;
+    }
+    let (u, v) = rectangle_axes(corners);
+    let opposite = corners[(index + 2) % 4];
+    // Offsets of the corner along each axis, as per A (0,0), B (1,0), C (1,1), D (0,1)
+    let (su, sv) = [(0., 0.), (1., 0.), (1., 1.), (0., 1.)][index];
+    let delta = sub(point, opposite);
+    let width = dot(delta, u) / (2. * su - 1.);
+    let height = dot(delta, v) / (2. * sv - 1.);
+    let a = sub(
+        sub(opposite, scale(u, (1. - su) * width)),
+        scale(v, (1. - sv) * height),
+    );
+    *corners = rectangle_from(a, u, v, width, height);
+}
+
+/// Snap the point as per the mode, if there are suitable scan points within the radius;
+/// otherwise the point is returned unchanged
+pub fn snap(point: Point2D, scan_points: &[Point2D], radius: f32, mode: SnapMode) -> Point2D {
+    let nearby: Vec<Point2D> = scan_points
+        .iter()
+        .filter(|p| distance_points(p, &point) <= radius)
+        .cloned()
+        .collect();
+
+    match mode {
+        SnapMode::Off => point,
+        SnapMode::ScanPoints => nearby
+            .into_iter()
+            .min_by(|a, b| distance_points(a, &point).total_cmp(&distance_points(b, &point)))
+            .unwrap_or(point),
+        SnapMode::Walls => {
+            if nearby.len() < MIN_WALL_POINTS {
+                return point;
+            }
+            // Principal axis of the nearby points, i.e. a least-squares line fit
+            let count = nearby.len() as f32;
+            let mean = nearby
+                .iter()
+                .fold((0., 0.), |acc, p| add(acc, scale(*p, 1. / count)));
+            let (mut xx, mut xy, mut yy) = (0., 0., 0.);
+            for p in nearby.iter() {
+                let (dx, dy) = sub(*p, mean);
+                xx += dx * dx;
+                xy += dx * dy;
+                yy += dy * dy;
+            }
+            let angle = 0.5 * (2. * xy).atan2(xx - yy);
+            let direction = (angle.cos(), angle.sin());
+            add(mean, scale(direction, dot(sub(point, mean), direction)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Point2D, expected: Point2D) {
+        assert!(
+            distance_points(&actual, &expected) < 0.01,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    fn assert_corners(actual: &[Point2D; 4], expected: [Point2D; 4]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert_close(*a, e);
+        }
+    }
+
+    #[test]
+    fn test_corner_near() {
+        let corners = [(0., 0.), (100., 0.), (100., 100.), (0., 100.)];
+        assert_eq!(corner_near(&corners, (95., 3.), 10.), Some(1));
+        assert_eq!(corner_near(&corners, (50., 50.), 10.), None);
+    }
+
+    #[test]
+    fn test_make_rectangle() {
+        let mut corners = [(0., 0.), (100., 0.), (120., 70.), (10., 50.)];
+        make_rectangle(&mut corners);
+        let h = (10f32 * 10. + 50. * 50.).sqrt();
+        assert_corners(&corners, [(0., 0.), (100., 0.), (100., h), (0., h)]);
+
+        set_rectangle_size(&mut corners, 200., 50.);
+        assert_corners(&corners, [(0., 0.), (200., 0.), (200., 50.), (0., 50.)]);
+    }
+
+    #[test]
+    fn test_move_corner_keeps_rectangle() {
+        let mut corners = [(0., 0.), (100., 0.), (100., 100.), (0., 100.)];
+        move_corner(&mut corners, 1, (150., -20.), true);
+        assert_corners(
+            &corners,
+            [(0., -20.), (150., -20.), (150., 100.), (0., 100.)],
+        );
+
+        // A rotated rectangle stays rotated (45 degrees)
+        let s = 2f32.sqrt() / 2.;
+        let mut corners = [(0., 0.), (s, s), (0., 2. * s), (-s, s)];
+        move_corner(&mut corners, 2, (0., 4. * s), true);
+        assert_corners(
+            &corners,
+            [(0., 0.), (2. * s, 2. * s), (0., 4. * s), (-2. * s, 2. * s)],
+        );
+
+        // Without the constraint, only that corner moves
+        move_corner(&mut corners, 0, (1., 1.), false);
+        assert_close(corners[0], (1., 1.));
+        assert_close(corners[1], (2. * s, 2. * s));
+    }
+
+    #[test]
+    fn test_snap() {
+        // A wall along y = 1000, with some noise
+        let wall: Vec<Point2D> = (0..20)
+            .map(|i| (i as f32 * 50., 1000. + if i % 2 == 0 { 5. } else { -5. }))
+            .collect();
+
+        assert_close(snap((500., 900.), &wall, 200., SnapMode::Off), (500., 900.));
+        assert_close(
+            snap((510., 980.), &wall, 200., SnapMode::ScanPoints),
+            (500., 1005.),
+        );
+        let snapped = snap((510., 900.), &wall, 200., SnapMode::Walls);
+        assert!((snapped.0 - 510.).abs() < 5. && (snapped.1 - 1000.).abs() < 5.);
+
+        // Nothing nearby
+        assert_close(
+            snap((5000., 5000.), &wall, 200., SnapMode::Walls),
+            (5000., 5000.),
+        );
+    }
+}
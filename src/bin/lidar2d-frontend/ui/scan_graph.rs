@@ -1,17 +1,30 @@
 use colorsys::Rgb;
 use egui::{
-    plot::{MarkerShape, Plot, PlotPoint, PlotPoints, Points, Text},
+    plot::{Line, MarkerShape, Plot, PlotPoint, PlotPoints, Points, Text},
     Color32, InnerResponse, Ui,
 };
 use log::{debug, warn};
-use tether_lidar2d_consolidation::backend_config::ConfigRectCornerPoint;
+use std::collections::HashMap;
+use tether_lidar2d_consolidation::{
+    backend_config::{AreaPolygon, BackendConfig, ConfigRectCornerPoint},
+    systems::clustering::scan_sample_to_point,
+    Point2D,
+};
+
+use crate::model::{AreaKind, EditingCorner, Model};
 
-use crate::model::{EditingCorner, Model};
+use super::{
+    angle_samples_to_plot_points, draw_circle, draw_line,
+    roi_editing::{corner_near, corners_to_points, move_corner, points_to_corners, snap, SnapMode},
+};
 
-use super::{angle_samples_to_plot_points, draw_circle, draw_line};
+/// Corner handles can be grabbed within this fraction of the visible plot width
+const HANDLE_RADIUS_FRACTION: f64 = 0.015;
 
 pub fn render_scan_graph(model: &mut Model, ui: &mut Ui) {
     let markers_plot = Plot::new("scans")
+        // Don't pan the plot when dragging a ROI corner handle
+        .allow_drag(model.hovered_corner.is_none() && model.dragging_corner.is_none())
         .data_aspect(1.0)
         .height(500.)
         .include_y(10000.)
@@ -21,7 +34,7 @@ pub fn render_scan_graph(model: &mut Model, ui: &mut Ui) {
 
     let InnerResponse {
         response,
-        inner: (pointer_coordinate, _bounds),
+        inner: (pointer_coordinate, bounds),
         ..
     } = markers_plot.show(ui, |plot_ui| {
         if let Some(tracking_config) = &model.backend_config {
@@ -57,6 +70,67 @@ pub fn render_scan_graph(model: &mut Model, ui: &mut Ui) {
                 // all_points.push(cluster_to_plot_points(cluster, radius_px.max(4.0)));
             }
 
+            for (kind, areas, colour) in [
+                (
+                    AreaKind::Include,
+                    &tracking_config.include_areas,
+                    Color32::LIGHT_GREEN,
+                ),
+                (
+                    AreaKind::Exclude,
+                    &tracking_config.exclude_areas,
+                    Color32::LIGHT_RED,
+                ),
+            ] {
+                for (index, area) in areas.iter().enumerate() {
+                    let is_selected = model.editing_area == Some((kind, index));
+                    plot_ui.line(draw_polygon(area, colour, is_selected));
+                    if is_selected {
+                        plot_ui.points(
+                            Points::new(PlotPoints::new(
+                                area.points
+                                    .iter()
+                                    .map(|(x, y)| [*x as f64, *y as f64])
+                                    .collect(),
+                            ))
+                            .radius(5.)
+                            .shape(MarkerShape::Square)
+                            .color(colour),
+                        );
+                    }
+                    if model.show_graph_labels {
+                        if let Some((x, y)) = area.points.first() {
+                            plot_ui
+                                .text(Text::new(PlotPoint::new(*x, *y), &area.name).color(colour));
+                        }
+                    }
+                }
+            }
+
+            for (index, point) in tracking_config.calibration_points.iter().enumerate() {
+                let (x, y) = point.source;
+                let colour = if model.editing_calibration_point == Some(index) {
+                    Color32::YELLOW
+                } else {
+                    Color32::GOLD
+                };
+                plot_ui.points(
+                    Points::new(PlotPoints::new(vec![[x as f64, y as f64]]))
+                        .radius(6.)
+                        .shape(MarkerShape::Diamond)
+                        .color(colour),
+                );
+                if model.show_graph_labels {
+                    plot_ui.text(
+                        Text::new(
+                            PlotPoint::new(x, y),
+                            format!("#{} → {:.2}, {:.2}", index, point.target.0, point.target.1),
+                        )
+                        .color(colour),
+                    );
+                }
+            }
+
             if let Some((a, b, c, d)) = tracking_config.region_of_interest() {
                 let corner_points: Vec<(f32, f32, &str)> = [a, b, c, d]
                     .iter()
@@ -74,12 +148,14 @@ pub fn render_scan_graph(model: &mut Model, ui: &mut Ui) {
                     })
                     .collect();
 
-                for (x, y, name) in corner_points {
+                for (index, (x, y, name)) in corner_points.into_iter().enumerate() {
                     let plot_points = PlotPoints::new(vec![[x as f64, y as f64]]);
+                    let is_active =
+                        model.hovered_corner == Some(index) || model.dragging_corner == Some(index);
                     plot_ui.points(
                         Points::new(plot_points)
                             .filled(true)
-                            .radius(10.)
+                            .radius(if is_active { 14. } else { 10. })
                             .shape(MarkerShape::Circle)
                             .name(name)
                             .color(Color32::from_rgba_unmultiplied(255, 0, 0, 32)),
@@ -107,6 +183,31 @@ pub fn render_scan_graph(model: &mut Model, ui: &mut Ui) {
 
     if response.clicked() {
         debug!("Clicked scan graph");
+        if let (Some((kind, index)), Some(PlotPoint { x, y }), Some(config)) = (
+            model.editing_area,
+            pointer_coordinate,
+            &mut model.backend_config,
+        ) {
+            let areas = match kind {
+                AreaKind::Include => &mut config.include_areas,
+                AreaKind::Exclude => &mut config.exclude_areas,
+            };
+            if let Some(area) = areas.get_mut(index) {
+                area.points.push((x as f32, y as f32));
+                model.is_editing = true;
+            }
+        }
+        if let (Some(index), Some(PlotPoint { x, y }), Some(config)) = (
+            model.editing_calibration_point,
+            pointer_coordinate,
+            &mut model.backend_config,
+        ) {
+            if let Some(point) = config.calibration_points.get_mut(index) {
+                point.source = (x as f32, y as f32);
+                model.editing_calibration_point = None;
+                model.is_editing = true;
+            }
+        }
         match &mut model.editing_corners {
             EditingCorner::None => {
                 // Do nothing
@@ -120,48 +221,92 @@ pub fn render_scan_graph(model: &mut Model, ui: &mut Ui) {
         }
     }
 
-    if let Some(egui::plot::PlotPoint { x, y }) = pointer_coordinate {
-        // debug!("Should edit using pointer at {},{}", x, y);
-        let x = x as f32;
-        let y = y as f32;
-        if let Some(config) = &mut model.backend_config {
-            if let Some((a, b, c, d)) = &mut config.region_of_interest_mut() {
-                // println!("{}, {}", x, y);
-                match model.editing_corners {
-                    EditingCorner::None => {}
-                    EditingCorner::A => {
-                        a.x = x;
-                        a.y = y;
-                    }
-                    EditingCorner::B => {
-                        b.x = x;
-                        b.y = y;
-                    }
-                    EditingCorner::C => {
-                        c.x = x;
-                        c.y = y;
-                    }
-                    EditingCorner::D => {
-                        d.x = x;
-                        d.y = y;
-                    }
-                }
+    // Corner handles: hover, drag (or follow the pointer, if placing a corner by clicking)
+    let handle_radius = (bounds.width() * HANDLE_RADIUS_FRACTION) as f32;
+    let pointer = pointer_coordinate.map(|PlotPoint { x, y }| (x as f32, y as f32));
+
+    if model.dragging_corner.is_none() {
+        model.hovered_corner = match (pointer, &model.backend_config) {
+            (Some(pointer), Some(config)) => config
+                .region_of_interest()
+                .and_then(|roi| corner_near(&corners_to_points(roi), pointer, handle_radius)),
+            _ => None,
+        };
+    }
+    if response.drag_started() && model.hovered_corner.is_some() {
+        model.dragging_corner = model.hovered_corner;
+        model.is_editing = true;
+    }
+    if response.drag_released() {
+        model.dragging_corner = None;
+    }
+
+    let active_corner = model.dragging_corner.or(model.editing_corners.index());
+    if let (Some(index), Some(pointer), Some(config)) =
+        (active_corner, pointer, &mut model.backend_config)
+    {
+        if let Some(roi) = config.region_of_interest() {
+            let scan_points = if model.roi_snap_mode == SnapMode::Off {
+                Vec::new()
             } else {
-                match model.editing_corners {
-                    EditingCorner::None => {}
-                    _ => {
-                        warn!("No ROI, create a new one with some default points",);
-                        let (x, y) = (0.0, 0.);
-                        let distance = 1000.;
-                        config.region_of_interest = Some((
-                            ConfigRectCornerPoint::new(0, x, y),
-                            ConfigRectCornerPoint::new(1, x + distance, y),
-                            ConfigRectCornerPoint::new(2, x + distance, y + distance),
-                            ConfigRectCornerPoint::new(3, x, y + distance),
-                        ));
-                    }
-                }
-            }
+                scan_points_in_world(&model.scans, config)
+            };
+            let target = snap(
+                pointer,
+                &scan_points,
+                handle_radius * 2.,
+                model.roi_snap_mode,
+            );
+            let mut corners = corners_to_points(roi);
+            move_corner(&mut corners, index, target, model.roi_force_rectangle);
+            config.region_of_interest = Some(points_to_corners(corners));
+        } else {
+            warn!("No ROI, create a new one with some default points",);
+            let (x, y) = (0.0, 0.);
+            let distance = 1000.;
+            config.region_of_interest = Some((
+                ConfigRectCornerPoint::new(0, x, y),
+                ConfigRectCornerPoint::new(1, x + distance, y),
+                ConfigRectCornerPoint::new(2, x + distance, y + distance),
+                ConfigRectCornerPoint::new(3, x, y + distance),
+            ));
         }
     }
 }
+
+/// All current scan samples as world-space points, e.g. for snapping
+fn scan_points_in_world(
+    scans: &HashMap<String, Vec<Point2D>>,
+    config: &BackendConfig,
+) -> Vec<Point2D> {
+    config
+        .devices()
+        .iter()
+        .filter_map(|device| scans.get(&device.serial).map(|samples| (device, samples)))
+        .flat_map(|(device, samples)| {
+            samples
+                .iter()
+                .filter_map(move |(angle, distance)| scan_sample_to_point(angle, distance, device))
+        })
+        .collect()
+}
+
+/// A closed outline of the polygon; dashed unless selected for editing
+fn draw_polygon(area: &AreaPolygon, colour: Color32, is_selected: bool) -> Line {
+    let mut points: Vec<[f64; 2]> = area
+        .points
+        .iter()
+        .map(|(x, y)| [*x as f64, *y as f64])
+        .collect();
+    if let Some(first) = points.first().cloned() {
+        points.push(first);
+    }
+    let line = Line::new(PlotPoints::new(points))
+        .color(colour)
+        .name(&area.name);
+    if is_selected {
+        line.width(2.)
+    } else {
+        line.style(egui::plot::LineStyle::dashed_dense())
+    }
+}
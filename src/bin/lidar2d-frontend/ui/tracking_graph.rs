@@ -1,9 +1,10 @@
 use crate::model::Model;
 use egui::{
-    plot::{Plot, PlotPoint, Text},
+    plot::{Plot, PlotPoint, PlotUi, Polygon, Text},
     Color32, Ui,
 };
 use quad_to_quad_transformer::DEFAULT_DST_QUAD;
+use tether_lidar2d_consolidation::systems::heatmap::heat_colour;
 
 use super::{draw_circle, draw_line};
 
@@ -31,6 +32,10 @@ pub fn render_tracking_graph(model: &mut Model, ui: &mut Ui) {
             }
         };
 
+        if model.show_heatmap {
+            draw_heatmap(model, plot_ui);
+        }
+
         for (x, y) in &model.raw_tracked_points {
             plot_ui.line(draw_circle(*x, *y, radius, Color32::DARK_RED));
         }
@@ -76,6 +81,11 @@ pub fn render_tracking_graph(model: &mut Model, ui: &mut Ui) {
                 // "Bearing" line from origin
                 plot_ui.line(draw_line(0., 0., p.x, p.y).color(light_green));
 
+                // Line towards predicted position
+                if let Some((px, py)) = p.predicted {
+                    plot_ui.line(draw_line(p.x, p.y, px, py).color(Color32::YELLOW));
+                }
+
                 // Current position of smoothed point
                 plot_ui.line(draw_circle(
                     p.x,
@@ -115,3 +125,44 @@ pub fn render_tracking_graph(model: &mut Model, ui: &mut Ui) {
         }
     });
 }
+
+/// Draw the occupancy heatmap, as filled cells over the ROI (in the smoothed points frame)
+fn draw_heatmap(model: &Model, plot_ui: &mut PlotUi) {
+    let (Some(heatmap), Some(config)) = (&model.heatmap, &model.backend_config) else {
+        return;
+    };
+    let max = heatmap.max();
+    if max <= 0. {
+        return;
+    }
+
+    let (origin, size) = if config.smoothing_use_real_units {
+        let [a, b, _c, d] = model.calculated_dst_quad.unwrap_or(DEFAULT_DST_QUAD);
+        (a, (b.0 - a.0, d.1 - a.1))
+    } else {
+        ((0., 0.), (1., 1.))
+    };
+    let (cell_width, cell_height) = (
+        size.0 / heatmap.columns as f32,
+        size.1 / heatmap.rows as f32,
+    );
+
+    for row in 0..heatmap.rows {
+        for column in 0..heatmap.columns {
+            let value = heatmap.get(column, row);
+            if value <= 0. {
+                continue;
+            }
+            let [r, g, b] = heat_colour(value / max);
+            let x = (origin.0 + column as f32 * cell_width) as f64;
+            let y = (origin.1 + row as f32 * cell_height) as f64;
+            let (w, h) = (cell_width as f64, cell_height as f64);
+            plot_ui.polygon(
+                Polygon::new(vec![[x, y], [x + w, y], [x + w, y + h], [x, y + h]])
+                    .color(Color32::from_rgb(r, g, b))
+                    .fill_alpha(0.5)
+                    .width(0.),
+            );
+        }
+    }
+}
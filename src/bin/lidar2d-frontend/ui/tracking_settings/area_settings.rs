@@ -0,0 +1,83 @@
+use egui::{DragValue, RichText, Ui};
+use log::warn;
+use tether_lidar2d_consolidation::backend_config::AreaPolygon;
+
+use crate::model::{AreaKind, Model};
+
+pub fn render_area_settings(model: &mut Model, ui: &mut Ui) {
+    ui.heading("Tracking Areas");
+    ui.label(
+        "Polygons in world space; click on the Scan Area graph to add points to the selected area.",
+    );
+
+    for kind in [AreaKind::Include, AreaKind::Exclude] {
+        let Some(backend_config) = &mut model.backend_config else {
+            return;
+        };
+        let areas = match kind {
+            AreaKind::Include => &mut backend_config.include_areas,
+            AreaKind::Exclude => &mut backend_config.exclude_areas,
+        };
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label(RichText::new(format!("{:?} areas", kind)).strong());
+            if ui.button("Add ➕").clicked() {
+                areas.push(AreaPolygon {
+                    name: format!("{:?} {}", kind, areas.len() + 1),
+                    points: Vec::new(),
+                });
+                model.editing_area = Some((kind, areas.len() - 1));
+                model.is_editing = true;
+            }
+        });
+
+        let mut delete_index: Option<usize> = None;
+        for (index, area) in areas.iter_mut().enumerate() {
+            ui.group(|ui| {
+                ui.horizontal(|ui| {
+                    if ui.text_edit_singleline(&mut area.name).changed() {
+                        model.is_editing = true;
+                    }
+                    if ui
+                        .selectable_label(model.editing_area == Some((kind, index)), "Draw ✏")
+                        .clicked()
+                    {
+                        model.editing_area = if model.editing_area == Some((kind, index)) {
+                            None
+                        } else {
+                            Some((kind, index))
+                        };
+                        model.is_editing = true;
+                    }
+                    if ui.button("Undo point ⟲").clicked() {
+                        area.points.pop();
+                        model.is_editing = true;
+                    }
+                    if ui.button("🗑").clicked() {
+                        warn!("Deleting area {}", &area.name);
+                        delete_index = Some(index);
+                    }
+                });
+                if area.points.len() < 3 {
+                    ui.label("Needs at least 3 points");
+                }
+                for (x, y) in area.points.iter_mut() {
+                    ui.horizontal(|ui| {
+                        let x_changed = ui.add(DragValue::new(x).prefix("x ").speed(10.)).changed();
+                        let y_changed = ui.add(DragValue::new(y).prefix("y ").speed(10.)).changed();
+                        if x_changed || y_changed {
+                            model.is_editing = true;
+                        }
+                    });
+                }
+            });
+        }
+
+        if let Some(index) = delete_index {
+            areas.remove(index);
+            model.editing_area = None;
+            model.is_editing = true;
+        }
+    }
+}
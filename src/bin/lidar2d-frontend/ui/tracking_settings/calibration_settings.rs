@@ -0,0 +1,81 @@
+use egui::{DragValue, RichText, Ui};
+use tether_lidar2d_consolidation::backend_config::CalibrationPoint;
+
+use crate::model::Model;
+
+pub fn render_calibration_settings(model: &mut Model, ui: &mut Ui) {
+    let Some(backend_config) = &mut model.backend_config else {
+        return;
+    };
+
+    ui.heading("Calibration");
+    if ui
+        .checkbox(
+            &mut backend_config.calibration_enabled,
+            "Use calibration points instead of ROI transform",
+        )
+        .clicked()
+    {
+        model.is_editing = true;
+    }
+    ui.label(
+        "Place markers on the floor; for each, select \"Place\" and click where it appears on the Scan Area graph, then enter where it should be in the output.",
+    );
+    if backend_config.calibration_enabled && backend_config.calibration_points.len() < 3 {
+        ui.label(RichText::new("At least 3 points are required").color(egui::Color32::LIGHT_RED));
+    }
+
+    if ui.button("Add point ➕").clicked() {
+        backend_config.calibration_points.push(CalibrationPoint {
+            source: (0., 0.),
+            target: (0., 0.),
+        });
+        model.editing_calibration_point = Some(backend_config.calibration_points.len() - 1);
+        model.is_editing = true;
+    }
+
+    let mut delete_index: Option<usize> = None;
+    for (index, point) in backend_config.calibration_points.iter_mut().enumerate() {
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.label(format!("#{}", index));
+                if ui
+                    .selectable_label(model.editing_calibration_point == Some(index), "Place ✏")
+                    .clicked()
+                {
+                    model.editing_calibration_point =
+                        if model.editing_calibration_point == Some(index) {
+                            None
+                        } else {
+                            Some(index)
+                        };
+                    model.is_editing = true;
+                }
+                if ui.button("🗑").clicked() {
+                    delete_index = Some(index);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label(format!(
+                    "Seen at {:.0}, {:.0} → output",
+                    point.source.0, point.source.1
+                ));
+                let x_changed = ui
+                    .add(DragValue::new(&mut point.target.0).speed(0.01))
+                    .changed();
+                let y_changed = ui
+                    .add(DragValue::new(&mut point.target.1).speed(0.01))
+                    .changed();
+                if x_changed || y_changed {
+                    model.is_editing = true;
+                }
+            });
+        });
+    }
+
+    if let Some(index) = delete_index {
+        backend_config.calibration_points.remove(index);
+        model.editing_calibration_point = None;
+        model.is_editing = true;
+    }
+}
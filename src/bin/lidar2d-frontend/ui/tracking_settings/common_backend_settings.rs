@@ -1,10 +1,24 @@
-use egui::{Color32, Grid, RichText, Slider, Ui};
+use egui::{Color32, DragValue, Grid, RichText, Slider, Ui};
 use log::debug;
-use tether_lidar2d_consolidation::systems::{
-    automasking::AutoMaskMessage, position_remapping::OriginLocation, smoothing::EmptyListSendMode,
+use tether_lidar2d_consolidation::{
+    encoding::{encode, ALL_ENCODINGS},
+    systems::{
+        automasking::AutoMaskMessage,
+        clustering::LegPairSettings,
+        movement::MovementMode,
+        occlusion::OcclusionSettings,
+        position_remapping::OriginLocation,
+        smoothing::{EmptyListSendMode, IdPolicy, ReidentifySettings, SmoothingFilter},
+    },
 };
 
-use crate::model::{EditingCorner, Model};
+use crate::{
+    model::{EditingCorner, Model},
+    ui::roi_editing::{
+        corners_to_points, make_rectangle, move_corner, points_to_corners, rectangle_size,
+        set_rectangle_size, SnapMode,
+    },
+};
 
 const BIG_TEXT_SIZE: f32 = 20.0;
 
@@ -32,15 +46,48 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
             ui.label(RichText::new("All Outputs currently enabled.").color(Color32::LIGHT_GREEN));
         }
 
+        // ------------------------ OUTPUT ENCODING
+        ui.separator();
+        ui.heading("Output Encoding");
+
+        ui.horizontal(|ui| {
+            for encoding in ALL_ENCODINGS {
+                if ui
+                    .selectable_label(
+                        backend_config.output_encoding == encoding,
+                        format!("{:?}", encoding),
+                    )
+                    .clicked()
+                {
+                    backend_config.output_encoding = encoding;
+                    model.is_editing = true;
+                };
+            }
+        });
+        if !backend_config.output_encodings.is_empty() {
+            ui.label(format!(
+                "Overridden for: {}",
+                backend_config
+                    .output_encodings
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ));
+        }
+
         // ------------------------ AUTOMASKING SETTINGS
         ui.separator();
         ui.heading("Automasking");
 
         ui.horizontal(|ui| {
             if ui.button("New auto-calibration").clicked() {
-                let payload = rmp_serde::to_vec(&AutoMaskMessage {
-                    r#type: "new".into(),
-                })
+                let payload = encode(
+                    &AutoMaskMessage {
+                        r#type: "new".into(),
+                    },
+                    model.backend_encoding,
+                )
                 .expect("failed to serialize automask command");
                 model
                     .tether_agent
@@ -48,9 +95,12 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                     .expect("failed to publish automask command");
             }
             if ui.button("Clear calibration").clicked() {
-                let payload = rmp_serde::to_vec(&AutoMaskMessage {
-                    r#type: "clear".into(),
-                })
+                let payload = encode(
+                    &AutoMaskMessage {
+                        r#type: "clear".into(),
+                    },
+                    model.backend_encoding,
+                )
                 .expect("failed to serialize automask command");
                 model
                     .tether_agent
@@ -132,6 +182,44 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
             }
         });
 
+        ui.horizontal(|ui| {
+            let mut enabled = backend_config.clustering_leg_pairing.is_some();
+            if ui
+                .checkbox(&mut enabled, "Pair legs")
+                .on_hover_text("Merge two leg-sized clusters within a stride into one person")
+                .clicked()
+            {
+                backend_config.clustering_leg_pairing = enabled.then(LegPairSettings::default);
+                model.is_editing = true;
+            }
+            if let Some(settings) = &mut backend_config.clustering_leg_pairing {
+                if ui
+                    .add(
+                        DragValue::new(&mut settings.max_leg_size)
+                            .speed(5.)
+                            .clamp_range(0. ..=f32::MAX)
+                            .prefix("leg ")
+                            .suffix("mm"),
+                    )
+                    .changed()
+                {
+                    model.is_editing = true;
+                }
+                if ui
+                    .add(
+                        DragValue::new(&mut settings.max_stride)
+                            .speed(10.)
+                            .clamp_range(0. ..=f32::MAX)
+                            .prefix("stride ")
+                            .suffix("mm"),
+                    )
+                    .changed()
+                {
+                    model.is_editing = true;
+                }
+            }
+        });
+
         ui.separator();
         ui.heading("Tracking region (ROI)");
         ui.horizontal(|ui| {
@@ -187,6 +275,88 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
             };
         });
 
+        ui.label("Or drag the corner handles on the Scan Area graph");
+
+        if let Some(roi) = backend_config.region_of_interest() {
+            let mut corners = corners_to_points(roi);
+            let mut changed = false;
+
+            Grid::new("roi_corners").num_columns(3).show(ui, |ui| {
+                for (index, name) in ["A", "B", "C", "D"].iter().enumerate() {
+                    ui.label(*name);
+                    let (mut x, mut y) = corners[index];
+                    let x_changed = ui
+                        .add(DragValue::new(&mut x).prefix("x ").suffix("mm").speed(10.))
+                        .changed();
+                    let y_changed = ui
+                        .add(DragValue::new(&mut y).prefix("y ").suffix("mm").speed(10.))
+                        .changed();
+                    if x_changed || y_changed {
+                        move_corner(&mut corners, index, (x, y), model.roi_force_rectangle);
+                        changed = true;
+                    }
+                    ui.end_row();
+                }
+            });
+
+            if ui
+                .checkbox(&mut model.roi_force_rectangle, "Force rectangle")
+                .on_hover_text("Keep all corners at right angles, oriented along A→B")
+                .changed()
+                && model.roi_force_rectangle
+            {
+                make_rectangle(&mut corners);
+                changed = true;
+            }
+
+            if model.roi_force_rectangle {
+                let (mut width, mut height) = rectangle_size(&corners);
+                ui.horizontal(|ui| {
+                    let width_changed = ui
+                        .add(
+                            DragValue::new(&mut width)
+                                .prefix("w ")
+                                .suffix("mm")
+                                .speed(10.),
+                        )
+                        .changed();
+                    let height_changed = ui
+                        .add(
+                            DragValue::new(&mut height)
+                                .prefix("h ")
+                                .suffix("mm")
+                                .speed(10.),
+                        )
+                        .changed();
+                    if width_changed || height_changed {
+                        set_rectangle_size(&mut corners, width, height);
+                        changed = true;
+                    }
+                });
+            }
+
+            if changed {
+                backend_config.region_of_interest = Some(points_to_corners(corners));
+                model.is_editing = true;
+            }
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Snap corners to");
+            for (mode, label) in [
+                (SnapMode::Off, "Nothing"),
+                (SnapMode::ScanPoints, "Scan points"),
+                (SnapMode::Walls, "Walls"),
+            ] {
+                if ui
+                    .selectable_label(model.roi_snap_mode == mode, label)
+                    .clicked()
+                {
+                    model.roi_snap_mode = mode;
+                }
+            }
+        });
+
         // ------------------------ SMOOTHING SETTINGS
         ui.separator();
         ui.heading("Smoothing");
@@ -244,6 +414,34 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                 };
                 ui.end_row();
 
+                ui.label("ID policy");
+                ui.horizontal(|ui| {
+                    let policies = [
+                        ("Lowest free", IdPolicy::LowestFree),
+                        ("Monotonic", IdPolicy::Monotonic),
+                        ("Cooldown", IdPolicy::Cooldown { cooldown_ms: 10000 }),
+                    ];
+                    for (label, policy) in policies {
+                        let selected = std::mem::discriminant(&backend_config.smoothing_id_policy)
+                            == std::mem::discriminant(&policy);
+                        if ui.selectable_label(selected, label).clicked() && !selected {
+                            backend_config.smoothing_id_policy = policy;
+                            model.is_editing = true;
+                        }
+                    }
+                    if let IdPolicy::Cooldown { cooldown_ms } =
+                        &mut backend_config.smoothing_id_policy
+                    {
+                        if ui
+                            .add(Slider::new(cooldown_ms, 0..=60000).suffix("ms"))
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                    }
+                });
+                ui.end_row();
+
                 let slider_range = {
                     if backend_config.smoothing_use_real_units {
                         0. ..=5000.
@@ -296,6 +494,48 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                 }
                 ui.end_row();
 
+                ui.label("Re-identify lost");
+                ui.horizontal(|ui| {
+                    let mut enabled = backend_config.smoothing_reidentify.is_some();
+                    if ui
+                        .checkbox(&mut enabled, "")
+                        .on_hover_text(
+                            "Tracks that reappear soon after expiring, near where they were \
+                             heading, keep their ID",
+                        )
+                        .clicked()
+                    {
+                        let use_real_units = backend_config.smoothing_use_real_units;
+                        backend_config.smoothing_reidentify =
+                            enabled.then(|| ReidentifySettings::for_units(use_real_units));
+                        model.is_editing = true;
+                    }
+                    if let Some(settings) = &mut backend_config.smoothing_reidentify {
+                        if ui
+                            .add(Slider::new(&mut settings.window_ms, 0..=10000).suffix("ms"))
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        if ui
+                            .add(
+                                DragValue::new(&mut settings.max_distance)
+                                    .speed(if backend_config.smoothing_use_real_units {
+                                        10.
+                                    } else {
+                                        0.01
+                                    })
+                                    .clamp_range(0. ..=f32::MAX)
+                                    .prefix("within "),
+                            )
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                    }
+                });
+                ui.end_row();
+
                 ui.label("Wait before expire");
                 let mut value = backend_config.smoothing_expire_ms as u64;
                 if ui
@@ -307,6 +547,45 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                 }
                 ui.end_row();
 
+                ui.label("Hold occluded");
+                ui.horizontal(|ui| {
+                    let mut enabled = backend_config.occlusion.is_some();
+                    if ui
+                        .checkbox(&mut enabled, "")
+                        .on_hover_text(
+                            "Tracks hidden from every LIDAR (e.g. behind someone else) are \
+                             held for longer before expiring",
+                        )
+                        .clicked()
+                    {
+                        backend_config.occlusion = enabled.then(OcclusionSettings::default);
+                        model.is_editing = true;
+                    }
+                    if let Some(settings) = &mut backend_config.occlusion {
+                        if ui
+                            .add(Slider::new(&mut settings.max_hold_ms, 0..=10000).suffix("ms"))
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        if ui
+                            .add(
+                                DragValue::new(&mut settings.track_radius)
+                                    .speed(10.)
+                                    .clamp_range(0. ..=f32::MAX)
+                                    .prefix("radius "),
+                            )
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        if ui.checkbox(&mut settings.coast, "Coast").changed() {
+                            model.is_editing = true;
+                        }
+                    }
+                });
+                ui.end_row();
+
                 ui.label("Update interval");
                 if ui
                     .add(
@@ -324,17 +603,93 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                 ));
                 ui.end_row();
 
-                ui.label("Lerp factor");
+                ui.label("Prediction lookahead");
                 if ui
-                    .add(Slider::new(
-                        &mut backend_config.smoothing_lerp_factor,
-                        0. ..=1.,
-                    ))
+                    .add(
+                        Slider::new(&mut backend_config.prediction_lookahead_ms, 0..=500)
+                            .suffix("ms"),
+                    )
+                    .on_hover_text("Extrapolate each point forward by this much; 0 to disable")
                     .changed()
                 {
                     model.is_editing = true;
                 }
                 ui.end_row();
+
+                ui.label("Filter");
+                ui.horizontal(|ui| {
+                    let filters = [
+                        ("Lerp", SmoothingFilter::Lerp),
+                        (
+                            "Exponential",
+                            SmoothingFilter::Exponential {
+                                time_constant_ms: 100.,
+                            },
+                        ),
+                        (
+                            "One Euro",
+                            SmoothingFilter::OneEuro {
+                                min_cutoff: 1.,
+                                beta: 0.01,
+                            },
+                        ),
+                    ];
+                    for (label, filter) in filters {
+                        let selected = std::mem::discriminant(&backend_config.smoothing_filter)
+                            == std::mem::discriminant(&filter);
+                        if ui.selectable_label(selected, label).clicked() && !selected {
+                            backend_config.smoothing_filter = filter;
+                            model.is_editing = true;
+                        }
+                    }
+                });
+                ui.end_row();
+
+                match &mut backend_config.smoothing_filter {
+                    SmoothingFilter::Lerp => {
+                        ui.label("Lerp factor");
+                        if ui
+                            .add(Slider::new(
+                                &mut backend_config.smoothing_lerp_factor,
+                                0. ..=1.,
+                            ))
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        ui.end_row();
+                    }
+                    SmoothingFilter::Exponential { time_constant_ms } => {
+                        ui.label("Time constant");
+                        if ui
+                            .add(Slider::new(time_constant_ms, 0. ..=2000.).suffix("ms"))
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        ui.end_row();
+                    }
+                    SmoothingFilter::OneEuro { min_cutoff, beta } => {
+                        ui.label("Min cutoff");
+                        if ui
+                            .add(Slider::new(min_cutoff, 0.01..=10.).suffix("Hz"))
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        ui.end_row();
+
+                        ui.label("Beta");
+                        if ui
+                            .add(DragValue::new(beta).speed(0.001).clamp_range(0. ..=1.))
+                            .on_hover_text("Reduce lag when moving fast; per unit of speed")
+                            .changed()
+                        {
+                            model.is_editing = true;
+                        }
+                        ui.end_row();
+                    }
+                }
             });
 
             ui.horizontal(|ui| {
@@ -493,22 +848,10 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                 &mut backend_config.enable_average_movement,
                 "Enable calculation + output",
             )
+            .on_hover_text("Per-point velocity is always calculated when this is enabled")
             .clicked()
         {
             model.is_editing = true;
-            if backend_config.enable_average_movement {
-                // Average movement calculations NEED per-point velocity calculations to be enabled, too
-                backend_config.enable_velocity = true;
-            }
-        }
-
-        if backend_config.enable_average_movement && !backend_config.enable_velocity {
-            ui.label(
-                RichText::new(
-                    "Per-point velocity must also be enabled for avarege movement analysis to work",
-                )
-                .color(Color32::LIGHT_RED),
-            );
         }
 
         ui.add_enabled_ui(backend_config.enable_average_movement, |ui| {
@@ -524,6 +867,57 @@ pub fn render_common_backend_settings(model: &mut Model, ui: &mut Ui) {
                     model.is_editing = true;
                 }
             });
+            ui.horizontal(|ui| {
+                ui.label("Mode");
+                for mode in [MovementMode::Sum, MovementMode::Mean, MovementMode::Median] {
+                    if ui
+                        .selectable_label(
+                            backend_config.average_movement_mode == mode,
+                            format!("{:?}", mode),
+                        )
+                        .clicked()
+                    {
+                        backend_config.average_movement_mode = mode;
+                        model.is_editing = true;
+                    }
+                }
+            });
+            if ui
+                .checkbox(
+                    &mut backend_config.average_movement_weight_by_size,
+                    "Weight by size",
+                )
+                .on_hover_text("Larger points count for more (Mean and Median modes)")
+                .changed()
+            {
+                model.is_editing = true;
+            }
+            ui.horizontal(|ui| {
+                ui.label("Smoothing time");
+                if ui
+                    .add(
+                        Slider::new(&mut backend_config.average_movement_smoothing_ms, 0..=5000)
+                            .suffix("ms"),
+                    )
+                    .on_hover_text("Time constant for exponential smoothing; 0 for none")
+                    .changed()
+                {
+                    model.is_editing = true;
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Dead-zone");
+                if ui
+                    .add(Slider::new(
+                        &mut backend_config.average_movement_dead_zone,
+                        0. ..=500.,
+                    ))
+                    .on_hover_text("Slower movement (speed) is sent as zero")
+                    .changed()
+                {
+                    model.is_editing = true;
+                }
+            });
         });
     }
 }
@@ -1,12 +1,17 @@
+use area_settings::render_area_settings;
+use calibration_settings::render_calibration_settings;
 use common_backend_settings::render_common_backend_settings;
 use device_settings::render_device_settings;
 use egui::{Color32, RichText, Ui};
 use log::debug;
+use tether_lidar2d_consolidation::encoding::encode;
 
 use crate::model::Model;
 
 use super::SPACING_AMOUNT;
 
+mod area_settings;
+mod calibration_settings;
 mod common_backend_settings;
 mod device_settings;
 
@@ -26,6 +31,16 @@ pub fn render_tracking_settings(model: &mut Model, ui: &mut Ui) {
                 ui.add_space(SPACING_AMOUNT);
                 ui.separator();
 
+                render_area_settings(model, ui);
+
+                ui.add_space(SPACING_AMOUNT);
+                ui.separator();
+
+                render_calibration_settings(model, ui);
+
+                ui.add_space(SPACING_AMOUNT);
+                ui.separator();
+
                 render_device_settings(model, ui, &mut should_publish_update);
             }
         });
@@ -44,6 +59,8 @@ pub fn render_tracking_settings(model: &mut Model, ui: &mut Ui) {
         {
             should_publish_update = true;
             model.is_editing = false;
+            model.editing_area = None;
+            model.editing_calibration_point = None;
         }
     } else if ui.button("Edit ✏").clicked() {
         model.is_editing = true;
@@ -53,7 +70,8 @@ pub fn render_tracking_settings(model: &mut Model, ui: &mut Ui) {
     // config file (JSON) and republishes the updated Config (on the plug "provideLidarConfig").
     if should_publish_update {
         debug!("Publish new backend config: {:?}", &model.backend_config);
-        let payload = rmp_serde::to_vec(&model.backend_config).expect("failed to serialize config");
+        let payload = encode(&model.backend_config, model.backend_encoding)
+            .expect("failed to serialize config");
         model
             .tether_agent
             .send(&model.outputs.config, Some(&payload))
@@ -1,4 +1,7 @@
+use std::path::Path;
+
 use egui::{Slider, Ui};
+use log::{error, info};
 
 use crate::model::Model;
 
@@ -11,4 +14,30 @@ pub fn render_vis_settings(model: &mut Model, ui: &mut Ui) {
         });
         ui.checkbox(&mut model.show_graph_labels, "Show all graph text");
     });
+
+    ui.heading("Heatmap");
+    ui.group(|ui| match &model.heatmap {
+        Some(heatmap) => {
+            ui.checkbox(&mut model.show_heatmap, "Show on Tracking graph");
+            ui.label(format!(
+                "{}x{} cells; busiest cell {:.0}s",
+                heatmap.columns,
+                heatmap.rows,
+                heatmap.max()
+            ));
+            ui.horizontal(|ui| {
+                for file_name in ["heatmap.csv", "heatmap.png"] {
+                    if ui.button(format!("Export {}", file_name)).clicked() {
+                        match heatmap.save(Path::new(file_name)) {
+                            Ok(()) => info!("Exported heatmap to {}", file_name),
+                            Err(e) => error!("Failed to export heatmap: {}", e),
+                        }
+                    }
+                }
+            });
+        }
+        None => {
+            ui.label("Nothing received yet; is \"heatmap\" set in the backend config?");
+        }
+    });
 }
@@ -0,0 +1,87 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A source of (monotonic) time for the tracking systems. In production this is simply
+/// [`MonotonicClock`]; tests and recording playback can use a [`ManualClock`] instead, so
+/// that timing-related behaviour (expiry, waiting to become active, zone timeouts, etc.)
+/// is deterministic.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Time elapsed since `earlier`, according to this clock. Never panics; returns zero
+    /// if `earlier` is somehow in the future.
+    fn elapsed_since(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+/// A Clock shared between all the systems, so they agree on what "now" is.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The default Clock, backed by [`Instant`], which is unaffected by changes to the wall clock
+/// (e.g. NTP adjustments).
+#[derive(Default, Debug, Clone, Copy)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn default_clock() -> SharedClock {
+    Arc::new(MonotonicClock)
+}
+
+/// A Clock that only moves when told to. Cloning it gives another handle to the **same**
+/// clock, so one handle can be given to the systems while another is used to advance time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock::starting_at(Instant::now())
+    }
+
+    pub fn starting_at(now: Instant) -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().expect("clock lock poisoned") += duration;
+    }
+
+    pub fn advance_ms(&self, ms: u64) {
+        self.advance(Duration::from_millis(ms));
+    }
+
+    /// Move the clock to the given time; it never goes backwards, so earlier times are ignored
+    pub fn set(&self, now: Instant) {
+        let mut current = self.now.lock().expect("clock lock poisoned");
+        if now > *current {
+            *current = now;
+        }
+    }
+
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().expect("clock lock poisoned")
+    }
+}
@@ -0,0 +1,227 @@
+//! A high-level, I/O-free way to use the consolidation pipeline from another application:
+//! push scans in, call [`Consolidator::tick`] regularly, and read the results.
+//!
+//! ```no_run
+//! use std::time::Instant;
+//! use tether_lidar2d_consolidation::{backend_config::BackendConfig, consolidator::Consolidator};
+//!
+//! let mut consolidator = Consolidator::new(BackendConfig::default());
+//! let samples = vec![(0.0, 1000.0), (0.5, 1001.0)]; // (angle, distance) from a LIDAR
+//! consolidator.push_scan("my-lidar-serial", &samples, Instant::now());
+//! consolidator.tick(Instant::now());
+//! for point in consolidator.smoothed_points() {
+//!     println!("#{} at {},{}", point.id, point.x, point.y);
+//! }
+//! ```
+
+use std::time::Instant;
+
+use anyhow::Result;
+
+use crate::{
+    backend_config::BackendConfig,
+    clock::ManualClock,
+    systems::{
+        automasking::apply_automask_command, behaviour::BehaviourChange, clustering::Cluster2D,
+        crowd_stats::CrowdStats, groups::Group, heatmap::HeatmapGrid, presence::Zone,
+        smoothing::TrackReacquired, Systems,
+    },
+    tracking::TrackedPoint2D,
+    Point2D,
+};
+
+/// Owns a [`BackendConfig`] and all the [`Systems`], driven entirely by the caller: time only
+/// moves forward when a (later) timestamp is given to [`Consolidator::push_scan`] or
+/// [`Consolidator::tick`], so recorded data can be replayed at any speed.
+///
+/// The clock starts at the first timestamp given, which may be earlier than when the
+/// Consolidator was created (e.g. when replaying a recording); after that, earlier
+/// timestamps are treated as "no time has passed".
+pub struct Consolidator {
+    config: BackendConfig,
+    systems: Systems,
+    clock: ManualClock,
+    /// False until the clock has been set from the first timestamp
+    is_started: bool,
+    tracked_points: Vec<Point2D>,
+    smoothed_points: Vec<TrackedPoint2D>,
+    remapped_points: Option<Vec<TrackedPoint2D>>,
+    region_points: Vec<(String, Vec<TrackedPoint2D>)>,
+    zone_events: Vec<Zone>,
+    behaviour_events: Vec<BehaviourChange>,
+    reacquired_events: Vec<TrackReacquired>,
+    crowd_stats: Option<CrowdStats>,
+    config_changed: bool,
+}
+
+impl Consolidator {
+    pub fn new(config: BackendConfig) -> Self {
+        let clock = ManualClock::new();
+        let systems = Systems::with_clock(&config, clock.shared());
+        Consolidator {
+            config,
+            systems,
+            clock,
+            is_started: false,
+            tracked_points: Vec::new(),
+            smoothed_points: Vec::new(),
+            remapped_points: None,
+            region_points: Vec::new(),
+            zone_events: Vec::new(),
+            behaviour_events: Vec::new(),
+            reacquired_events: Vec::new(),
+            crowd_stats: None,
+            config_changed: false,
+        }
+    }
+
+    pub fn config(&self) -> &BackendConfig {
+        &self.config
+    }
+
+    /// Replace the config; all systems are re-created, so any tracked points are lost
+    pub fn apply_config(&mut self, config: BackendConfig) {
+        self.config = config;
+        self.systems.reset(&self.config);
+        self.tracked_points.clear();
+        self.smoothed_points.clear();
+        self.remapped_points = None;
+        self.region_points.clear();
+        self.crowd_stats = None;
+    }
+
+    /// Returns true (once) if the config was changed internally since this was last called,
+    /// e.g. because a new device was found or automasking completed. The application
+    /// may want to save it.
+    pub fn take_config_changed(&mut self) -> bool {
+        std::mem::take(&mut self.config_changed)
+    }
+
+    /// Add scan samples (angle, distance) from the device with the given serial number
+    pub fn push_scan(&mut self, serial: &str, samples: &[Point2D], timestamp: Instant) {
+        self.set_time(timestamp);
+        let update = self.systems.process_scan(serial, samples, &mut self.config);
+        if update.config_changed() {
+            self.config_changed = true;
+        }
+        if let Some(tracked_points) = update.tracked_points {
+            self.tracked_points = tracked_points.iter().map(|c| (c.x, c.y)).collect();
+        }
+    }
+
+    /// Update smoothing, and everything that depends on it. Call this regularly (e.g. at
+    /// `smoothing_update_interval`), whether or not new scans have arrived.
+    pub fn tick(&mut self, now: Instant) {
+        self.set_time(now);
+        if self.config.smoothing_disable {
+            return;
+        }
+
+        let interval = (self.systems.smoothing_system.get_elapsed().as_millis() as u64).max(1);
+        let outputs = self.systems.update_smoothed(&self.config, interval);
+
+        self.smoothed_points = outputs.smoothed_points.unwrap_or_default();
+        self.remapped_points = outputs.remapped_points;
+        self.region_points = outputs.region_points;
+        self.zone_events.extend(outputs.changed_zones);
+        self.behaviour_events.extend(outputs.behaviour_changes);
+        self.reacquired_events.extend(outputs.reacquired);
+        if let Some(stats) = outputs.crowd_stats {
+            self.crowd_stats = Some(stats);
+        }
+    }
+
+    /// Move the clock forward; the first time, start it (and so all the systems) from here.
+    /// Nothing has been tracked yet at that point, but automasking may have been requested,
+    /// so that is kept.
+    fn set_time(&mut self, now: Instant) {
+        if self.is_started {
+            self.clock.set(now);
+        } else {
+            self.clock = ManualClock::starting_at(now);
+            let automask_samplers = std::mem::take(&mut self.systems.automask_samplers);
+            self.systems = Systems::with_clock(&self.config, self.clock.shared());
+            self.systems.automask_samplers = automask_samplers;
+            self.is_started = true;
+        }
+    }
+
+    /// The most recently calculated clusters, in world (not ROI) coordinates
+    pub fn clusters(&self) -> &[Cluster2D] {
+        self.systems.clustering_system.clusters()
+    }
+
+    /// Points within the ROI, transformed but not smoothed
+    pub fn tracked_points(&self) -> &[Point2D] {
+        &self.tracked_points
+    }
+
+    /// Active smoothed points, as of the last [`Consolidator::tick`]
+    pub fn smoothed_points(&self) -> &[TrackedPoint2D] {
+        &self.smoothed_points
+    }
+
+    /// Active smoothed points in each named region (see [`BackendConfig::regions`]), as of
+    /// the last [`Consolidator::tick`]
+    pub fn region_points(&self) -> &[(String, Vec<TrackedPoint2D>)] {
+        &self.region_points
+    }
+
+    /// Active smoothed points converted into the configured remapped frame, if any, as of
+    /// the last [`Consolidator::tick`]
+    pub fn remapped_points(&self) -> Option<&[TrackedPoint2D]> {
+        self.remapped_points.as_deref()
+    }
+
+    /// Combined movement of all smoothed points, as per the average movement settings, as
+    /// of the last [`Consolidator::tick`]
+    pub fn movement(&self) -> Point2D {
+        self.systems.movement_analysis.movement()
+    }
+
+    /// Presence zones that changed (became active or inactive) since this was last called
+    pub fn zone_events(&mut self) -> Vec<Zone> {
+        std::mem::take(&mut self.zone_events)
+    }
+
+    /// Groups of smoothed points (if configured), as of the last [`Consolidator::tick`]
+    pub fn groups(&self) -> &[Group] {
+        self.systems
+            .group_detection
+            .as_ref()
+            .map(|g| g.groups())
+            .unwrap_or_default()
+    }
+
+    /// The most recent crowd stats (if configured), calculated every "publishInterval"
+    pub fn crowd_stats(&self) -> Option<&CrowdStats> {
+        self.crowd_stats.as_ref()
+    }
+
+    /// The occupancy heatmap (if configured), accumulated so far
+    pub fn heatmap(&self) -> Option<&HeatmapGrid> {
+        self.systems.heatmap.as_ref().map(|heatmap| heatmap.grid())
+    }
+
+    /// Changes in behaviour of smoothed points (if configured) since this was last called
+    pub fn behaviour_events(&mut self) -> Vec<BehaviourChange> {
+        std::mem::take(&mut self.behaviour_events)
+    }
+
+    /// Lost tracks that were re-acquired (if configured) since this was last called
+    pub fn reacquired_events(&mut self) -> Vec<TrackReacquired> {
+        std::mem::take(&mut self.reacquired_events)
+    }
+
+    /// Start ("new") or clear ("clear") automasking for all known devices
+    pub fn request_automask(&mut self, command_type: &str) -> Result<()> {
+        if apply_automask_command(
+            command_type,
+            &mut self.systems.automask_samplers,
+            &mut self.config,
+        )? {
+            self.config_changed = true;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// How message payloads are (de)serialised. MessagePack is the Tether default, but JSON and
+/// CBOR are much easier to consume from some environments (web, TouchDesigner, etc.)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    MessagePack,
+    Json,
+    Cbor,
+}
+
+pub const ALL_ENCODINGS: [Encoding; 3] = [Encoding::MessagePack, Encoding::Json, Encoding::Cbor];
+
+/// The "self-describe CBOR" tag (RFC 8949, section 3.4.6), which CBOR decoders ignore
+const CBOR_SELF_DESCRIBE: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+pub fn encode<T: Serialize + ?Sized>(value: &T, encoding: Encoding) -> Result<Vec<u8>> {
+    match encoding {
+        Encoding::MessagePack => Ok(rmp_serde::to_vec(value)?),
+        Encoding::Json => Ok(serde_json::to_vec(value)?),
+        Encoding::Cbor => {
+            // Prefixed with the self-describe tag, so that CBOR can always be told apart
+            // from MessagePack when decoding
+            let mut payload = Vec::from(CBOR_SELF_DESCRIBE);
+            ciborium::into_writer(value, &mut payload)
+                .map_err(|e| anyhow!("failed to encode CBOR: {}", e))?;
+            Ok(payload)
+        }
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(payload: &[u8], encoding: Encoding) -> Result<T> {
+    match encoding {
+        Encoding::MessagePack => Ok(rmp_serde::from_slice(payload)?),
+        Encoding::Json => Ok(serde_json::from_slice(payload)?),
+        Encoding::Cbor => {
+            ciborium::from_reader(payload).map_err(|e| anyhow!("failed to decode CBOR: {}", e))
+        }
+    }
+}
+
+/// Best guess at the encoding of a payload, without fully decoding it
+pub fn detect(payload: &[u8]) -> Encoding {
+    if payload.starts_with(&CBOR_SELF_DESCRIBE) {
+        return Encoding::Cbor;
+    }
+    match payload.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') | Some(b'[') => Encoding::Json,
+        _ => Encoding::MessagePack,
+    }
+}
+
+/// Decode a payload of unknown encoding: the most likely encoding (see [`detect`]) is tried
+/// first, then the others. Returns the value along with the encoding that worked.
+pub fn decode_any<T: DeserializeOwned>(payload: &[u8]) -> Result<(T, Encoding)> {
+    let likely = detect(payload);
+    let mut errors = Vec::new();
+    for encoding in
+        std::iter::once(likely).chain(ALL_ENCODINGS.into_iter().filter(|e| *e != likely))
+    {
+        match decode(payload, encoding) {
+            Ok(value) => return Ok((value, encoding)),
+            Err(e) => errors.push(format!("{:?}: {}", encoding, e)),
+        }
+    }
+    Err(anyhow!(
+        "Failed to decode payload with any encoding; {}",
+        errors.join("; ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracking::TrackedPoint2D;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Example {
+        some_value: u128,
+        list: Vec<(f32, f32)>,
+        name: String,
+    }
+
+    fn example() -> Example {
+        Example {
+            some_value: 3000,
+            list: vec![(1.5, -2.0), (0., 100.)],
+            name: "abc".into(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip_each_encoding() {
+        for encoding in ALL_ENCODINGS {
+            let payload = encode(&example(), encoding).unwrap();
+            assert_eq!(detect(&payload), encoding);
+            assert_eq!(decode::<Example>(&payload, encoding).unwrap(), example());
+            assert_eq!(
+                decode_any::<Example>(&payload).unwrap(),
+                (example(), encoding)
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_is_readable() {
+        let payload = encode(&TrackedPoint2D::new(1, (2., 3.), None), Encoding::Json).unwrap();
+        assert_eq!(
+            String::from_utf8(payload).unwrap(),
+            r#"{"id":1,"x":2.0,"y":3.0}"#
+        );
+    }
+
+    #[test]
+    fn test_plain_cbor_is_decoded() {
+        // e.g. from a client which does not add the self-describe tag
+        let mut payload = Vec::new();
+        ciborium::into_writer(&example(), &mut payload).unwrap();
+        assert_eq!(
+            decode_any::<Example>(&payload).unwrap(),
+            (example(), Encoding::Cbor)
+        );
+    }
+
+    #[test]
+    fn test_garbage_is_error() {
+        assert!(decode_any::<Example>(&[0xc1, 0x00]).is_err());
+        assert!(decode_any::<Example>(b"{ nope").is_err());
+    }
+}
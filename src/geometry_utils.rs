@@ -43,10 +43,42 @@ pub fn bearing(x: f32, y: f32) -> f32 {
     }
 }
 
+/// Whether the point is inside the polygon (given as a list of vertices, in order), using
+/// ray casting; polygons with fewer than 3 vertices contain nothing
+pub fn point_in_polygon(point: &Point2D, polygon: &[Point2D]) -> bool {
+    if polygon.len() < 3 {
+        return false;
+    }
+    let (x, y) = *point;
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (xi, yi) = polygon[i];
+        let (xj, yj) = polygon[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_point_in_polygon() {
+        // An "L" shape
+        let polygon = [(0., 0.), (2., 0.), (2., 1.), (1., 1.), (1., 2.), (0., 2.)];
+        assert!(point_in_polygon(&(0.5, 0.5), &polygon));
+        assert!(point_in_polygon(&(1.5, 0.5), &polygon));
+        assert!(point_in_polygon(&(0.5, 1.5), &polygon));
+        assert!(!point_in_polygon(&(1.5, 1.5), &polygon));
+        assert!(!point_in_polygon(&(-1., 0.5), &polygon));
+        assert!(!point_in_polygon(&(0.5, 0.5), &polygon[..2]));
+    }
+
     #[test]
     fn test_bearing_easy_cardinals() {
         assert_eq!(bearing(0., 1.0), 0.); // N
@@ -1,7 +1,14 @@
+pub mod backend;
 pub mod backend_config;
+pub mod clock;
+pub mod consolidator;
+pub mod encoding;
 pub mod geometry_utils;
+pub mod osc;
 pub mod systems;
 pub mod tether_interface;
 pub mod tracking;
+pub mod transport;
+pub mod tuio;
 
 pub type Point2D = (f32, f32);
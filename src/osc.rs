@@ -0,0 +1,222 @@
+//! Minimal [OSC 1.0](https://opensoundcontrol.stanford.edu/spec-1_0.html) encoding, plus a
+//! UDP sender which mirrors the smoothed tracking outputs for media servers that speak OSC
+//! rather than MQTT.
+
+use std::net::{SocketAddr, UdpSocket};
+
+use anyhow::Result;
+use log::{info, warn};
+
+use crate::{backend::SmoothedOutputs, systems::presence::Zone, tracking::TrackedPoint2D, Point2D};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> Self {
+        OscMessage {
+            address: String::from(address),
+            args,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_string(&mut bytes, &self.address);
+        let type_tags: String = std::iter::once(',')
+            .chain(self.args.iter().map(|arg| match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::String(_) => 's',
+            }))
+            .collect();
+        write_string(&mut bytes, &type_tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(v) => bytes.extend_from_slice(&v.to_be_bytes()),
+                OscArg::Float(v) => bytes.extend_from_slice(&v.to_be_bytes()),
+                OscArg::String(v) => write_string(&mut bytes, v),
+            }
+        }
+        bytes
+    }
+}
+
+/// Encode messages as a single OSC bundle, with the "immediately" time tag
+pub fn bundle_to_bytes(messages: &[OscMessage]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    write_string(&mut bytes, "#bundle");
+    bytes.extend_from_slice(&1u64.to_be_bytes());
+    for message in messages {
+        let element = message.to_bytes();
+        bytes.extend_from_slice(&(element.len() as i32).to_be_bytes());
+        bytes.extend_from_slice(&element);
+    }
+    bytes
+}
+
+/// OSC strings are null-terminated, and padded with nulls to a multiple of 4 bytes
+fn write_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend_from_slice(s.as_bytes());
+    let padding = 4 - (s.len() % 4);
+    bytes.extend(std::iter::repeat_n(0, padding));
+}
+
+/// Sends smoothed points, presence changes and movement as OSC bundles over UDP, using
+/// the address scheme (where `/lidar` is the configurable prefix):
+/// - `/lidar/count <n>` number of smoothed points
+/// - `/lidar/point/<id> <x> <y>` for each smoothed point
+/// - `/lidar/zone/<id> <1|0>` for each presence zone that changed
+/// - `/lidar/movement <x> <y>`
+pub struct OscSender {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: String,
+}
+
+impl OscSender {
+    pub fn new(target: SocketAddr, prefix: &str) -> Result<Self> {
+        let socket = UdpSocket::bind(if target.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+        info!("Will send OSC to {} with prefix \"{}\"", target, prefix);
+        Ok(OscSender {
+            socket,
+            target,
+            prefix: String::from(prefix.trim_end_matches('/')),
+        })
+    }
+
+    pub fn send_bundle(&self, messages: &[OscMessage]) {
+        if messages.is_empty() {
+            return;
+        }
+        if let Err(e) = self.socket.send_to(&bundle_to_bytes(messages), self.target) {
+            warn!("Failed to send OSC bundle to {}: {}", self.target, e);
+        }
+    }
+
+    /// Mirror everything from one smoothing update as a single bundle
+    pub fn send_smoothed_outputs(&self, outputs: &SmoothedOutputs) {
+        let mut messages = Vec::new();
+        if let Some(points) = &outputs.smoothed_points {
+            messages.extend(self.points_messages(points));
+        }
+        if let Some(movement) = outputs.movement {
+            messages.push(self.movement_message(movement));
+        }
+        messages.extend(outputs.changed_zones.iter().map(|z| self.zone_message(z)));
+        self.send_bundle(&messages);
+    }
+
+    pub fn points_messages(&self, points: &[TrackedPoint2D]) -> Vec<OscMessage> {
+        std::iter::once(OscMessage::new(
+            &format!("{}/count", self.prefix),
+            vec![OscArg::Int(points.len() as i32)],
+        ))
+        .chain(points.iter().map(|p| {
+            OscMessage::new(
+                &format!("{}/point/{}", self.prefix, p.id),
+                vec![OscArg::Float(p.x), OscArg::Float(p.y)],
+            )
+        }))
+        .collect()
+    }
+
+    pub fn zone_message(&self, zone: &Zone) -> OscMessage {
+        OscMessage::new(
+            &format!("{}/zone/{}", self.prefix, zone.id),
+            vec![OscArg::Int(if zone.active { 1 } else { 0 })],
+        )
+    }
+
+    pub fn movement_message(&self, movement: Point2D) -> OscMessage {
+        let (x, y) = movement;
+        OscMessage::new(
+            &format!("{}/movement", self.prefix),
+            vec![OscArg::Float(x), OscArg::Float(y)],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_message_encoding() {
+        let message = OscMessage::new(
+            "/lidar/point/3",
+            vec![
+                OscArg::Float(1.0),
+                OscArg::Int(-2),
+                OscArg::String("abcd".into()),
+            ],
+        );
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"/lidar/point/3\0\0");
+        expected.extend_from_slice(b",fis\0\0\0\0");
+        expected.extend_from_slice(&[0x3f, 0x80, 0, 0]);
+        expected.extend_from_slice(&[0xff, 0xff, 0xff, 0xfe]);
+        expected.extend_from_slice(b"abcd\0\0\0\0");
+        assert_eq!(message.to_bytes(), expected);
+    }
+
+    #[test]
+    fn test_bundle_encoding() {
+        let message = OscMessage::new("/a", vec![]);
+        let bytes = bundle_to_bytes(&[message.clone(), message]);
+        assert_eq!(&bytes[0..8], b"#bundle\0");
+        assert_eq!(&bytes[8..16], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        // Each element: 4-byte size, then "/a\0\0" ",\0\0\0"
+        assert_eq!(&bytes[16..20], &[0, 0, 0, 8]);
+        assert_eq!(&bytes[20..28], b"/a\0\0,\0\0\0");
+        assert_eq!(bytes.len(), 16 + 2 * 12);
+    }
+
+    #[test]
+    fn test_sends_over_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let sender = OscSender::new(receiver.local_addr().unwrap(), "/lidar/").unwrap();
+
+        let mut point = TrackedPoint2D::new(5, (0.5, 0.25), None);
+        point.velocity = Some([1., 1.]);
+        sender.send_smoothed_outputs(&SmoothedOutputs {
+            smoothed_points: Some(vec![point]),
+            movement: Some((1., 2.)),
+            ..Default::default()
+        });
+
+        let mut buffer = [0u8; 1024];
+        let size = receiver.recv(&mut buffer).unwrap();
+        let expected = bundle_to_bytes(&[
+            OscMessage::new("/lidar/count", vec![OscArg::Int(1)]),
+            OscMessage::new(
+                "/lidar/point/5",
+                vec![OscArg::Float(0.5), OscArg::Float(0.25)],
+            ),
+            OscMessage::new(
+                "/lidar/movement",
+                vec![OscArg::Float(1.), OscArg::Float(2.)],
+            ),
+        ]);
+        assert_eq!(&buffer[..size], expected.as_slice());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::backend_config::{AreaPolygon, BackendConfig};
+
+use super::clustering::Cluster2D;
+
+/// Filters clusters by the include/exclude areas (world-space polygons) in the config,
+/// before they are remapped or smoothed. This is independent of the ROI.
+pub struct AreaFilter {
+    include_areas: Vec<AreaPolygon>,
+    exclude_areas: Vec<AreaPolygon>,
+}
+
+impl AreaFilter {
+    /// Areas with fewer than 3 points (e.g. still being drawn) are ignored, since they
+    /// cannot contain anything
+    pub fn new(config: &BackendConfig) -> Self {
+        let valid = |areas: &[AreaPolygon]| -> Vec<AreaPolygon> {
+            areas
+                .iter()
+                .filter(|a| a.points.len() >= 3)
+                .cloned()
+                .collect()
+        };
+        AreaFilter {
+            include_areas: valid(&config.include_areas),
+            exclude_areas: valid(&config.exclude_areas),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.include_areas.is_empty() || !self.exclude_areas.is_empty()
+    }
+
+    pub fn passes(&self, cluster: &Cluster2D) -> bool {
+        let point = (cluster.x, cluster.y);
+        (self.include_areas.is_empty() || self.include_areas.iter().any(|a| a.contains(&point)))
+            && !self.exclude_areas.iter().any(|a| a.contains(&point))
+    }
+
+    pub fn filter_clusters(&self, clusters: &[Cluster2D]) -> Vec<Cluster2D> {
+        clusters
+            .iter()
+            .filter(|c| self.passes(c))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: f32, y: f32, size: f32) -> AreaPolygon {
+        AreaPolygon {
+            name: String::new(),
+            points: vec![(x, y), (x + size, y), (x + size, y + size), (x, y + size)],
+        }
+    }
+
+    fn cluster(id: usize, x: f32, y: f32) -> Cluster2D {
+        Cluster2D {
+            id,
+            x,
+            y,
+            size: 100.,
+        }
+    }
+
+    #[test]
+    fn test_no_areas_passes_everything() {
+        let filter = AreaFilter::new(&BackendConfig::default());
+        assert!(!filter.is_active());
+        assert_eq!(filter.filter_clusters(&[cluster(0, 1e6, -1e6)]).len(), 1);
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let config = BackendConfig {
+            include_areas: vec![square(0., 0., 4000.), square(10000., 0., 1000.)],
+            // A pillar in the middle of the first area
+            exclude_areas: vec![square(1500., 1500., 1000.)],
+            ..Default::default()
+        };
+        let filter = AreaFilter::new(&config);
+        assert!(filter.is_active());
+
+        let passed: Vec<usize> = filter
+            .filter_clusters(&[
+                cluster(0, 500., 500.),
+                cluster(1, 2000., 2000.),
+                cluster(2, 5000., 500.),
+                cluster(3, 10500., 500.),
+            ])
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(passed, [0, 3]);
+    }
+
+    #[test]
+    fn test_incomplete_areas_are_ignored() {
+        let incomplete = |points: Vec<(f32, f32)>| AreaPolygon {
+            name: String::new(),
+            points,
+        };
+        let config = BackendConfig {
+            include_areas: vec![
+                incomplete(vec![]),
+                incomplete(vec![(0., 0.), (1000., 1000.)]),
+            ],
+            exclude_areas: vec![incomplete(vec![(0., 0.)])],
+            ..Default::default()
+        };
+        let filter = AreaFilter::new(&config);
+        assert!(!filter.is_active());
+        assert_eq!(filter.filter_clusters(&[cluster(0, 500., 500.)]).len(), 1);
+
+        // Alongside a complete include area, only that one counts
+        let config = BackendConfig {
+            include_areas: vec![incomplete(vec![]), square(0., 0., 1000.)],
+            ..Default::default()
+        };
+        let passed = AreaFilter::new(&config)
+            .filter_clusters(&[cluster(0, 500., 500.), cluster(1, 5000., 500.)]);
+        assert_eq!(passed.len(), 1);
+        assert_eq!(passed[0].id, 0);
+    }
+}
@@ -3,7 +3,7 @@ use indexmap::IndexMap;
 use log::info;
 use serde::{Deserialize, Serialize};
 
-use crate::{backend_config::BackendConfig, Point2D};
+use crate::{backend_config::BackendConfig, encoding::decode_any, Point2D};
 
 pub type MaskThresholdMap = IndexMap<String, f32>;
 
@@ -66,35 +66,123 @@ pub fn handle_automask_message(
     automask_samplers: &mut IndexMap<String, AutoMaskSampler>,
     config: &mut BackendConfig,
 ) -> Result<bool> {
-    if let Ok(automask_command) = rmp_serde::from_slice::<AutoMaskMessage>(payload) {
-        let command_type: &str = &automask_command.r#type;
-        match command_type {
-            "new" => {
-                info!("request NEW auto mask samplers");
-                automask_samplers.clear();
-                config.clear_device_masking();
-                for device in config.devices().iter() {
-                    automask_samplers.insert(
-                        String::from(&device.serial),
-                        AutoMaskSampler::new(
-                            config.automask_scans_required,
-                            config.automask_threshold_margin,
-                        ),
-                    );
-                }
-                Ok(false)
-            }
-            "clear" => {
-                info!("request CLEAR all device masking thresholds");
-                automask_samplers.clear();
-                config.clear_device_masking();
-                Ok(true)
-            }
-            _ => Err(anyhow!(
-                "Unrecognised command type for RequestAutoMask message"
-            )),
-        }
+    if let Ok((automask_command, _encoding)) = decode_any::<AutoMaskMessage>(payload) {
+        apply_automask_command(&automask_command.r#type, automask_samplers, config)
     } else {
         Err(anyhow!("Failed to parse auto mask command"))
     }
 }
+
+/// As per [`handle_automask_message`], for an already-decoded command type
+/// ("new" or "clear")
+pub fn apply_automask_command(
+    command_type: &str,
+    automask_samplers: &mut IndexMap<String, AutoMaskSampler>,
+    config: &mut BackendConfig,
+) -> Result<bool> {
+    match command_type {
+        "new" => {
+            info!("request NEW auto mask samplers");
+            automask_samplers.clear();
+            config.clear_device_masking();
+            for device in config.devices().iter() {
+                automask_samplers.insert(
+                    String::from(&device.serial),
+                    AutoMaskSampler::new(
+                        config.automask_scans_required,
+                        config.automask_threshold_margin,
+                    ),
+                );
+            }
+            Ok(false)
+        }
+        "clear" => {
+            info!("request CLEAR all device masking thresholds");
+            automask_samplers.clear();
+            config.clear_device_masking();
+            Ok(true)
+        }
+        _ => Err(anyhow!(
+            "Unrecognised command type for RequestAutoMask message"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(r#type: &str) -> Vec<u8> {
+        rmp_serde::to_vec(&AutoMaskMessage {
+            r#type: r#type.into(),
+        })
+        .unwrap()
+    }
+
+    fn config_with_masked_devices() -> BackendConfig {
+        let mut config = BackendConfig::default();
+        let mut mask = IndexMap::new();
+        mask.insert("0".to_string(), 100.);
+        for serial in ["a", "b"] {
+            config.check_or_create_device(serial, 0.);
+            config.update_device_masking(&mask, serial).unwrap();
+        }
+        config
+    }
+
+    #[test]
+    fn test_new_command_creates_samplers() {
+        let mut config = config_with_masked_devices();
+        let mut samplers = AutoMaskSamplerMap::new();
+
+        let should_save = handle_automask_message(&command("new"), &mut samplers, &mut config);
+        assert!(!should_save.unwrap());
+        assert_eq!(samplers.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(config
+            .devices()
+            .iter()
+            .all(|d| d.scan_mask_thresholds.is_none()));
+    }
+
+    #[test]
+    fn test_clear_command_clears_everything() {
+        let mut config = config_with_masked_devices();
+        let mut samplers = AutoMaskSamplerMap::new();
+        samplers.insert("a".into(), AutoMaskSampler::new(1, 0.));
+
+        let should_save = handle_automask_message(&command("clear"), &mut samplers, &mut config);
+        assert!(should_save.unwrap());
+        assert!(samplers.is_empty());
+        assert!(config
+            .devices()
+            .iter()
+            .all(|d| d.scan_mask_thresholds.is_none()));
+    }
+
+    #[test]
+    fn test_invalid_commands() {
+        let mut config = config_with_masked_devices();
+        let mut samplers = AutoMaskSamplerMap::new();
+        assert!(handle_automask_message(&command("other"), &mut samplers, &mut config).is_err());
+        assert!(handle_automask_message(&[0xc1], &mut samplers, &mut config).is_err());
+        // Nothing was changed
+        assert!(config
+            .devices()
+            .iter()
+            .all(|d| d.scan_mask_thresholds.is_some()));
+    }
+
+    #[test]
+    fn test_sampler_completes_after_required_scans() {
+        let mut sampler = AutoMaskSampler::new(3, 50.);
+        assert!(sampler.add_samples(&[(10.2, 1000.), (20., 30.)]).is_none());
+        assert!(!sampler.is_complete());
+        assert!(sampler.add_samples(&[(10., 900.)]).is_none());
+
+        let mask = sampler.add_samples(&[(10., 5.)]).unwrap();
+        // Latest sample per (rounded) angle wins; too-close samples are ignored
+        assert_eq!(mask.len(), 1);
+        assert_eq!(mask.get("10"), Some(&850.));
+        assert!(sampler.is_complete());
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{default_clock, SharedClock},
+    geometry_utils::distance_points,
+    tracking::TrackedPoint2D,
+    Point2D,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Behaviour {
+    Stationary,
+    Walking,
+    Running,
+    /// Moving around, but not going anywhere
+    Loitering,
+}
+
+/// Thresholds for [`BehaviourAnalysis`]; distances and speeds are in the same units as
+/// smoothedTrackedPoints (e.g. mm and mm/s if using real units)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BehaviourSettings {
+    /// Each track is classified over this (rolling) window, in ms; tracks that are
+    /// younger than this are not classified yet
+    pub window_ms: u64,
+    /// Slower than this (average speed over the window) is "Stationary"
+    pub stationary_max_speed: f32,
+    /// At least this fast is "Running"
+    pub running_min_speed: f32,
+    /// Moving (neither stationary nor running) and ending up at least this far from where
+    /// the window started is "Walking"; having moved at least twice this far, but ending up
+    /// closer, is "Loitering". Anything in between keeps the previous behaviour.
+    pub loitering_max_displacement: f32,
+}
+
+impl Default for BehaviourSettings {
+    fn default() -> Self {
+        BehaviourSettings {
+            window_ms: 3000,
+            stationary_max_speed: 150.,
+            running_min_speed: 2500.,
+            loitering_max_displacement: 500.,
+        }
+    }
+}
+
+/// As published on "behaviour"; `behaviour` is null once the track has expired
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BehaviourChange {
+    pub id: usize,
+    pub behaviour: Option<Behaviour>,
+    pub previous: Option<Behaviour>,
+}
+
+struct TrackHistory {
+    /// The (latest) ID of the track, as published
+    id: usize,
+    positions: VecDeque<(Instant, Point2D)>,
+    behaviour: Option<Behaviour>,
+}
+
+/// Classifies each smoothed tracked point by how it has moved over a time window
+pub struct BehaviourAnalysis {
+    settings: BehaviourSettings,
+    /// By track key (see [`TrackedPoint2D::track_key`]), so that a reused ID starts afresh
+    tracks: HashMap<String, TrackHistory>,
+    clock: SharedClock,
+}
+
+impl BehaviourAnalysis {
+    pub fn new(settings: BehaviourSettings) -> Self {
+        BehaviourAnalysis::with_clock(settings, default_clock())
+    }
+
+    /// As per [`BehaviourAnalysis::new`], but using the given Clock as the source of time
+    pub fn with_clock(settings: BehaviourSettings, clock: SharedClock) -> Self {
+        BehaviourAnalysis {
+            settings,
+            tracks: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Add the latest positions of all (active) smoothed points; returns any changes
+    /// in behaviour, ordered by ID
+    pub fn update(&mut self, points: &[TrackedPoint2D]) -> Vec<BehaviourChange> {
+        let now = self.clock.now();
+        let window = Duration::from_millis(self.settings.window_ms);
+        let mut changes = Vec::new();
+
+        for p in points {
+            let track = self
+                .tracks
+                .entry(p.track_key())
+                .or_insert_with(|| TrackHistory {
+                    id: p.id,
+                    positions: VecDeque::new(),
+                    behaviour: None,
+                });
+            track.id = p.id;
+            track.positions.push_back((now, (p.x, p.y)));
+            // Keep one sample at (or before) the start of the window
+            while track.positions.len() > 2
+                && now.saturating_duration_since(track.positions[1].0) >= window
+            {
+                track.positions.pop_front();
+            }
+
+            let behaviour = classify(&track.positions, window, &self.settings);
+            if behaviour.is_some() && behaviour != track.behaviour {
+                changes.push(BehaviourChange {
+                    id: p.id,
+                    behaviour,
+                    previous: track.behaviour,
+                });
+                track.behaviour = behaviour;
+            }
+        }
+
+        let active_keys: HashSet<String> = points.iter().map(|p| p.track_key()).collect();
+        self.tracks.retain(|key, track| {
+            let is_active = active_keys.contains(key);
+            if !is_active && track.behaviour.is_some() {
+                changes.push(BehaviourChange {
+                    id: track.id,
+                    behaviour: None,
+                    previous: track.behaviour,
+                });
+            }
+            is_active
+        });
+
+        changes.sort_by_key(|c| c.id);
+        changes
+    }
+
+    /// The current behaviour of the given track, if it has been classified
+    pub fn behaviour(&self, id: usize) -> Option<Behaviour> {
+        self.tracks
+            .values()
+            .find(|track| track.id == id)
+            .and_then(|track| track.behaviour)
+    }
+}
+
+/// None if the track is too young to classify, or its behaviour is ambiguous
+fn classify(
+    positions: &VecDeque<(Instant, Point2D)>,
+    window: Duration,
+    settings: &BehaviourSettings,
+) -> Option<Behaviour> {
+    let (start_time, start) = positions.front()?;
+    let (end_time, end) = positions.back()?;
+    let elapsed = end_time.saturating_duration_since(*start_time);
+    if elapsed < window || elapsed.is_zero() {
+        return None;
+    }
+
+    let path_length: f32 = positions
+        .iter()
+        .zip(positions.iter().skip(1))
+        .map(|((_, a), (_, b))| distance_points(a, b))
+        .sum();
+    let speed = path_length / elapsed.as_secs_f32();
+
+    let displacement = distance_points(start, end);
+
+    if speed < settings.stationary_max_speed {
+        Some(Behaviour::Stationary)
+    } else if speed >= settings.running_min_speed {
+        Some(Behaviour::Running)
+    } else if displacement >= settings.loitering_max_displacement {
+        Some(Behaviour::Walking)
+    } else if path_length >= 2. * settings.loitering_max_displacement {
+        Some(Behaviour::Loitering)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    const STEP_MS: u64 = 100;
+
+    fn analysis(clock: &ManualClock) -> BehaviourAnalysis {
+        BehaviourAnalysis::with_clock(
+            BehaviourSettings {
+                window_ms: 1000,
+                ..Default::default()
+            },
+            clock.shared(),
+        )
+    }
+
+    /// Run for the given time, with the position of a single track (ID 0) given by a
+    /// function of elapsed seconds; returns all changes
+    fn run(
+        analysis: &mut BehaviourAnalysis,
+        clock: &ManualClock,
+        ms: u64,
+        position: impl Fn(f32) -> Point2D,
+    ) -> Vec<BehaviourChange> {
+        let mut changes = Vec::new();
+        for step in 0..(ms / STEP_MS) {
+            let t = (step * STEP_MS) as f32 / 1000.;
+            changes.extend(analysis.update(&[TrackedPoint2D::new(0, position(t), None)]));
+            clock.advance_ms(STEP_MS);
+        }
+        changes
+    }
+
+    fn behaviours(changes: &[BehaviourChange]) -> Vec<Option<Behaviour>> {
+        changes.iter().map(|c| c.behaviour).collect()
+    }
+
+    #[test]
+    fn test_classification() {
+        let clock = ManualClock::new();
+        let mut analysis = analysis(&clock);
+
+        // Not classified until the window is full
+        assert!(run(&mut analysis, &clock, 500, |_| (0., 0.)).is_empty());
+        assert_eq!(analysis.behaviour(0), None);
+
+        let changes = run(&mut analysis, &clock, 1000, |_| (0., 0.));
+        assert_eq!(behaviours(&changes), [Some(Behaviour::Stationary)]);
+        assert_eq!(changes[0].previous, None);
+
+        // 1m/s in a straight line
+        let changes = run(&mut analysis, &clock, 2000, |t| (t * 1000., 0.));
+        assert_eq!(behaviours(&changes), [Some(Behaviour::Walking)]);
+        assert_eq!(changes[0].previous, Some(Behaviour::Stationary));
+
+        // 3m/s
+        let changes = run(&mut analysis, &clock, 2000, |t| (t * 3000., 0.));
+        assert_eq!(behaviours(&changes), [Some(Behaviour::Running)]);
+
+        // Pacing back and forth (twice a second), 0.15m either side
+        let changes = run(&mut analysis, &clock, 3000, |t| {
+            ((t * 4. * std::f32::consts::PI).sin() * 150., 0.)
+        });
+        assert_eq!(behaviours(&changes), [Some(Behaviour::Loitering)]);
+        assert_eq!(analysis.behaviour(0), Some(Behaviour::Loitering));
+
+        // Neither starting to walk nor slowing to a stop is loitering (or walking)
+        let changes = run(&mut analysis, &clock, 3000, |_| (0., 0.));
+        assert_eq!(behaviours(&changes), [Some(Behaviour::Stationary)]);
+        let changes = run(&mut analysis, &clock, 2000, |t| (t * 1000., 0.));
+        assert_eq!(behaviours(&changes), [Some(Behaviour::Walking)]);
+    }
+
+    #[test]
+    fn test_expired_tracks() {
+        let clock = ManualClock::new();
+        let mut analysis = analysis(&clock);
+        run(&mut analysis, &clock, 1500, |_| (0., 0.));
+
+        let changes = analysis.update(&[]);
+        assert_eq!(
+            changes,
+            [BehaviourChange {
+                id: 0,
+                behaviour: None,
+                previous: Some(Behaviour::Stationary)
+            }]
+        );
+        assert_eq!(analysis.behaviour(0), None);
+        assert!(analysis.update(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_reused_id_is_a_new_track() {
+        let clock = ManualClock::new();
+        let mut analysis = analysis(&clock);
+        let point = |key: &str| {
+            let mut p = TrackedPoint2D::new(0, (0., 0.), None);
+            p.key = Some(key.into());
+            p
+        };
+        for _ in 0..15 {
+            analysis.update(&[point("first")]);
+            clock.advance_ms(STEP_MS);
+        }
+        assert_eq!(analysis.behaviour(0), Some(Behaviour::Stationary));
+
+        // The first track has expired, and its ID has been given to another one
+        let changes = analysis.update(&[point("second")]);
+        assert_eq!(behaviours(&changes), [None]);
+        assert_eq!(analysis.behaviour(0), None);
+    }
+}
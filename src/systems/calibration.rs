@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use nalgebra::{DMatrix, DVector};
+
+use crate::{backend_config::CalibrationPoint, Point2D};
+
+/// A thin-plate spline mapping from world space (as seen by the LIDARs) to the output
+/// space, which passes exactly through every control point and bends as little as
+/// possible in between. Unlike the 4-corner perspective transform, this can follow
+/// curved screens, misaligned projectors, etc.
+#[derive(Debug)]
+pub struct ThinPlateSpline {
+    sources: Vec<(f64, f64)>,
+    /// Per-control-point weights, for X and Y outputs
+    weights: Vec<(f64, f64)>,
+    /// Affine part: constant, x and y coefficients, for X and Y outputs
+    affine: [(f64, f64); 3],
+    /// Sources are normalised (centred and scaled) for numerical stability
+    centre: (f64, f64),
+    scale: f64,
+}
+
+/// Radial basis function for thin-plate splines: r^2 log(r^2) (but 0 at r=0)
+fn radial_basis(r_squared: f64) -> f64 {
+    if r_squared <= f64::EPSILON {
+        0.
+    } else {
+        r_squared * r_squared.ln()
+    }
+}
+
+impl ThinPlateSpline {
+    /// Needs at least 3 control points, which must not all lie on a line
+    pub fn new(points: &[CalibrationPoint]) -> Result<Self> {
+        let n = points.len();
+        if n < 3 {
+            return Err(anyhow!(
+                "At least 3 calibration points are required, got {}",
+                n
+            ));
+        }
+
+        let count = n as f64;
+        let centre = points.iter().fold((0., 0.), |acc, p| {
+            (
+                acc.0 + p.source.0 as f64 / count,
+                acc.1 + p.source.1 as f64 / count,
+            )
+        });
+        let scale = points
+            .iter()
+            .map(|p| {
+                let (dx, dy) = (p.source.0 as f64 - centre.0, p.source.1 as f64 - centre.1);
+                (dx * dx + dy * dy).sqrt()
+            })
+            .fold(0., f64::max)
+            .max(f64::EPSILON);
+        let normalised: Vec<(f64, f64)> = points
+            .iter()
+            .map(|p| {
+                (
+                    (p.source.0 as f64 - centre.0) / scale,
+                    (p.source.1 as f64 - centre.1) / scale,
+                )
+            })
+            .collect();
+
+        let (x0, y0) = normalised[0];
+        let spans_an_area = normalised.iter().any(|(xi, yi)| {
+            normalised
+                .iter()
+                .any(|(xj, yj)| ((xi - x0) * (yj - y0) - (yi - y0) * (xj - x0)).abs() > 1e-6)
+        });
+        if !spans_an_area {
+            return Err(anyhow!(
+                "Calibration points must not all lie on a single line"
+            ));
+        }
+
+        // Solve [K P; P^T 0] [w; a] = [v; 0]
+        let size = n + 3;
+        let mut l = DMatrix::<f64>::zeros(size, size);
+        let mut bx = DVector::<f64>::zeros(size);
+        let mut by = DVector::<f64>::zeros(size);
+        for (i, (xi, yi)) in normalised.iter().enumerate() {
+            for (j, (xj, yj)) in normalised.iter().enumerate() {
+                l[(i, j)] = radial_basis((xi - xj).powi(2) + (yi - yj).powi(2));
+            }
+            for (k, value) in [1., *xi, *yi].into_iter().enumerate() {
+                l[(i, n + k)] = value;
+                l[(n + k, i)] = value;
+            }
+            bx[i] = points[i].target.0 as f64;
+            by[i] = points[i].target.1 as f64;
+        }
+
+        let lu = l.lu();
+        let (solution_x, solution_y) = match (lu.solve(&bx), lu.solve(&by)) {
+            (Some(x), Some(y)) if x.iter().chain(y.iter()).all(|v| v.is_finite()) => (x, y),
+            _ => {
+                return Err(anyhow!(
+                    "Failed to solve calibration; are there duplicate points?"
+                ))
+            }
+        };
+
+        Ok(ThinPlateSpline {
+            sources: normalised,
+            weights: (0..n).map(|i| (solution_x[i], solution_y[i])).collect(),
+            affine: [0, 1, 2].map(|k| (solution_x[n + k], solution_y[n + k])),
+            centre,
+            scale,
+        })
+    }
+
+    pub fn transform(&self, point: &Point2D) -> Point2D {
+        let x = (point.0 as f64 - self.centre.0) / self.scale;
+        let y = (point.1 as f64 - self.centre.1) / self.scale;
+
+        let [(cx, cy), (xx, xy), (yx, yy)] = self.affine;
+        let (mut out_x, mut out_y) = (cx + xx * x + yx * y, cy + xy * x + yy * y);
+        for ((sx, sy), (wx, wy)) in self.sources.iter().zip(self.weights.iter()) {
+            let u = radial_basis((x - sx).powi(2) + (y - sy).powi(2));
+            out_x += wx * u;
+            out_y += wy * u;
+        }
+        (out_x as f32, out_y as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(source: Point2D, target: Point2D) -> CalibrationPoint {
+        CalibrationPoint { source, target }
+    }
+
+    fn assert_close(actual: Point2D, expected: Point2D, tolerance: f32) {
+        assert!(
+            (actual.0 - expected.0).abs() < tolerance && (actual.1 - expected.1).abs() < tolerance,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_requires_enough_non_collinear_points() {
+        assert!(ThinPlateSpline::new(&[point((0., 0.), (0., 0.))]).is_err());
+        assert!(ThinPlateSpline::new(&[
+            point((0., 0.), (0., 0.)),
+            point((1000., 0.), (1., 0.)),
+            point((2000., 0.), (2., 0.)),
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_affine_mapping_is_exact() {
+        // Scale from mm to normalised, with an offset; a TPS with only affine
+        // control points should reproduce this everywhere
+        let points = [
+            point((-2000., 1000.), (0., 0.)),
+            point((2000., 1000.), (1., 0.)),
+            point((2000., 5000.), (1., 1.)),
+            point((-2000., 5000.), (0., 1.)),
+        ];
+        let tps = ThinPlateSpline::new(&points).unwrap();
+        for p in points.iter() {
+            assert_close(tps.transform(&p.source), p.target, 0.0001);
+        }
+        assert_close(tps.transform(&(0., 3000.)), (0.5, 0.5), 0.0001);
+        assert_close(tps.transform(&(-1000., 2000.)), (0.25, 0.25), 0.0001);
+    }
+
+    #[test]
+    fn test_passes_through_non_linear_control_points() {
+        // A 3x3 grid, with the middle point displaced (e.g. a bulge in the screen)
+        let mut points = Vec::new();
+        for i in 0..3 {
+            for j in 0..3 {
+                let source = (i as f32 * 1000., j as f32 * 1000.);
+                let target = if i == 1 && j == 1 {
+                    (1.2, 1.1)
+                } else {
+                    (i as f32, j as f32)
+                };
+                points.push(point(source, target));
+            }
+        }
+        let tps = ThinPlateSpline::new(&points).unwrap();
+        for p in points.iter() {
+            assert_close(tps.transform(&p.source), p.target, 0.0001);
+        }
+        // In between, the displacement is partly followed
+        let (x, y) = tps.transform(&(750., 750.));
+        assert!(x > 0.75 && x < 1.2, "{}", x);
+        assert!(y > 0.75 && y < 1.1, "{}", y);
+    }
+}
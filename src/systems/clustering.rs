@@ -1,4 +1,4 @@
-use crate::{backend_config::LidarDevice, Point2D};
+use crate::{backend_config::LidarDevice, geometry_utils::distance_points, Point2D};
 
 use indexmap::IndexMap;
 use log::debug;
@@ -18,6 +18,26 @@ pub struct Cluster2D {
     pub size: f32,
 }
 
+/// Settings for treating pairs of small clusters (e.g. two legs, with LIDARs at ankle
+/// height) as a single person
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LegPairSettings {
+    /// Only clusters up to this size (mm) can be legs
+    pub max_leg_size: f32,
+    /// Pair legs whose centres are at most this far apart (mm)
+    pub max_stride: f32,
+}
+
+impl Default for LegPairSettings {
+    fn default() -> Self {
+        LegPairSettings {
+            max_leg_size: 250.,
+            max_stride: 700.,
+        }
+    }
+}
+
 struct Bounds2D {
     x_min: Option<f32>,
     y_min: Option<f32>,
@@ -30,6 +50,7 @@ pub struct ClusteringSystem {
     clustering_engine: Dbscan<f32, Euclidean>,
     cached_clusters: Vec<Cluster2D>,
     max_cluster_size: f32,
+    leg_pairing: Option<LegPairSettings>,
 }
 
 impl ClusteringSystem {
@@ -47,9 +68,16 @@ impl ClusteringSystem {
             },
             cached_clusters: Vec::new(),
             max_cluster_size,
+            leg_pairing: None,
         }
     }
 
+    /// Merge pairs of leg-sized clusters into one (see [`pair_legs`]), if settings are given
+    pub fn with_leg_pairing(mut self, settings: Option<LegPairSettings>) -> Self {
+        self.leg_pairing = settings;
+        self
+    }
+
     /** A snapshot of the most recently-calculated clusters list */
     pub fn clusters(&self) -> &[Cluster2D] {
         &self.cached_clusters
@@ -82,7 +110,7 @@ impl ClusteringSystem {
             outliers.len()
         );
 
-        self.cached_clusters = clusters
+        let clusters: Vec<Cluster2D> = clusters
             .iter()
             .map(|c| {
                 let (cluster_index, point_indexes) = c;
@@ -97,7 +125,12 @@ impl ClusteringSystem {
                 circle_of_cluster_points(matched_points, *cluster_index)
             })
             .filter(|cluster| cluster.size <= self.max_cluster_size)
-            .collect()
+            .collect();
+
+        self.cached_clusters = match &self.leg_pairing {
+            Some(settings) => pair_legs(&clusters, settings),
+            None => clusters,
+        };
     }
 
     // pub fn update_from_external_tracker(&mut self, points: &[Point2D], tracker: &ExternalTracker) {
@@ -219,10 +252,64 @@ pub fn circle_of_cluster_points(points: Vec<Point2D>, id: usize) -> Cluster2D {
     }
 }
 
+/**
+Merge leg-sized clusters into pairs, closest pairs first, so that each person is a single
+cluster (enclosing both legs, and keeping the ID of the first). Anything that cannot be
+paired is kept as it is.
+*/
+pub fn pair_legs(clusters: &[Cluster2D], settings: &LegPairSettings) -> Vec<Cluster2D> {
+    let is_leg = |c: &Cluster2D| c.size <= settings.max_leg_size;
+    let mut candidates: Vec<(f32, usize, usize)> = Vec::new();
+    for (i, a) in clusters.iter().enumerate().filter(|(_, c)| is_leg(c)) {
+        for (j, b) in clusters
+            .iter()
+            .enumerate()
+            .skip(i + 1)
+            .filter(|(_, c)| is_leg(c))
+        {
+            let d = distance_points(&(a.x, a.y), &(b.x, b.y));
+            if d <= settings.max_stride {
+                candidates.push((d, i, j));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut paired_with: Vec<Option<usize>> = vec![None; clusters.len()];
+    let mut is_paired = vec![false; clusters.len()];
+    for (_, i, j) in candidates {
+        if !is_paired[i] && !is_paired[j] {
+            paired_with[i] = Some(j);
+            is_paired[i] = true;
+            is_paired[j] = true;
+        }
+    }
+
+    clusters
+        .iter()
+        .enumerate()
+        .filter_map(|(i, c)| match paired_with[i] {
+            Some(j) => {
+                let other = &clusters[j];
+                let extremes = [c, other]
+                    .iter()
+                    .flat_map(|leg| {
+                        let r = leg.size / 2.;
+                        [(leg.x - r, leg.y - r), (leg.x + r, leg.y + r)]
+                    })
+                    .collect();
+                Some(circle_of_cluster_points(extremes, c.id))
+            }
+            None if is_paired[i] => None,
+            None => Some(c.clone()),
+        })
+        .collect()
+}
+
 /**
 Take in angle and distance, return as Point2D i.e. (x,y) coordinates
 */
-fn scan_sample_to_point(angle: &f32, distance: &f32, device: &LidarDevice) -> Option<Point2D> {
+pub fn scan_sample_to_point(angle: &f32, distance: &f32, device: &LidarDevice) -> Option<Point2D> {
     let LidarDevice {
         x,
         y,
@@ -297,3 +384,205 @@ fn passes_mask_threshold(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(rotation: f32, flip_coords: Option<(i8, i8)>) -> LidarDevice {
+        LidarDevice {
+            serial: "test".into(),
+            name: "test".into(),
+            rotation,
+            x: 100.,
+            y: 200.,
+            colour: "#ffffff".into(),
+            min_distance_threshold: 20.,
+            scan_mask_thresholds: None,
+            flip_coords,
+        }
+    }
+
+    fn assert_near(actual: Option<Point2D>, expected: Point2D) {
+        let (x, y) = actual.expect("expected a point");
+        assert!(
+            (x - expected.0).abs() < 0.01 && (y - expected.1).abs() < 0.01,
+            "{:?} != {:?}",
+            (x, y),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_scan_sample_to_point_no_rotation() {
+        // With no rotation, any flip combination simply mirrors on that axis
+        for (flip, expected) in [
+            (None, (1100., 200.)),
+            (Some((1, 1)), (1100., 200.)),
+            (Some((-1, 1)), (-900., 200.)),
+            (Some((1, -1)), (1100., 200.)),
+            (Some((-1, -1)), (-900., 200.)),
+        ] {
+            assert_near(
+                scan_sample_to_point(&90., &1000., &device(0., flip)),
+                expected,
+            );
+        }
+        for (flip, expected) in [
+            (None, (100., 1200.)),
+            (Some((1, 1)), (100., 1200.)),
+            (Some((-1, 1)), (100., 1200.)),
+            (Some((1, -1)), (100., -800.)),
+            (Some((-1, -1)), (100., -800.)),
+        ] {
+            assert_near(
+                scan_sample_to_point(&0., &1000., &device(0., flip)),
+                expected,
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_sample_to_point_with_rotation() {
+        // When exactly one axis is flipped, rotation is reversed
+        for (flip, expected) in [
+            (None, (1100., 200.)),
+            (Some((1, 1)), (1100., 200.)),
+            (Some((-1, 1)), (1100., 200.)),
+            (Some((1, -1)), (-900., 200.)),
+            (Some((-1, -1)), (-900., 200.)),
+        ] {
+            assert_near(
+                scan_sample_to_point(&0., &1000., &device(90., flip)),
+                expected,
+            );
+        }
+        for (flip, expected) in [
+            (None, (100., -800.)),
+            (Some((1, 1)), (100., -800.)),
+            (Some((-1, 1)), (100., 1200.)),
+            (Some((1, -1)), (100., -800.)),
+            (Some((-1, -1)), (100., 1200.)),
+        ] {
+            assert_near(
+                scan_sample_to_point(&90., &1000., &device(90., flip)),
+                expected,
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_sample_to_point_thresholds() {
+        let mut d = device(0., None);
+        assert!(scan_sample_to_point(&0., &0., &d).is_none());
+        assert!(scan_sample_to_point(&0., &20., &d).is_none());
+        assert!(scan_sample_to_point(&0., &21., &d).is_some());
+
+        let mut mask = IndexMap::new();
+        mask.insert("0".to_string(), 500.);
+        d.scan_mask_thresholds = Some(mask);
+        assert!(scan_sample_to_point(&0., &499., &d).is_some());
+        assert!(scan_sample_to_point(&0., &500., &d).is_none());
+        assert!(scan_sample_to_point(&1., &5000., &d).is_some());
+    }
+
+    #[test]
+    fn test_passes_mask_threshold() {
+        assert!(passes_mask_threshold(&90., &10000., &None));
+
+        let mut mask = IndexMap::new();
+        mask.insert("90".to_string(), 500.);
+        let mask = Some(mask);
+        assert!(passes_mask_threshold(&90., &400., &mask));
+        assert!(!passes_mask_threshold(&90., &600., &mask));
+        // Angles are rounded to the nearest degree
+        assert!(!passes_mask_threshold(&90.4, &600., &mask));
+        assert!(!passes_mask_threshold(&89.6, &600., &mask));
+        assert!(passes_mask_threshold(&90.6, &600., &mask));
+        // Angles without a threshold always pass
+        assert!(passes_mask_threshold(&45., &600., &mask));
+    }
+
+    #[test]
+    fn test_circle_of_cluster_points() {
+        let cluster = circle_of_cluster_points(vec![(0., 0.), (100., 0.), (100., 50.)], 7);
+        assert_eq!(cluster.id, 7);
+        assert_eq!(cluster.x, 50.);
+        assert_eq!(cluster.y, 25.);
+        assert_eq!(cluster.size, 100.);
+
+        let single = circle_of_cluster_points(vec![(-10., 20.)], 0);
+        assert_eq!((single.x, single.y, single.size), (-10., 20., 0.));
+    }
+
+    #[test]
+    fn test_update_from_scan_finds_clusters() {
+        let mut clustering = ClusteringSystem::new(200., 4, 2500.);
+        let d = device(0., None);
+        // Two tight groups of samples, well apart
+        let samples: Vec<Point2D> = (0..8)
+            .map(|i| (i as f32 * 0.5, 1000.))
+            .chain((0..8).map(|i| (90. + i as f32 * 0.5, 2000.)))
+            .collect();
+        clustering.update_from_scan(&samples, &d);
+        assert_eq!(clustering.clusters().len(), 2);
+
+        // A max cluster size smaller than both groups excludes them
+        let mut clustering = ClusteringSystem::new(200., 4, 1.);
+        clustering.update_from_scan(&samples, &d);
+        assert!(clustering.clusters().is_empty());
+    }
+
+    fn leg(id: usize, x: f32, y: f32, size: f32) -> Cluster2D {
+        Cluster2D { id, x, y, size }
+    }
+
+    #[test]
+    fn test_pair_legs() {
+        let settings = LegPairSettings {
+            max_leg_size: 200.,
+            max_stride: 500.,
+        };
+        let clusters = [
+            // Three legs in a row: the closest two are paired, and the third kept alone
+            leg(0, 0., 0., 100.),
+            leg(1, 400., 0., 100.),
+            leg(2, 300., 0., 100.),
+            // Too big to be a leg
+            leg(3, 300., 300., 400.),
+            // Two more legs, too far from the others
+            leg(4, 2000., 0., 150.),
+            leg(5, 2000., 200., 150.),
+        ];
+        let people = pair_legs(&clusters, &settings);
+        let summary: Vec<(usize, f32, f32, f32)> =
+            people.iter().map(|c| (c.id, c.x, c.y, c.size)).collect();
+        assert_eq!(
+            summary,
+            [
+                (0, 0., 0., 100.),
+                (1, 350., 0., 200.),
+                (3, 300., 300., 400.),
+                (4, 2000., 100., 350.),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_update_from_scan_pairs_legs() {
+        let d = device(0., None);
+        // Two small groups of samples 300mm apart, around 2000mm in front of the device
+        let samples: Vec<Point2D> = (0..8)
+            .map(|i| (-4. + i as f32 * 0.25, 2000.))
+            .chain((0..8).map(|i| (4. + i as f32 * 0.25, 2000.)))
+            .collect();
+        let mut clustering = ClusteringSystem::new(100., 4, 2500.);
+        clustering.update_from_scan(&samples, &d);
+        assert_eq!(clustering.clusters().len(), 2);
+
+        let mut clustering = ClusteringSystem::new(100., 4, 2500.)
+            .with_leg_pairing(Some(LegPairSettings::default()));
+        clustering.update_from_scan(&samples, &d);
+        assert_eq!(clustering.clusters().len(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{default_clock, SharedClock},
+    tracking::TrackedPoint2D,
+    Point2D,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CrowdStatsSettings {
+    /// Number of flow field cells across the ROI (along A→B)
+    pub flow_columns: usize,
+    /// Number of flow field cells along the depth of the ROI (along A→D)
+    pub flow_rows: usize,
+    /// How often (ms) to publish stats
+    pub publish_interval: u64,
+    /// Entry and exit rates are calculated over this (rolling) window, in ms
+    pub rate_window: u64,
+}
+
+impl Default for CrowdStatsSettings {
+    fn default() -> Self {
+        CrowdStatsSettings {
+            flow_columns: 4,
+            flow_rows: 4,
+            publish_interval: 1000,
+            rate_window: 60000,
+        }
+    }
+}
+
+/// Mean velocity of the smoothed tracked points in each cell of a coarse grid over the
+/// ROI, row by row starting with the row along the A→B edge. Cells with no (moving)
+/// points are null.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FlowField {
+    pub columns: usize,
+    pub rows: usize,
+    pub cells: Vec<Option<[f32; 2]>>,
+}
+
+/// As published on "crowdStats"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CrowdStats {
+    /// Number of active smoothed tracked points
+    pub headcount: usize,
+    /// People per square metre of the ROI
+    pub density: f32,
+    /// Velocities are in the same units as smoothedTrackedPoints (per second)
+    pub flow_field: FlowField,
+    /// New tracked points per minute, over the rate window
+    pub entry_rate: f32,
+    /// Tracked points that expired per minute, over the rate window
+    pub exit_rate: f32,
+}
+
+/// Aggregate measures of the whole crowd, as opposed to individual points. This needs
+/// to see every smoothing update (to count IDs appearing and disappearing), but only
+/// publishes occasionally.
+pub struct CrowdAnalysis {
+    settings: CrowdStatsSettings,
+    /// IDs as of the last update; None until the first update, so that everyone already
+    /// present (e.g. after a restart) is not counted as an entry
+    known_ids: Option<HashSet<usize>>,
+    entries: VecDeque<Instant>,
+    exits: VecDeque<Instant>,
+    last_published: Instant,
+    clock: SharedClock,
+}
+
+impl CrowdAnalysis {
+    pub fn new(settings: CrowdStatsSettings) -> Self {
+        CrowdAnalysis::with_clock(settings, default_clock())
+    }
+
+    /// As per [`CrowdAnalysis::new`], but using the given Clock as the source of time
+    pub fn with_clock(settings: CrowdStatsSettings, clock: SharedClock) -> Self {
+        CrowdAnalysis {
+            settings,
+            known_ids: None,
+            entries: VecDeque::new(),
+            exits: VecDeque::new(),
+            last_published: clock.now(),
+            clock,
+        }
+    }
+
+    /// Record entries and exits since the last update
+    pub fn update(&mut self, points: &[TrackedPoint2D]) {
+        let now = self.clock.now();
+        let ids: HashSet<usize> = points.iter().map(|p| p.id).collect();
+        if let Some(known_ids) = &self.known_ids {
+            for _ in ids.difference(known_ids) {
+                self.entries.push_back(now);
+            }
+            for _ in known_ids.difference(&ids) {
+                self.exits.push_back(now);
+            }
+        }
+        self.known_ids = Some(ids);
+
+        let window = Duration::from_millis(self.settings.rate_window);
+        for events in [&mut self.entries, &mut self.exits] {
+            while events
+                .front()
+                .is_some_and(|t| now.saturating_duration_since(*t) > window)
+            {
+                events.pop_front();
+            }
+        }
+    }
+
+    pub fn should_publish(&self) -> bool {
+        self.clock.elapsed_since(self.last_published)
+            >= Duration::from_millis(self.settings.publish_interval)
+    }
+
+    pub fn reset_timer(&mut self) {
+        self.last_published = self.clock.now();
+    }
+
+    /// Stats for the given points; `normalised_positions` are the same points in
+    /// normalised [0;1] ROI coordinates, and `roi_size` is the size of the ROI in mm
+    pub fn stats(
+        &self,
+        points: &[TrackedPoint2D],
+        normalised_positions: &[Point2D],
+        roi_size: Point2D,
+    ) -> CrowdStats {
+        let area_square_metres = roi_size.0 * roi_size.1 / 1_000_000.;
+        let per_minute = 60000. / self.settings.rate_window.max(1) as f32;
+
+        CrowdStats {
+            headcount: points.len(),
+            density: if area_square_metres > 0. {
+                points.len() as f32 / area_square_metres
+            } else {
+                0.
+            },
+            flow_field: flow_field(
+                points,
+                normalised_positions,
+                self.settings.flow_columns,
+                self.settings.flow_rows,
+            ),
+            entry_rate: self.entries.len() as f32 * per_minute,
+            exit_rate: self.exits.len() as f32 * per_minute,
+        }
+    }
+}
+
+fn flow_field(
+    points: &[TrackedPoint2D],
+    normalised_positions: &[Point2D],
+    columns: usize,
+    rows: usize,
+) -> FlowField {
+    let (columns, rows) = (columns.max(1), rows.max(1));
+    let mut sums = vec![([0., 0.], 0); columns * rows];
+
+    for (p, (u, v)) in points.iter().zip(normalised_positions) {
+        let Some([vx, vy]) = p.velocity else {
+            continue;
+        };
+        if !(0. ..=1.).contains(u) || !(0. ..=1.).contains(v) {
+            continue;
+        }
+        let column = ((u * columns as f32) as usize).min(columns - 1);
+        let row = ((v * rows as f32) as usize).min(rows - 1);
+        let (sum, count) = &mut sums[row * columns + column];
+        sum[0] += vx;
+        sum[1] += vy;
+        *count += 1;
+    }
+
+    FlowField {
+        columns,
+        rows,
+        cells: sums
+            .into_iter()
+            .map(|(sum, count)| {
+                if count == 0 {
+                    None
+                } else {
+                    Some([sum[0] / count as f32, sum[1] / count as f32])
+                }
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn point(id: usize, velocity: Option<[f32; 2]>) -> TrackedPoint2D {
+        let mut p = TrackedPoint2D::new(id, (0., 0.), None);
+        p.velocity = velocity;
+        p
+    }
+
+    #[test]
+    fn test_headcount_density_and_flow() {
+        let clock = ManualClock::new();
+        let analysis = CrowdAnalysis::with_clock(
+            CrowdStatsSettings {
+                flow_columns: 2,
+                flow_rows: 1,
+                ..Default::default()
+            },
+            clock.shared(),
+        );
+
+        let points = [
+            point(0, Some([1., 0.])),
+            point(1, Some([3., 2.])),
+            point(2, Some([0., -1.])),
+            // No velocity: counted, but not part of the flow field
+            point(3, None),
+        ];
+        let positions = [(0.1, 0.5), (0.4, 0.9), (0.8, 0.2), (0.8, 0.8)];
+        let stats = analysis.stats(&points, &positions, (4000., 2000.));
+
+        assert_eq!(stats.headcount, 4);
+        assert_eq!(stats.density, 0.5);
+        assert_eq!(
+            stats.flow_field.cells,
+            vec![Some([2., 1.]), Some([0., -1.])]
+        );
+    }
+
+    #[test]
+    fn test_entry_and_exit_rates() {
+        let clock = ManualClock::new();
+        let mut analysis = CrowdAnalysis::with_clock(
+            CrowdStatsSettings {
+                rate_window: 30000,
+                ..Default::default()
+            },
+            clock.shared(),
+        );
+        let rates = |analysis: &CrowdAnalysis| {
+            let stats = analysis.stats(&[], &[], (1000., 1000.));
+            (stats.entry_rate, stats.exit_rate)
+        };
+
+        // Whoever is there to begin with has not "entered"
+        analysis.update(&[point(0, None), point(1, None)]);
+        assert_eq!(rates(&analysis), (0., 0.));
+
+        clock.advance_ms(10000);
+        analysis.update(&[point(1, None), point(2, None), point(3, None)]);
+        // 2 entries and 1 exit in 30s
+        assert_eq!(rates(&analysis), (4., 2.));
+
+        // Events drop out of the window
+        clock.advance_ms(25000);
+        analysis.update(&[point(1, None), point(2, None)]);
+        assert_eq!(rates(&analysis), (4., 4.));
+        clock.advance_ms(10000);
+        analysis.update(&[point(1, None), point(2, None)]);
+        assert_eq!(rates(&analysis), (0., 2.));
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{default_clock, SharedClock},
+    geometry_utils::distance_points,
+    tracking::TrackedPoint2D,
+};
+
+use super::smoothing::{apply_empty_list_send_mode, EmptyListSendMode};
+
+/// Thresholds for [`GroupDetection`]; distances and speeds are in the same units as
+/// smoothedTrackedPoints (e.g. mm and mm/s if using real units)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupSettings {
+    /// Two points must stay at least this close...
+    pub max_distance: f32,
+    /// ...with velocities differing by no more than this...
+    pub max_speed_difference: f32,
+    /// ...for this long (ms), to be considered part of the same group
+    pub min_duration_ms: u64,
+}
+
+impl Default for GroupSettings {
+    fn default() -> Self {
+        GroupSettings {
+            max_distance: 1000.,
+            max_speed_difference: 300.,
+            min_duration_ms: 2000,
+        }
+    }
+}
+
+/// As published on "groups"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Group {
+    pub id: usize,
+    /// IDs of the smoothed tracked points in this group, in order
+    pub members: Vec<usize>,
+    /// Centroid of the members
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Finds groups of smoothed tracked points that stay close together and move alike.
+/// Any two such points are "linked"; a group is every point linked (directly or via
+/// others) to each other. A group keeps its ID for as long as it keeps any of its members.
+pub struct GroupDetection {
+    settings: GroupSettings,
+    /// When each (close, similar) pair of tracks started being so; by track key (see
+    /// [`TrackedPoint2D::track_key`]), lowest first, so that a reused ID starts afresh
+    pairs_since: HashMap<(String, String), Instant>,
+    groups: Vec<Group>,
+    /// The track keys of each group's members, in the same order as `groups`
+    group_keys: Vec<BTreeSet<String>>,
+    next_group_id: usize,
+    empty_lists_sent: u128,
+    clock: SharedClock,
+}
+
+impl GroupDetection {
+    pub fn new(settings: GroupSettings) -> Self {
+        GroupDetection::with_clock(settings, default_clock())
+    }
+
+    /// As per [`GroupDetection::new`], but using the given Clock as the source of time
+    pub fn with_clock(settings: GroupSettings, clock: SharedClock) -> Self {
+        GroupDetection {
+            settings,
+            pairs_since: HashMap::new(),
+            groups: Vec::new(),
+            group_keys: Vec::new(),
+            next_group_id: 0,
+            empty_lists_sent: 0,
+            clock,
+        }
+    }
+
+    /// Update with the latest (active) smoothed points; returns the current groups
+    pub fn update(&mut self, points: &[TrackedPoint2D]) -> &[Group] {
+        let now = self.clock.now();
+        let min_duration = Duration::from_millis(self.settings.min_duration_ms);
+
+        let keys: Vec<String> = points.iter().map(|p| p.track_key()).collect();
+        let mut pairs_since = HashMap::new();
+        // By index into the points
+        let mut links: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, a) in points.iter().enumerate() {
+            for (j, b) in points.iter().enumerate().skip(i + 1) {
+                if !self.are_alike(a, b) {
+                    continue;
+                }
+                let pair = if keys[i] < keys[j] {
+                    (keys[i].clone(), keys[j].clone())
+                } else {
+                    (keys[j].clone(), keys[i].clone())
+                };
+                let since = *self.pairs_since.get(&pair).unwrap_or(&now);
+                pairs_since.insert(pair, since);
+                if now.saturating_duration_since(since) >= min_duration {
+                    links.entry(i).or_default().push(j);
+                    links.entry(j).or_default().push(i);
+                }
+            }
+        }
+        self.pairs_since = pairs_since;
+
+        let components = connected_components(&links);
+        (self.groups, self.group_keys) = self.assign_group_ids(components, points, &keys);
+        &self.groups
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    /// The current groups, if they should be sent as per the empty list send mode
+    pub fn groups_to_send(&mut self, mode: EmptyListSendMode) -> Option<Vec<Group>> {
+        apply_empty_list_send_mode(self.groups.clone(), mode, &mut self.empty_lists_sent)
+    }
+
+    fn are_alike(&self, a: &TrackedPoint2D, b: &TrackedPoint2D) -> bool {
+        if distance_points(&(a.x, a.y), &(b.x, b.y)) > self.settings.max_distance {
+            return false;
+        }
+        let [avx, avy] = a.velocity.unwrap_or_default();
+        let [bvx, bvy] = b.velocity.unwrap_or_default();
+        distance_points(&(avx, avy), &(bvx, bvy)) <= self.settings.max_speed_difference
+    }
+
+    /// Re-use the ID of the previous group with the most members (by track key) in
+    /// common (largest groups first), otherwise use a new ID. The components are sets of
+    /// indexes into the points; returns the groups along with their members' keys.
+    fn assign_group_ids(
+        &mut self,
+        mut components: Vec<BTreeSet<usize>>,
+        points: &[TrackedPoint2D],
+        keys: &[String],
+    ) -> (Vec<Group>, Vec<BTreeSet<String>>) {
+        components.sort_by_key(|members| std::cmp::Reverse(members.len()));
+        let mut available: Vec<(usize, &BTreeSet<String>)> = self
+            .groups
+            .iter()
+            .map(|g| g.id)
+            .zip(self.group_keys.iter())
+            .collect();
+
+        let mut groups: Vec<(Group, BTreeSet<String>)> = components
+            .into_iter()
+            .map(|members| {
+                let member_keys: BTreeSet<String> =
+                    members.iter().map(|&i| keys[i].clone()).collect();
+                let previous = available
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (_, keys))| (index, keys.intersection(&member_keys).count()))
+                    .filter(|(_, shared)| *shared > 0)
+                    .max_by_key(|(_, shared)| *shared)
+                    .map(|(index, _)| available.remove(index).0);
+                let id = previous.unwrap_or_else(|| {
+                    self.next_group_id += 1;
+                    self.next_group_id - 1
+                });
+
+                let member_points: Vec<&TrackedPoint2D> =
+                    members.iter().map(|&i| &points[i]).collect();
+                let mut ids: Vec<usize> = member_points.iter().map(|p| p.id).collect();
+                ids.sort();
+                let count = member_points.len() as f32;
+                let group = Group {
+                    id,
+                    members: ids,
+                    x: member_points.iter().map(|p| p.x).sum::<f32>() / count,
+                    y: member_points.iter().map(|p| p.y).sum::<f32>() / count,
+                };
+                (group, member_keys)
+            })
+            .collect();
+
+        groups.sort_by_key(|(g, _)| g.id);
+        groups.into_iter().unzip()
+    }
+}
+
+fn connected_components(links: &HashMap<usize, Vec<usize>>) -> Vec<BTreeSet<usize>> {
+    let mut visited = BTreeSet::new();
+    let mut components = Vec::new();
+    let mut starts: Vec<usize> = links.keys().cloned().collect();
+    starts.sort();
+
+    for start in starts {
+        if visited.contains(&start) {
+            continue;
+        }
+        let mut component = BTreeSet::new();
+        let mut stack = vec![start];
+        while let Some(id) = stack.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            component.insert(id);
+            if let Some(neighbours) = links.get(&id) {
+                stack.extend(neighbours.iter().filter(|n| !visited.contains(n)));
+            }
+        }
+        components.push(component);
+    }
+    components
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn point(id: usize, x: f32, y: f32, velocity: [f32; 2]) -> TrackedPoint2D {
+        let mut p = TrackedPoint2D::new(id, (x, y), None);
+        p.velocity = Some(velocity);
+        p
+    }
+
+    fn members(groups: &[Group]) -> Vec<(usize, Vec<usize>)> {
+        groups.iter().map(|g| (g.id, g.members.clone())).collect()
+    }
+
+    #[test]
+    fn test_groups_form_after_min_duration() {
+        let clock = ManualClock::new();
+        let mut detection = GroupDetection::with_clock(GroupSettings::default(), clock.shared());
+
+        let points = [
+            // Walking together
+            point(0, 0., 0., [1000., 0.]),
+            point(1, 600., 0., [1100., 0.]),
+            // Close to 1, but standing still
+            point(2, 600., 500., [0., 0.]),
+            // Far away
+            point(3, 5000., 0., [1000., 0.]),
+        ];
+        assert!(detection.update(&points).is_empty());
+        clock.advance_ms(1000);
+        assert!(detection.update(&points).is_empty());
+        clock.advance_ms(1000);
+        let groups = detection.update(&points);
+        assert_eq!(members(groups), [(0, vec![0, 1])]);
+        assert_eq!((groups[0].x, groups[0].y), (300., 0.));
+    }
+
+    #[test]
+    fn test_groups_are_transitive_and_keep_their_ids() {
+        let clock = ManualClock::new();
+        let mut detection = GroupDetection::with_clock(
+            GroupSettings {
+                min_duration_ms: 0,
+                ..Default::default()
+            },
+            clock.shared(),
+        );
+
+        // 0 and 2 are too far apart, but both are close to 1
+        let a = point(0, 0., 0., [0., 0.]);
+        let b = point(1, 900., 0., [0., 0.]);
+        let c = point(2, 1800., 0., [0., 0.]);
+        let d = point(3, 9000., 0., [0., 0.]);
+        let e = point(4, 9500., 0., [0., 0.]);
+        assert_eq!(
+            members(detection.update(&[a.clone(), b.clone(), c.clone(), d, e.clone()])),
+            [(0, vec![0, 1, 2]), (1, vec![3, 4])]
+        );
+
+        // Someone leaves the first group; someone else joins it
+        let f = point(5, 2500., 0., [0., 0.]);
+        assert_eq!(
+            members(detection.update(&[b.clone(), c.clone(), e.clone(), f.clone()])),
+            [(0, vec![1, 2, 5])]
+        );
+
+        // The second group is gone, so a new one gets a new ID
+        let g = point(6, 9000., 0., [0., 0.]);
+        assert_eq!(
+            members(detection.update(&[b, c, e, f, g])),
+            [(0, vec![1, 2, 5]), (2, vec![4, 6])]
+        );
+    }
+
+    #[test]
+    fn test_reused_id_is_a_new_pair() {
+        let clock = ManualClock::new();
+        let mut detection = GroupDetection::with_clock(GroupSettings::default(), clock.shared());
+        let keyed = |id: usize, x: f32, key: &str| {
+            let mut p = point(id, x, 0., [0., 0.]);
+            p.key = Some(key.into());
+            p
+        };
+
+        let a = keyed(0, 0., "a");
+        assert!(detection
+            .update(&[a.clone(), keyed(1, 500., "b")])
+            .is_empty());
+        clock.advance_ms(1000);
+        // ID 1 now belongs to someone else, who has only just arrived
+        assert!(detection
+            .update(&[a.clone(), keyed(1, 500., "c")])
+            .is_empty());
+        clock.advance_ms(1000);
+        assert!(detection
+            .update(&[a.clone(), keyed(1, 500., "c")])
+            .is_empty());
+        clock.advance_ms(1000);
+        assert_eq!(
+            members(detection.update(&[a, keyed(1, 500., "c")])),
+            [(0, vec![0, 1])]
+        );
+    }
+}
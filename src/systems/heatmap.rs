@@ -0,0 +1,283 @@
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{default_clock, SharedClock},
+    Point2D,
+};
+
+/// Size (in pixels) of each cell when rendering the heatmap as a PNG
+const PNG_CELL_SIZE: usize = 8;
+
+/// Colour ramp for PNG rendering, from no occupancy to the busiest cell
+const PNG_COLOUR_STOPS: [[f32; 3]; 4] = [
+    [0., 0., 0.],
+    [200., 0., 0.],
+    [255., 220., 0.],
+    [255., 255., 255.],
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HeatmapSettings {
+    /// Number of cells across the ROI (along A→B)
+    pub columns: usize,
+    /// Number of cells along the depth of the ROI (along A→D)
+    pub rows: usize,
+    /// How often (ms) to publish the grid
+    pub publish_interval: u64,
+}
+
+impl Default for HeatmapSettings {
+    fn default() -> Self {
+        HeatmapSettings {
+            columns: 32,
+            rows: 32,
+            publish_interval: 5000,
+        }
+    }
+}
+
+/// Time spent (in seconds) by smoothed tracked points in each cell of a grid over the ROI.
+/// Cells are stored row by row, starting with the row along the A→B edge.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeatmapGrid {
+    pub columns: usize,
+    pub rows: usize,
+    pub cells: Vec<f32>,
+}
+
+impl HeatmapGrid {
+    pub fn new(columns: usize, rows: usize) -> Self {
+        let (columns, rows) = (columns.max(1), rows.max(1));
+        HeatmapGrid {
+            columns,
+            rows,
+            cells: vec![0.; columns * rows],
+        }
+    }
+
+    /// Add time to the cell containing the point, given in normalised [0;1] ROI
+    /// coordinates; points outside the ROI are ignored
+    pub fn add(&mut self, point: Point2D, seconds: f32) {
+        let (u, v) = point;
+        if !(0. ..=1.).contains(&u) || !(0. ..=1.).contains(&v) {
+            return;
+        }
+        let column = ((u * self.columns as f32) as usize).min(self.columns - 1);
+        let row = ((v * self.rows as f32) as usize).min(self.rows - 1);
+        self.cells[row * self.columns + column] += seconds;
+    }
+
+    pub fn get(&self, column: usize, row: usize) -> f32 {
+        self.cells[row * self.columns + column]
+    }
+
+    pub fn max(&self) -> f32 {
+        self.cells.iter().cloned().fold(0., f32::max)
+    }
+
+    /// Seconds per cell, one line per row. As with the PNG, the first line is the far
+    /// side of the ROI (D→C), so that it looks the same as the frontend graphs.
+    pub fn to_csv(&self) -> String {
+        (0..self.rows)
+            .rev()
+            .map(|row| {
+                let line: Vec<String> = (0..self.columns)
+                    .map(|column| format!("{:.3}", self.get(column, row)))
+                    .collect();
+                line.join(",") + "\n"
+            })
+            .collect()
+    }
+
+    /// Render (without a GPU) as a PNG, relative to the busiest cell; the far side of
+    /// the ROI (D→C) is at the top
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let (width, height) = (self.columns * PNG_CELL_SIZE, self.rows * PNG_CELL_SIZE);
+        let max = self.max();
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            let row = self.rows - 1 - y / PNG_CELL_SIZE;
+            for x in 0..width {
+                let value = self.get(x / PNG_CELL_SIZE, row);
+                let level = if max > 0. { value / max } else { 0. };
+                pixels.extend_from_slice(&heat_colour(level));
+            }
+        }
+
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+        Ok(bytes)
+    }
+
+    /// Write to a file: PNG if the path ends in ".png", otherwise CSV
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let is_png = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+        if is_png {
+            fs::write(path, self.to_png()?)?;
+        } else {
+            fs::write(path, self.to_csv())?;
+        }
+        Ok(())
+    }
+}
+
+/// Map a level in the range [0;1] onto the colour ramp
+pub fn heat_colour(level: f32) -> [u8; 3] {
+    let segments = (PNG_COLOUR_STOPS.len() - 1) as f32;
+    let position = level.clamp(0., 1.) * segments;
+    let index = (position as usize).min(PNG_COLOUR_STOPS.len() - 2);
+    let t = position - index as f32;
+    let (from, to) = (PNG_COLOUR_STOPS[index], PNG_COLOUR_STOPS[index + 1]);
+    [0, 1, 2].map(|i| (from[i] + (to[i] - from[i]) * t).round() as u8)
+}
+
+/// Accumulates the positions of smoothed tracked points over time, for as long as the
+/// backend runs, and keeps track of when the grid should next be published.
+pub struct OccupancyHeatmap {
+    settings: HeatmapSettings,
+    grid: HeatmapGrid,
+    last_accumulated: Instant,
+    last_published: Instant,
+    clock: SharedClock,
+}
+
+impl OccupancyHeatmap {
+    pub fn new(settings: HeatmapSettings) -> Self {
+        OccupancyHeatmap::with_clock(settings, default_clock())
+    }
+
+    /// As per [`OccupancyHeatmap::new`], but using the given Clock as the source of time
+    pub fn with_clock(settings: HeatmapSettings, clock: SharedClock) -> Self {
+        OccupancyHeatmap {
+            settings,
+            grid: HeatmapGrid::new(settings.columns, settings.rows),
+            last_accumulated: clock.now(),
+            last_published: clock.now(),
+            clock,
+        }
+    }
+
+    pub fn settings(&self) -> &HeatmapSettings {
+        &self.settings
+    }
+
+    pub fn grid(&self) -> &HeatmapGrid {
+        &self.grid
+    }
+
+    /// Add the time since the last update to the cell of each point (in normalised
+    /// [0;1] ROI coordinates)
+    pub fn accumulate(&mut self, points: &[Point2D]) {
+        let seconds = self
+            .clock
+            .elapsed_since(self.last_accumulated)
+            .as_secs_f32();
+        self.last_accumulated = self.clock.now();
+        for point in points {
+            self.grid.add(*point, seconds);
+        }
+    }
+
+    pub fn should_publish(&self) -> bool {
+        self.clock.elapsed_since(self.last_published)
+            >= Duration::from_millis(self.settings.publish_interval)
+    }
+
+    pub fn reset_timer(&mut self) {
+        self.last_published = self.clock.now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn test_add_to_cells() {
+        let mut grid = HeatmapGrid::new(4, 2);
+        grid.add((0., 0.), 1.);
+        grid.add((0.99, 0.6), 2.);
+        grid.add((1., 1.), 0.5);
+        // Outside the ROI
+        grid.add((-0.1, 0.5), 10.);
+        grid.add((0.5, 1.2), 10.);
+
+        assert_eq!(grid.get(0, 0), 1.);
+        assert_eq!(grid.get(3, 1), 2.5);
+        assert_eq!(grid.max(), 2.5);
+        assert_eq!(grid.cells.iter().sum::<f32>(), 3.5);
+    }
+
+    #[test]
+    fn test_csv_has_far_side_first() {
+        let mut grid = HeatmapGrid::new(3, 2);
+        grid.add((0.1, 0.1), 1.);
+        grid.add((0.9, 0.9), 0.25);
+        assert_eq!(
+            grid.to_csv(),
+            "0.000,0.000,0.250\n1.000,0.000,0.000\n".to_string()
+        );
+    }
+
+    #[test]
+    fn test_png() {
+        let mut grid = HeatmapGrid::new(3, 2);
+        grid.add((0.1, 0.1), 1.);
+
+        let bytes = grid.to_png().unwrap();
+        let decoder = png::Decoder::new(bytes.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!(
+            (info.width, info.height),
+            (3 * PNG_CELL_SIZE as u32, 2 * PNG_CELL_SIZE as u32)
+        );
+        // The busiest cell is at the bottom left
+        let bottom_left = (info.height as usize - 1) * info.line_size;
+        assert_eq!(pixels[bottom_left..bottom_left + 3], [255, 255, 255]);
+        assert_eq!(pixels[0..3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_accumulates_elapsed_time() {
+        let clock = ManualClock::new();
+        let mut heatmap = OccupancyHeatmap::with_clock(
+            HeatmapSettings {
+                columns: 2,
+                rows: 2,
+                publish_interval: 1000,
+            },
+            clock.shared(),
+        );
+
+        clock.advance_ms(500);
+        heatmap.accumulate(&[(0.25, 0.25), (0.75, 0.75)]);
+        assert!(!heatmap.should_publish());
+        clock.advance_ms(500);
+        heatmap.accumulate(&[(0.25, 0.25)]);
+        assert!(heatmap.should_publish());
+
+        assert!((heatmap.grid().get(0, 0) - 1.).abs() < 0.001);
+        assert!((heatmap.grid().get(1, 1) - 0.5).abs() < 0.001);
+
+        heatmap.reset_timer();
+        assert!(!heatmap.should_publish());
+    }
+}
@@ -1,62 +1,347 @@
+pub mod area_filtering;
 pub mod automasking;
+pub mod behaviour;
+pub mod calibration;
 pub mod clustering;
+pub mod crowd_stats;
+pub mod groups;
+pub mod heatmap;
 pub mod movement;
+pub mod occlusion;
 pub mod position_remapping;
 pub mod presence;
+pub mod regions;
 pub mod smoothing;
 
+use std::time::Duration;
+
+use area_filtering::AreaFilter;
 use automasking::AutoMaskSamplerMap;
-use clustering::ClusteringSystem;
+use behaviour::{BehaviourAnalysis, BehaviourChange};
+use clustering::{Cluster2D, ClusteringSystem};
+use crowd_stats::{CrowdAnalysis, CrowdStats};
+use groups::{Group, GroupDetection};
+use heatmap::{HeatmapGrid, OccupancyHeatmap};
 use indexmap::IndexMap;
-use movement::AverageMovementAnalysis;
+use log::{debug, error, info};
+use movement::{AverageMovementAnalysis, MovementSettings};
+use occlusion::LineOfSight;
 use position_remapping::PositionRemapping;
-use presence::PresenceDetectionZones;
-use smoothing::{SmoothSettings, TrackingSmoother};
+use presence::{PresenceDetectionZones, Zone};
+use regions::RegionTracking;
+use smoothing::{SmoothSettings, TrackReacquired, TrackingSmoother};
+
+use crate::{
+    backend_config::BackendConfig,
+    clock::{default_clock, SharedClock},
+    tracking::TrackedPoint2D,
+    Point2D,
+};
 
-use crate::backend_config::BackendConfig;
+/// What happened as a result of [`Systems::process_scan`]
+#[derive(Debug, Default)]
+pub struct ScanUpdate {
+    /// The device was previously unknown, and has now been added to the config
+    pub device_added: bool,
+    /// Automasking for the device has completed, and the config has been updated
+    pub mask_updated: bool,
+    /// Clusters transformed into ROI space and filtered; None if there is no ROI (yet)
+    pub tracked_points: Option<Vec<Cluster2D>>,
+}
+
+impl ScanUpdate {
+    /// True if the config was changed, and so should be saved (and re-published)
+    pub fn config_changed(&self) -> bool {
+        self.device_added || self.mask_updated
+    }
+}
+
+/// Everything produced by a single call to [`Systems::update_smoothed`], i.e. everything
+/// that should be published (and may be mirrored elsewhere)
+#[derive(Debug, Default)]
+pub struct SmoothedOutputs {
+    /// None if nothing should be sent (as per the "empty list send mode")
+    pub smoothed_points: Option<Vec<TrackedPoint2D>>,
+    /// None unless a remapped frame is configured and smoothed points are sent
+    pub remapped_points: Option<Vec<TrackedPoint2D>>,
+    /// Smoothed points for each named region, by region name
+    pub region_points: Vec<(String, Vec<TrackedPoint2D>)>,
+    /// None unless average movement is enabled and it is time to send it
+    pub movement: Option<Point2D>,
+    pub changed_zones: Vec<Zone>,
+    /// Changes in behaviour since the last update (if behaviour classification is configured)
+    pub behaviour_changes: Vec<BehaviourChange>,
+    /// Lost tracks that were re-acquired since the last update (if re-identification
+    /// is configured)
+    pub reacquired: Vec<TrackReacquired>,
+    /// None unless group detection is configured (and as per the empty list send mode)
+    pub groups: Option<Vec<Group>>,
+    /// None unless crowd stats are configured and it is time to send them
+    pub crowd_stats: Option<CrowdStats>,
+    /// None unless a heatmap is configured and it is time to send it
+    pub heatmap: Option<HeatmapGrid>,
+}
 
 pub struct Systems {
     pub clustering_system: ClusteringSystem,
+    pub area_filter: AreaFilter,
     pub position_remapping: PositionRemapping,
     pub smoothing_system: TrackingSmoother,
     pub automask_samplers: AutoMaskSamplerMap,
     pub presence_detector: PresenceDetectionZones,
     pub movement_analysis: AverageMovementAnalysis,
+    /// Only if behaviour classification is configured
+    pub behaviour_analysis: Option<BehaviourAnalysis>,
+    /// Only if group detection is configured
+    pub group_detection: Option<GroupDetection>,
+    /// Only if crowd stats are configured
+    pub crowd_analysis: Option<CrowdAnalysis>,
+    /// Only if there are named regions in the config
+    pub region_tracking: Option<RegionTracking>,
+    /// Only if a heatmap is configured
+    pub heatmap: Option<OccupancyHeatmap>,
+    /// Only if occlusion handling is configured
+    pub line_of_sight: Option<LineOfSight>,
+    clock: SharedClock,
 }
 
 impl Systems {
     pub fn new(config: &BackendConfig) -> Systems {
+        Systems::with_clock(config, default_clock())
+    }
+
+    /// As per [`Systems::new`], but with every time-based system sharing the given Clock
+    pub fn with_clock(config: &BackendConfig, clock: SharedClock) -> Systems {
         let clustering_system = ClusteringSystem::new(
             config.clustering_neighbourhood_radius,
             config.clustering_min_neighbours,
             config.clustering_max_cluster_size,
-        );
+        )
+        .with_leg_pairing(config.clustering_leg_pairing);
 
-        let smoothing_system = TrackingSmoother::new(SmoothSettings {
-            id_offset: config.offset_index,
-            merge_radius: config.smoothing_merge_radius,
-            enable_auto_merge: config.enable_auto_merge_radius,
-            wait_before_active_ms: config.smoothing_wait_before_active_ms,
-            expire_ms: config.smoothing_expire_ms,
-            lerp_factor: config.smoothing_lerp_factor,
-            empty_list_send_mode: config.smoothing_empty_send_mode,
-            origin_mode: config.origin_location,
-            should_calculate_velocity: config.enable_velocity,
-            should_calculate_bearing: config.enable_bearing,
-            should_calculate_range: config.enable_range,
-        });
+        let smoothing_system = TrackingSmoother::with_clock(
+            SmoothSettings {
+                id_offset: config.offset_index,
+                id_policy: config.smoothing_id_policy,
+                merge_radius: config.smoothing_merge_radius,
+                enable_auto_merge: config.enable_auto_merge_radius,
+                wait_before_active_ms: config.smoothing_wait_before_active_ms,
+                expire_ms: config.smoothing_expire_ms,
+                lerp_factor: config.smoothing_lerp_factor,
+                filter: config.smoothing_filter,
+                reidentify: config.smoothing_reidentify,
+                occlusion: config.occlusion,
+                empty_list_send_mode: config.smoothing_empty_send_mode,
+                origin_mode: config.origin_location,
+                should_calculate_velocity: config.should_calculate_velocity(),
+                should_calculate_bearing: config.enable_bearing,
+                should_calculate_range: config.enable_range,
+            },
+            clock.clone(),
+        );
 
         let position_system = PositionRemapping::new(config);
 
-        let presence_detector = PresenceDetectionZones::new(config.zones().unwrap_or_default());
+        let presence_detector =
+            PresenceDetectionZones::with_clock(config.zones().unwrap_or_default(), clock.clone());
 
         Systems {
             clustering_system,
+            area_filter: AreaFilter::new(config),
             smoothing_system,
             automask_samplers: IndexMap::new(),
             position_remapping: position_system,
             presence_detector,
-            movement_analysis: AverageMovementAnalysis::new(),
+            movement_analysis: AverageMovementAnalysis::with_clock(
+                MovementSettings {
+                    mode: config.average_movement_mode,
+                    weight_by_size: config.average_movement_weight_by_size,
+                    smoothing_ms: config.average_movement_smoothing_ms,
+                    dead_zone: config.average_movement_dead_zone,
+                },
+                clock.clone(),
+            ),
+            behaviour_analysis: config
+                .behaviour
+                .map(|settings| BehaviourAnalysis::with_clock(settings, clock.clone())),
+            group_detection: config
+                .groups
+                .map(|settings| GroupDetection::with_clock(settings, clock.clone())),
+            crowd_analysis: config
+                .crowd_stats
+                .map(|settings| CrowdAnalysis::with_clock(settings, clock.clone())),
+            region_tracking: RegionTracking::new(config),
+            heatmap: config
+                .heatmap
+                .map(|settings| OccupancyHeatmap::with_clock(settings, clock.clone())),
+            line_of_sight: config.occlusion.map(LineOfSight::new),
+            clock,
+        }
+    }
+
+    /// Feed a scan from the given device through clustering, position remapping and (if
+    /// active) automasking, and on to the smoothing system. Unknown devices are added
+    /// to the config.
+    pub fn process_scan(
+        &mut self,
+        serial: &str,
+        scans: &[Point2D],
+        config: &mut BackendConfig,
+    ) -> ScanUpdate {
+        let mut update = ScanUpdate {
+            device_added: config
+                .check_or_create_device(serial, config.default_min_distance_threshold)
+                .is_some(),
+            ..Default::default()
+        };
+
+        if let Some(device) = config.get_device(serial) {
+            self.clustering_system.update_from_scan(scans, device);
+
+            if let Some(line_of_sight) = &mut self.line_of_sight {
+                line_of_sight.update_scan(device, scans);
+            }
+
+            let clusters = self
+                .area_filter
+                .filter_clusters(self.clustering_system.clusters());
+
+            if self.position_remapping.is_ready() {
+                let transformed_clusters = self.position_remapping.transform_clusters(&clusters);
+                let filtered_clusters = self
+                    .position_remapping
+                    .filter_clusters_inside(&transformed_clusters);
+
+                self.smoothing_system
+                    .update_tracked_points(&filtered_clusters);
+                update.tracked_points = Some(filtered_clusters);
+            }
+        }
+
+        if let Some(sampler) = self.automask_samplers.get_mut(serial) {
+            if !sampler.is_complete() {
+                if let Some(new_mask) = sampler.add_samples(scans) {
+                    debug!("Sufficient samples for masking device {}", serial);
+                    match config.update_device_masking(new_mask, serial) {
+                        Ok(()) => {
+                            info!("Updated masking for device {}", serial);
+                            update.mask_updated = true;
+                            sampler.angles_with_thresholds.clear();
+                        }
+                        Err(e) => {
+                            error!("Error updating masking for device {}: {}", serial, e);
+                        }
+                    }
+                }
+            }
+        }
+
+        update
+    }
+
+    /// Update smoothing (by the given interval, in ms), and everything that depends on the
+    /// smoothed points: movement, presence, behaviour, groups, crowd stats and so on. Does
+    /// no I/O; the results are returned for the caller to publish or store.
+    pub fn update_smoothed(&mut self, config: &BackendConfig, interval: u64) -> SmoothedOutputs {
+        self.update_occlusion();
+        self.smoothing_system.update_smoothing(interval);
+
+        let mut outputs = SmoothedOutputs {
+            smoothed_points: self.smoothing_system.get_active_smoothed_points(),
+            reacquired: self.smoothing_system.take_reacquired(),
+            ..Default::default()
+        };
+
+        if let Some(points) = &mut outputs.smoothed_points {
+            if config.prediction_lookahead_ms > 0 {
+                self.position_remapping
+                    .add_predicted_positions(points, config.prediction_lookahead_ms);
+            }
+            if let Some(frame) = &config.smoothed_remapped_frame {
+                outputs.remapped_points =
+                    Some(self.position_remapping.remap_to_frame(points, frame));
+            }
+        }
+
+        // Use smoothed points for presence detection and movement analysis, updating
+        // with zero points if there are none...
+        let points = outputs.smoothed_points.as_deref().unwrap_or_default();
+
+        if let Some(region_tracking) = &mut self.region_tracking {
+            outputs.region_points = region_tracking.region_points(points, &self.position_remapping);
+        }
+
+        if config.enable_average_movement {
+            self.movement_analysis.update(points);
+            if self.movement_analysis.get_elapsed()
+                >= Duration::from_millis(config.average_movement_interval)
+            {
+                outputs.movement = Some(self.movement_analysis.movement());
+                self.movement_analysis.reset_timer();
+            }
+        }
+
+        if let Some(behaviour_analysis) = &mut self.behaviour_analysis {
+            outputs.behaviour_changes = behaviour_analysis.update(points);
+        }
+
+        if let Some(group_detection) = &mut self.group_detection {
+            group_detection.update(points);
+            outputs.groups = group_detection.groups_to_send(config.smoothing_empty_send_mode);
+        }
+
+        let normalised_points: Vec<Point2D> = points
+            .iter()
+            .map(|p| self.position_remapping.normalise_point((p.x, p.y)))
+            .collect();
+
+        if let Some(crowd_analysis) = &mut self.crowd_analysis {
+            crowd_analysis.update(points);
+            if crowd_analysis.should_publish() {
+                let [a, b, _c, d] = self.position_remapping.get_dst_quad();
+                let roi_size = (b.0 - a.0, d.1 - a.1);
+                outputs.crowd_stats =
+                    Some(crowd_analysis.stats(points, &normalised_points, roi_size));
+                crowd_analysis.reset_timer();
+            }
+        }
+
+        if let Some(heatmap) = &mut self.heatmap {
+            heatmap.accumulate(&normalised_points);
+            if heatmap.should_publish() {
+                outputs.heatmap = Some(heatmap.grid().clone());
+                heatmap.reset_timer();
+            }
+        }
+
+        outputs.changed_zones = self.presence_detector.update_zones(points);
+
+        outputs
+    }
+
+    /// Mark which smoothed points are currently hidden from every device, so that they
+    /// are held rather than expiring; does nothing unless occlusion handling is configured.
+    /// Call this before updating smoothing.
+    pub fn update_occlusion(&mut self) {
+        if let Some(line_of_sight) = &self.line_of_sight {
+            let position_remapping = &self.position_remapping;
+            self.smoothing_system.update_occlusion(|position| {
+                position_remapping
+                    .inverse_transform_point(position)
+                    .is_some_and(|world| line_of_sight.is_occluded(world))
+            });
+        }
+    }
+
+    /// Re-create all systems from the given config (e.g. after it was updated remotely),
+    /// keeping the same Clock. The heatmap is kept, unless its settings have changed.
+    pub fn reset(&mut self, config: &BackendConfig) {
+        let previous_heatmap = self.heatmap.take();
+        *self = Systems::with_clock(config, self.clock.clone());
+        if let (Some(heatmap), Some(previous)) = (&mut self.heatmap, previous_heatmap) {
+            if heatmap.settings() == previous.settings() {
+                *heatmap = previous;
+            }
         }
     }
 }
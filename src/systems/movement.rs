@@ -1,43 +1,220 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
-use log::error;
+use serde::{Deserialize, Serialize};
 
-use crate::{tracking::TrackedPoint2D, Point2D};
+use crate::{
+    clock::{default_clock, SharedClock},
+    tracking::TrackedPoint2D,
+    Point2D,
+};
+
+use super::smoothing::smoothing_factor;
+
+/// How the velocities of all smoothed points are combined into a single vector
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum MovementMode {
+    #[default]
+    Sum,
+    Mean,
+    /// Per axis; less affected by a single fast-moving point than the mean
+    Median,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MovementSettings {
+    pub mode: MovementMode,
+    /// Weight each point by its size (cluster size), for the mean and median modes
+    pub weight_by_size: bool,
+    /// Time constant (ms) for exponential smoothing over time; 0 for no smoothing
+    pub smoothing_ms: u64,
+    /// Movement slower than this (speed) is reported as zero
+    pub dead_zone: f32,
+}
 
 pub struct AverageMovementAnalysis {
-    last_updated: SystemTime,
+    settings: MovementSettings,
+    movement: Point2D,
+    last_calculated: Option<Instant>,
+    last_updated: Instant,
+    clock: SharedClock,
 }
 
 impl AverageMovementAnalysis {
-    pub fn new() -> Self {
+    pub fn new(settings: MovementSettings) -> Self {
+        AverageMovementAnalysis::with_clock(settings, default_clock())
+    }
+
+    /// As per [`AverageMovementAnalysis::new`], but using the given Clock as the source of time
+    pub fn with_clock(settings: MovementSettings, clock: SharedClock) -> Self {
         AverageMovementAnalysis {
-            last_updated: SystemTime::now(),
+            settings,
+            movement: (0., 0.),
+            last_calculated: None,
+            last_updated: clock.now(),
+            clock,
         }
     }
 
+    /// Time since the movement was last published (see [`AverageMovementAnalysis::reset_timer`])
     pub fn get_elapsed(&self) -> Duration {
-        self.last_updated.elapsed().unwrap_or_default()
+        self.clock.elapsed_since(self.last_updated)
     }
 
     pub fn reset_timer(&mut self) {
-        self.last_updated = SystemTime::now();
+        self.last_updated = self.clock.now();
     }
-}
 
-pub fn calculate(points: &[TrackedPoint2D]) -> Point2D {
-    points.iter().fold((0., 0.), |acc, p| {
-        if let Some(v) = p.velocity {
-            let [vx, vy] = v;
-            (acc.0 + vx, acc.1 + vy)
+    /// Combine the velocities of the given points and smooth the result over time; call
+    /// this on every smoothing update, even when not publishing
+    pub fn update(&mut self, points: &[TrackedPoint2D]) {
+        let target = calculate(points, self.settings.mode, self.settings.weight_by_size);
+        let now = self.clock.now();
+
+        self.movement = match self.last_calculated {
+            Some(last_calculated) if self.settings.smoothing_ms > 0 => {
+                let elapsed = now.saturating_duration_since(last_calculated).as_secs_f32();
+                let t = smoothing_factor(elapsed, self.settings.smoothing_ms as f32 / 1000.);
+                (
+                    self.movement.0 + (target.0 - self.movement.0) * t,
+                    self.movement.1 + (target.1 - self.movement.1) * t,
+                )
+            }
+            _ => target,
+        };
+        self.last_calculated = Some(now);
+    }
+
+    /// The (smoothed) movement as of the last update, with the dead-zone applied
+    pub fn movement(&self) -> Point2D {
+        let (x, y) = self.movement;
+        if (x * x + y * y).sqrt() < self.settings.dead_zone {
+            (0., 0.)
         } else {
-            error!("No velocity for points; is velocity calculation enabled?");
-            acc
+            (x, y)
         }
-    })
+    }
+}
+
+/// Combine the velocities of the given points; points without a velocity are ignored
+pub fn calculate(points: &[TrackedPoint2D], mode: MovementMode, weight_by_size: bool) -> Point2D {
+    let weighted: Vec<([f32; 2], f32)> = points
+        .iter()
+        .filter_map(|p| {
+            let weight = if weight_by_size {
+                p.size.unwrap_or(1.)
+            } else {
+                1.
+            };
+            p.velocity.map(|v| (v, weight))
+        })
+        .collect();
+
+    match mode {
+        MovementMode::Sum => weighted
+            .iter()
+            .fold((0., 0.), |acc, ([vx, vy], _)| (acc.0 + vx, acc.1 + vy)),
+        MovementMode::Mean => {
+            let total_weight: f32 = weighted.iter().map(|(_, w)| w).sum();
+            if total_weight <= 0. {
+                return (0., 0.);
+            }
+            let (x, y) = weighted.iter().fold((0., 0.), |acc, ([vx, vy], w)| {
+                (acc.0 + vx * w, acc.1 + vy * w)
+            });
+            (x / total_weight, y / total_weight)
+        }
+        MovementMode::Median => (
+            weighted_median(weighted.iter().map(|(v, w)| (v[0], *w)).collect()),
+            weighted_median(weighted.iter().map(|(v, w)| (v[1], *w)).collect()),
+        ),
+    }
+}
+
+/// The value at which half the total weight is reached (0 if there are no values)
+fn weighted_median(mut values: Vec<(f32, f32)>) -> f32 {
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let half = values.iter().map(|(_, w)| w).sum::<f32>() / 2.;
+    let mut cumulative = 0.;
+    for (index, (value, weight)) in values.iter().enumerate() {
+        cumulative += weight;
+        if cumulative > half {
+            return *value;
+        }
+        if cumulative == half {
+            // Exactly between two values
+            return values
+                .get(index + 1)
+                .map(|(next, _)| (value + next) / 2.)
+                .unwrap_or(*value);
+        }
+    }
+    0.
 }
 
 impl Default for AverageMovementAnalysis {
     fn default() -> Self {
-        AverageMovementAnalysis::new()
+        AverageMovementAnalysis::new(MovementSettings::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn moving(velocity: [f32; 2], size: f32) -> TrackedPoint2D {
+        let mut p = TrackedPoint2D::new(0, (0., 0.), Some(size));
+        p.velocity = Some(velocity);
+        p
+    }
+
+    #[test]
+    fn test_modes() {
+        let points = [
+            moving([100., 0.], 200.),
+            moving([200., 50.], 200.),
+            moving([900., -50.], 600.),
+            TrackedPoint2D::new(1, (0., 0.), None),
+        ];
+        assert_eq!(calculate(&points, MovementMode::Sum, false), (1200., 0.));
+        assert_eq!(calculate(&points, MovementMode::Mean, false), (400., 0.));
+        assert_eq!(calculate(&points, MovementMode::Median, false), (200., 0.));
+
+        assert_eq!(calculate(&points, MovementMode::Mean, true), (600., -20.));
+        assert_eq!(calculate(&points, MovementMode::Median, true), (900., -50.));
+
+        assert_eq!(calculate(&[], MovementMode::Mean, false), (0., 0.));
+        assert_eq!(calculate(&[], MovementMode::Median, true), (0., 0.));
+    }
+
+    #[test]
+    fn test_smoothing_and_dead_zone() {
+        let clock = ManualClock::new();
+        let mut analysis = AverageMovementAnalysis::with_clock(
+            MovementSettings {
+                smoothing_ms: 100,
+                dead_zone: 50.,
+                ..Default::default()
+            },
+            clock.shared(),
+        );
+
+        // The first update is taken as-is
+        analysis.update(&[moving([0., 0.], 1.)]);
+        assert_eq!(analysis.movement(), (0., 0.));
+
+        // After one time constant, ~63% of the way there
+        clock.advance_ms(100);
+        analysis.update(&[moving([1000., 0.], 1.)]);
+        let (x, _y) = analysis.movement();
+        assert!((x - 632.1).abs() < 1., "{}", x);
+
+        // Decays towards zero; eventually inside the dead-zone
+        clock.advance_ms(200);
+        analysis.update(&[]);
+        assert!(analysis.movement().0 > 50.);
+        clock.advance_ms(200);
+        analysis.update(&[]);
+        assert_eq!(analysis.movement(), (0., 0.));
     }
 }
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend_config::LidarDevice,
+    geometry_utils::{bearing, distance_points},
+    Point2D,
+};
+
+use super::clustering::scan_sample_to_point;
+
+/// Settings for holding on to smoothed points that are hidden from every LIDAR (e.g.
+/// behind someone else), instead of letting them expire
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OcclusionSettings {
+    /// Roughly how wide (mm, from the centre) a tracked person is: the line of sight
+    /// to a point is this wide, and only returns nearer than the point by more than
+    /// this can be hiding it
+    pub track_radius: f32,
+    /// Hold hidden points for at most this long (ms) beyond the normal expiry time
+    pub max_hold_ms: u64,
+    /// Keep hidden points moving at their last velocity, rather than holding them still
+    pub coast: bool,
+}
+
+impl Default for OcclusionSettings {
+    fn default() -> Self {
+        OcclusionSettings {
+            track_radius: 250.,
+            max_hold_ms: 5000,
+            coast: false,
+        }
+    }
+}
+
+/// The latest scan from one device, in polar coordinates around the device position
+struct DeviceScan {
+    origin: Point2D,
+    /// (bearing in degrees, range) of each return, sorted by bearing
+    returns: Vec<(f32, f32)>,
+}
+
+/// Keeps the latest scan from every device, to work out whether a (world) position is
+/// currently hidden from all of them
+pub struct LineOfSight {
+    settings: OcclusionSettings,
+    scans: HashMap<String, DeviceScan>,
+}
+
+impl LineOfSight {
+    pub fn new(settings: OcclusionSettings) -> Self {
+        LineOfSight {
+            settings,
+            scans: HashMap::new(),
+        }
+    }
+
+    pub fn settings(&self) -> &OcclusionSettings {
+        &self.settings
+    }
+
+    /// Replace the scan for this device; samples are (angle, distance) as received, and
+    /// are filtered (min distance, masking) the same way as for clustering
+    pub fn update_scan(&mut self, device: &LidarDevice, samples: &[Point2D]) {
+        let origin = (device.x, device.y);
+        let mut returns: Vec<(f32, f32)> = samples
+            .iter()
+            .filter_map(|(angle, distance)| scan_sample_to_point(angle, distance, device))
+            .map(|(x, y)| {
+                (
+                    bearing(x - origin.0, y - origin.1),
+                    distance_points(&origin, &(x, y)),
+                )
+            })
+            .collect();
+        returns.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.scans
+            .insert(device.serial.clone(), DeviceScan { origin, returns });
+    }
+
+    /// True if every device has a return in front of the given (world) position, i.e.
+    /// nothing can see it; false if there are no scans at all
+    pub fn is_occluded(&self, position: Point2D) -> bool {
+        !self.scans.is_empty()
+            && self
+                .scans
+                .values()
+                .all(|scan| self.is_hidden_from(scan, position))
+    }
+
+    fn is_hidden_from(&self, scan: &DeviceScan, position: Point2D) -> bool {
+        let radius = self.settings.track_radius;
+        let range = distance_points(&scan.origin, &position);
+        if range <= radius * 2. {
+            return false;
+        }
+        let centre = bearing(position.0 - scan.origin.0, position.1 - scan.origin.1);
+        let half_width = (radius / range).atan().to_degrees();
+        let blocks = |(b, r): &(f32, f32)| {
+            let difference = (b - centre + 540.) % 360. - 180.;
+            difference.abs() <= half_width && *r < range - radius
+        };
+
+        // Returns are sorted by bearing; look only at those in the line of sight (which
+        // may wrap around 0/360)
+        let (from, to) = (centre - half_width, centre + half_width);
+        let in_range = |from: f32, to: f32| {
+            let start = scan.returns.partition_point(|(b, _)| *b < from);
+            let end = scan.returns.partition_point(|(b, _)| *b <= to);
+            scan.returns[start..end.max(start)].iter().any(blocks)
+        };
+        in_range(from, to) || in_range(from + 360., to + 360.) || in_range(from - 360., to - 360.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(serial: &str, x: f32, y: f32) -> LidarDevice {
+        LidarDevice {
+            serial: serial.into(),
+            name: serial.into(),
+            rotation: 0.,
+            x,
+            y,
+            colour: "#ffffff".into(),
+            min_distance_threshold: 0.,
+            scan_mask_thresholds: None,
+            flip_coords: None,
+        }
+    }
+
+    /// Samples (angle, distance) for a round object at the given position, as seen from
+    /// a device at the origin
+    fn object(x: f32, y: f32) -> Vec<Point2D> {
+        (0..16)
+            .map(|i| {
+                let t = i as f32 / 16. * std::f32::consts::TAU;
+                let (px, py) = (x + 150. * t.sin(), y + 150. * t.cos());
+                (bearing(px, py), (px * px + py * py).sqrt())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hidden_behind_nearer_return() {
+        let mut line_of_sight = LineOfSight::new(OcclusionSettings::default());
+        assert!(!line_of_sight.is_occluded((0., 3000.)));
+
+        let a = device("a", 0., 0.);
+        line_of_sight.update_scan(&a, &object(0., 1500.));
+        assert!(line_of_sight.is_occluded((0., 3000.)));
+        // Off to the side, or in front of the object
+        assert!(!line_of_sight.is_occluded((1000., 3000.)));
+        assert!(!line_of_sight.is_occluded((0., 1000.)));
+        // Not hidden by its own surface
+        assert!(!line_of_sight.is_occluded((0., 1600.)));
+
+        // Around 0/360 degrees
+        line_of_sight.update_scan(&a, &object(-50., 1500.));
+        assert!(line_of_sight.is_occluded((20., 3000.)));
+    }
+
+    #[test]
+    fn test_hidden_only_if_hidden_from_every_device() {
+        let mut line_of_sight = LineOfSight::new(OcclusionSettings::default());
+        line_of_sight.update_scan(&device("a", 0., 0.), &object(0., 1500.));
+        // The second device (off to the side) sees nothing in the way
+        line_of_sight.update_scan(&device("b", 3000., 0.), &[]);
+        assert!(!line_of_sight.is_occluded((0., 3000.)));
+    }
+}
@@ -4,12 +4,13 @@ use quad_to_quad_transformer::{QuadTransformer, RectCorners, DEFAULT_DST_QUAD};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    backend_config::{BackendConfig, CornerPoints},
-    geometry_utils::distance,
+    backend_config::{BackendConfig, CalibrationPoint, CornerPoints},
+    geometry_utils::{bearing, distance},
+    tracking::TrackedPoint2D,
     Point2D,
 };
 
-use super::clustering::Cluster2D;
+use super::{calibration::ThinPlateSpline, clustering::Cluster2D};
 
 /// Which part of the destination quad (ROI) to use as the origin [0,0].
 /// All points sent on "smoothedTrackedPoints" will be relative to this.
@@ -20,57 +21,145 @@ pub enum OriginLocation {
     Centre,
 }
 
+/// Units for a [`RemappedFrame`]; "real" units are based on the ROI dimensions in mm
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FrameUnits {
+    /// The ROI is 1x1
+    Normalised,
+    Millimetres,
+    Centimetres,
+    Metres,
+}
+
+/// A second coordinate frame for smoothed points, independent of the main one, as sent
+/// on "smoothedRemappedPoints"
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RemappedFrame {
+    /// Which part of the ROI to use as the origin [0,0]
+    pub origin: OriginLocation,
+    /// Flip the X axis, i.e. from corner B towards A instead of A towards B
+    #[serde(default)]
+    pub flip_x: bool,
+    /// Flip the Y axis, i.e. from corner D towards A instead of A towards D
+    #[serde(default)]
+    pub flip_y: bool,
+    pub units: FrameUnits,
+    /// Multiplier applied after conversion to the given units
+    pub scale: f32,
+}
+
+impl Default for RemappedFrame {
+    fn default() -> Self {
+        RemappedFrame {
+            origin: OriginLocation::Corner,
+            flip_x: false,
+            flip_y: false,
+            units: FrameUnits::Normalised,
+            scale: 1.,
+        }
+    }
+}
+
 pub struct PositionRemapping {
     transformer: QuadTransformer,
+    /// From the main frame back to world space
+    inverse_transformer: QuadTransformer,
     dst_quad: RectCorners,
+    use_real_units: bool,
+    /// If set, used instead of the perspective transform (but the ROI is still used
+    /// for filtering)
+    calibration: Option<ThinPlateSpline>,
+    /// The calibration, fitted the other way round (approximately its inverse)
+    inverse_calibration: Option<ThinPlateSpline>,
 }
 
 impl PositionRemapping {
     pub fn new(config: &BackendConfig) -> Self {
-        let dst_quad = if let Some(roi) = config.region_of_interest() {
-            calculate_dst_quad(roi, config.origin_location)
+        let mut remapping = PositionRemapping::with_region(
+            config.region_of_interest(),
+            config.origin_location,
+            config.smoothing_use_real_units,
+            if config.transform_include_outside {
+                None
+            } else {
+                Some(config.transform_ignore_outside_margin)
+            },
+        );
+        if config.calibration_enabled {
+            match ThinPlateSpline::new(&config.calibration_points) {
+                Ok(calibration) => {
+                    info!(
+                        "Using calibration with {} points",
+                        config.calibration_points.len()
+                    );
+                    remapping.calibration = Some(calibration);
+                    let reversed: Vec<CalibrationPoint> = config
+                        .calibration_points
+                        .iter()
+                        .map(|p| CalibrationPoint {
+                            source: p.target,
+                            target: p.source,
+                        })
+                        .collect();
+                    remapping.inverse_calibration = ThinPlateSpline::new(&reversed).ok();
+                }
+                Err(e) => warn!("Calibration is enabled but cannot be used: {}", e),
+            }
+        }
+        remapping
+    }
+
+    /// Remapping for the given ROI (if any); points further than `ignore_outside_margin`
+    /// outside of it are filtered out, unless the margin is None
+    pub fn with_region(
+        region_of_interest: Option<&CornerPoints>,
+        origin_location: OriginLocation,
+        use_real_units: bool,
+        ignore_outside_margin: Option<f32>,
+    ) -> Self {
+        let dst_quad = if let Some(roi) = region_of_interest {
+            calculate_dst_quad(roi, origin_location)
         } else {
             DEFAULT_DST_QUAD
         };
         let perspective_transformer = QuadTransformer::new(
-            match config.region_of_interest() {
-                Some(region_of_interest) => {
-                    let (c1, c2, c3, c4) = region_of_interest;
-                    let corners = [c1, c2, c3, c4].map(|c| (c.x, c.y));
-                    Some(corners)
-                }
-                None => None,
-            },
-            if config.smoothing_use_real_units {
+            region_of_interest.map(|(c1, c2, c3, c4)| [c1, c2, c3, c4].map(|c| (c.x, c.y))),
+            if use_real_units {
                 info!("Using real units (mm)");
                 Some(dst_quad)
             } else {
                 warn!("Using normalised units");
                 None
             },
-            {
-                if config.transform_include_outside {
-                    None
-                } else {
-                    Some(config.transform_ignore_outside_margin)
-                }
-            },
+            ignore_outside_margin,
         );
+        let mut inverse_transformer = QuadTransformer::default();
+        if let Some((c1, c2, c3, c4)) = region_of_interest {
+            inverse_transformer.set_new_quad(
+                &main_quad(dst_quad, use_real_units),
+                Some([c1, c2, c3, c4].map(|c| (c.x, c.y))),
+            );
+        }
         PositionRemapping {
             transformer: perspective_transformer,
+            inverse_transformer,
             dst_quad,
+            use_real_units,
+            calibration: None,
+            inverse_calibration: None,
         }
     }
 
     pub fn is_ready(&self) -> bool {
-        self.transformer.is_ready()
+        self.calibration.is_some() || self.transformer.is_ready()
     }
 
     pub fn transform_clusters(&self, clusters: &[Cluster2D]) -> Vec<Cluster2D> {
         clusters
             .iter()
             .map(|c| {
-                let (x, y) = self.transformer.transform(&(c.x, c.y)).unwrap();
+                let (x, y) = self.transform_point((c.x, c.y)).unwrap();
                 Cluster2D {
                     id: c.id,
                     x,
@@ -81,6 +170,34 @@ impl PositionRemapping {
             .collect()
     }
 
+    /// Transform a single point, without any filtering; None if there is no ROI
+    /// (or calibration)
+    pub fn transform_point(&self, point: Point2D) -> Option<Point2D> {
+        match &self.calibration {
+            Some(calibration) => Some(calibration.transform(&point)),
+            None => self.transformer.transform(&point).ok(),
+        }
+    }
+
+    /// Convert a point in the main frame back into world space; None if there is no ROI
+    /// (or calibration)
+    pub fn inverse_transform_point(&self, point: Point2D) -> Option<Point2D> {
+        match &self.inverse_calibration {
+            Some(calibration) => Some(calibration.transform(&point)),
+            None => self.inverse_transformer.transform(&point).ok(),
+        }
+    }
+
+    /// Transform a single point, returning None if it is outside the ROI (plus margin)
+    pub fn transform_point_inside(&self, point: Point2D) -> Option<Point2D> {
+        let transformed = self.transform_point(point)?;
+        if self.transformer.point_is_inside_quad(&transformed) {
+            Some(transformed)
+        } else {
+            None
+        }
+    }
+
     pub fn filter_clusters_inside(&self, clusters: &[Cluster2D]) -> Vec<Cluster2D> {
         clusters
             .iter()
@@ -97,19 +214,137 @@ impl PositionRemapping {
     ) {
         let (c1, c2, c3, c4) = region_of_interest;
         let corners = [c1, c2, c3, c4].map(|c| (c.x, c.y));
+        self.dst_quad = calculate_dst_quad(region_of_interest, origin_location);
+        self.use_real_units = use_real_units;
         self.transformer.set_new_quad(
             &corners,
             if use_real_units {
-                Some(calculate_dst_quad(region_of_interest, origin_location))
+                Some(self.dst_quad)
             } else {
                 None
             },
         );
+        self.inverse_transformer
+            .set_new_quad(&main_quad(self.dst_quad, use_real_units), Some(corners));
     }
 
     pub fn get_dst_quad(&self) -> RectCorners {
         self.dst_quad
     }
+
+    /// The origin and size of the main frame (i.e. smoothed points), in its own units
+    fn main_frame(&self) -> (Point2D, Point2D) {
+        let [a, b, _c, d] = self.dst_quad;
+        if self.use_real_units {
+            (a, (b.0 - a.0, d.1 - a.1))
+        } else {
+            ((0., 0.), (1., 1.))
+        }
+    }
+
+    /// Convert a point in the main frame (whether normalised or real units) into
+    /// normalised [0;1] ROI coordinates, with corner A as the origin
+    pub fn normalise_point(&self, point: Point2D) -> Point2D {
+        let (origin, size) = self.main_frame();
+        ((point.0 - origin.0) / size.0, (point.1 - origin.1) / size.1)
+    }
+
+    /// Move a point in the main frame onto the nearest edge of the ROI, if it lies outside
+    pub fn clamp_to_roi(&self, point: Point2D) -> Point2D {
+        let (origin, size) = self.main_frame();
+        let (u, v) = self.normalise_point(point);
+        (
+            origin.0 + u.clamp(0., 1.) * size.0,
+            origin.1 + v.clamp(0., 1.) * size.1,
+        )
+    }
+
+    /// Extrapolate each point (that has a velocity) forward by the given lookahead,
+    /// clamped to the ROI
+    pub fn add_predicted_positions(&self, points: &mut [TrackedPoint2D], lookahead_ms: u64) {
+        let lookahead = lookahead_ms as f32 / 1000.;
+        for p in points.iter_mut() {
+            p.predicted = p
+                .velocity
+                .map(|[vx, vy]| self.clamp_to_roi((p.x + vx * lookahead, p.y + vy * lookahead)));
+        }
+    }
+
+    /// Convert smoothed points (in the main frame, whether normalised or real units) into
+    /// the given frame. Velocity, bearing, range and predicted position are converted too,
+    /// if present.
+    pub fn remap_to_frame(
+        &self,
+        points: &[TrackedPoint2D],
+        frame: &RemappedFrame,
+    ) -> Vec<TrackedPoint2D> {
+        let [a, b, _c, d] = self.dst_quad;
+        let (roi_width, roi_height) = (b.0 - a.0, d.1 - a.1);
+
+        let (_, main_size) = self.main_frame();
+
+        // Size of the ROI in the new frame's units
+        let units_per_mm = match frame.units {
+            FrameUnits::Normalised => None,
+            FrameUnits::Millimetres => Some(1.),
+            FrameUnits::Centimetres => Some(0.1),
+            FrameUnits::Metres => Some(0.001),
+        };
+        let (w, h) = match units_per_mm {
+            Some(k) => (roi_width * k, roi_height * k),
+            None => (1., 1.),
+        };
+        let frame_quad = [(0., 0.), (w, 0.), (w, h), (0., h)];
+        let (sign_x, sign_y) = (
+            if frame.flip_x { -1. } else { 1. },
+            if frame.flip_y { -1. } else { 1. },
+        );
+
+        let remap_position = |position: Point2D| {
+            let (mut u, mut v) = self.normalise_point(position);
+            if frame.flip_x {
+                u = 1. - u;
+            }
+            if frame.flip_y {
+                v = 1. - v;
+            }
+            let (x, y) = point_remap_from_origin((u * w, v * h), frame.origin, frame_quad);
+            (x * frame.scale, y * frame.scale)
+        };
+
+        points
+            .iter()
+            .map(|p| {
+                let (x, y) = remap_position((p.x, p.y));
+
+                TrackedPoint2D {
+                    id: p.id,
+                    x,
+                    y,
+                    size: p.size.map(|size| size / main_size.0 * w * frame.scale),
+                    velocity: p.velocity.map(|[vx, vy]| {
+                        [
+                            sign_x * vx / main_size.0 * w * frame.scale,
+                            sign_y * vy / main_size.1 * h * frame.scale,
+                        ]
+                    }),
+                    bearing: p.bearing.map(|_| bearing(x, y)),
+                    range: p.range.map(|_| distance(x, y, 0., 0.)),
+                    predicted: p.predicted.map(remap_position),
+                    key: p.key.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The quad that the ROI is transformed into, i.e. the main frame
+fn main_quad(dst_quad: RectCorners, use_real_units: bool) -> RectCorners {
+    if use_real_units {
+        dst_quad
+    } else {
+        DEFAULT_DST_QUAD
+    }
 }
 
 /// Take a ROI, which might **not** be a rectangle, and return
@@ -155,3 +390,306 @@ pub fn point_remap_from_origin(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_config::ConfigRectCornerPoint;
+
+    fn roi(w: f32, h: f32) -> CornerPoints {
+        (
+            ConfigRectCornerPoint::new(0, 0., 0.),
+            ConfigRectCornerPoint::new(1, w, 0.),
+            ConfigRectCornerPoint::new(2, w, h),
+            ConfigRectCornerPoint::new(3, 0., h),
+        )
+    }
+
+    #[test]
+    fn test_calculate_dst_quad() {
+        let roi = roi(4000., 3000.);
+        assert_eq!(
+            calculate_dst_quad(&roi, OriginLocation::Corner),
+            [(0., 0.), (4000., 0.), (4000., 3000.), (0., 3000.)]
+        );
+        assert_eq!(
+            calculate_dst_quad(&roi, OriginLocation::CloseCentre),
+            [(-2000., 0.), (2000., 0.), (2000., 3000.), (-2000., 3000.)]
+        );
+        assert_eq!(
+            calculate_dst_quad(&roi, OriginLocation::Centre),
+            [
+                (-2000., -1500.),
+                (2000., -1500.),
+                (2000., 1500.),
+                (-2000., 1500.)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_calculate_dst_quad_skewed_roi() {
+        // Width is taken from AB and height from AD, even if the ROI is not a rectangle
+        let roi = (
+            ConfigRectCornerPoint::new(0, 0., 0.),
+            ConfigRectCornerPoint::new(1, 300., 400.),
+            ConfigRectCornerPoint::new(2, 1000., 1000.),
+            ConfigRectCornerPoint::new(3, 0., 200.),
+        );
+        assert_eq!(
+            calculate_dst_quad(&roi, OriginLocation::Corner),
+            [(0., 0.), (500., 0.), (500., 200.), (0., 200.)]
+        );
+    }
+
+    #[test]
+    fn test_point_remap_from_origin() {
+        let dst_quad = calculate_dst_quad(&roi(4000., 3000.), OriginLocation::Corner);
+
+        for p in [(0., 0.), (4000., 3000.), (1000., 500.)] {
+            assert_eq!(
+                point_remap_from_origin(p, OriginLocation::Corner, dst_quad),
+                p
+            );
+        }
+
+        assert_eq!(
+            point_remap_from_origin((0., 0.), OriginLocation::CloseCentre, dst_quad),
+            (-2000., 0.)
+        );
+        assert_eq!(
+            point_remap_from_origin((3000., 500.), OriginLocation::CloseCentre, dst_quad),
+            (1000., 500.)
+        );
+
+        assert_eq!(
+            point_remap_from_origin((0., 0.), OriginLocation::Centre, dst_quad),
+            (-2000., -1500.)
+        );
+        assert_eq!(
+            point_remap_from_origin((2000., 1500.), OriginLocation::Centre, dst_quad),
+            (0., 0.)
+        );
+        assert_eq!(
+            point_remap_from_origin((4000., 3000.), OriginLocation::Centre, dst_quad),
+            (2000., 1500.)
+        );
+    }
+
+    #[test]
+    fn test_remapping_transforms_and_filters() {
+        let config = BackendConfig {
+            region_of_interest: Some(roi(4000., 3000.)),
+            origin_location: OriginLocation::Centre,
+            ..Default::default()
+        };
+        let remapping = PositionRemapping::new(&config);
+        assert!(remapping.is_ready());
+
+        let clusters = [
+            Cluster2D {
+                id: 0,
+                x: 2000.,
+                y: 1500.,
+                size: 100.,
+            },
+            Cluster2D {
+                id: 1,
+                x: 5000.,
+                y: 1500.,
+                size: 100.,
+            },
+        ];
+        let transformed = remapping.transform_clusters(&clusters);
+        assert!(transformed[0].x.abs() < 0.5 && transformed[0].y.abs() < 0.5);
+
+        let inside = remapping.filter_clusters_inside(&transformed);
+        assert_eq!(inside.len(), 1);
+        assert_eq!(inside[0].id, 0);
+    }
+
+    #[test]
+    fn test_inverse_transform_round_trip() {
+        let skewed = || {
+            (
+                ConfigRectCornerPoint::new(0, -1500., 1000.),
+                ConfigRectCornerPoint::new(1, 2500., 800.),
+                ConfigRectCornerPoint::new(2, 3000., 5000.),
+                ConfigRectCornerPoint::new(3, -2000., 4000.),
+            )
+        };
+        for use_real_units in [true, false] {
+            let config = BackendConfig {
+                region_of_interest: Some(skewed()),
+                origin_location: OriginLocation::CloseCentre,
+                smoothing_use_real_units: use_real_units,
+                ..Default::default()
+            };
+            let remapping = PositionRemapping::new(&config);
+            for world in [(0., 2000.), (-1500., 1000.), (2800., 4500.)] {
+                let transformed = remapping.transform_point(world).unwrap();
+                let (x, y) = remapping.inverse_transform_point(transformed).unwrap();
+                assert!(
+                    (x - world.0).abs() < 0.5 && (y - world.1).abs() < 0.5,
+                    "{:?} != {:?}",
+                    (x, y),
+                    world
+                );
+            }
+        }
+        assert!(PositionRemapping::new(&BackendConfig::default())
+            .inverse_transform_point((0., 0.))
+            .is_none());
+    }
+
+    #[test]
+    fn test_remapping_not_ready_without_roi() {
+        let remapping = PositionRemapping::new(&BackendConfig::default());
+        assert!(!remapping.is_ready());
+    }
+
+    fn assert_close(actual: (f32, f32), expected: (f32, f32)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 0.001 && (actual.1 - expected.1).abs() < 0.001,
+            "{:?} != {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_remap_to_frame_from_real_units() {
+        let config = BackendConfig {
+            region_of_interest: Some(roi(4000., 3000.)),
+            origin_location: OriginLocation::Centre,
+            smoothing_use_real_units: true,
+            ..Default::default()
+        };
+        let remapping = PositionRemapping::new(&config);
+        let mut point = TrackedPoint2D::new(0, (1000., -1500.), None);
+        point.velocity = Some([400., 300.]);
+
+        let normalised = remapping.remap_to_frame(&[point.clone()], &RemappedFrame::default());
+        assert_close((normalised[0].x, normalised[0].y), (0.75, 0.));
+        assert_close(
+            (
+                normalised[0].velocity.unwrap()[0],
+                normalised[0].velocity.unwrap()[1],
+            ),
+            (0.1, 0.1),
+        );
+
+        let metres_flipped = remapping.remap_to_frame(
+            &[point.clone()],
+            &RemappedFrame {
+                origin: OriginLocation::CloseCentre,
+                flip_y: true,
+                units: FrameUnits::Metres,
+                ..Default::default()
+            },
+        );
+        assert_close((metres_flipped[0].x, metres_flipped[0].y), (1., 3.));
+        assert_close(
+            (
+                metres_flipped[0].velocity.unwrap()[0],
+                metres_flipped[0].velocity.unwrap()[1],
+            ),
+            (0.4, -0.3),
+        );
+
+        let scaled = remapping.remap_to_frame(
+            &[point],
+            &RemappedFrame {
+                origin: OriginLocation::Centre,
+                units: FrameUnits::Centimetres,
+                scale: 2.,
+                ..Default::default()
+            },
+        );
+        assert_close((scaled[0].x, scaled[0].y), (200., -300.));
+    }
+
+    #[test]
+    fn test_remap_to_frame_from_normalised() {
+        let config = BackendConfig {
+            region_of_interest: Some(roi(4000., 3000.)),
+            smoothing_use_real_units: false,
+            ..Default::default()
+        };
+        let remapping = PositionRemapping::new(&config);
+        let mut point = TrackedPoint2D::new(3, (0.25, 0.5), None);
+        point.bearing = Some(0.);
+        point.range = Some(0.);
+
+        let remapped = remapping.remap_to_frame(
+            &[point],
+            &RemappedFrame {
+                origin: OriginLocation::Centre,
+                flip_x: true,
+                units: FrameUnits::Millimetres,
+                ..Default::default()
+            },
+        );
+        assert_eq!(remapped[0].id, 3);
+        assert_close((remapped[0].x, remapped[0].y), (1000., 0.));
+        assert_close(
+            (remapped[0].bearing.unwrap(), remapped[0].range.unwrap()),
+            (90., 1000.),
+        );
+    }
+
+    #[test]
+    fn test_predicted_positions_are_clamped_to_roi() {
+        let config = BackendConfig {
+            region_of_interest: Some(roi(4000., 3000.)),
+            smoothing_use_real_units: false,
+            ..Default::default()
+        };
+        let remapping = PositionRemapping::new(&config);
+        let mut points = [
+            TrackedPoint2D::new(0, (0.5, 0.5), None),
+            TrackedPoint2D::new(1, (0.9, 0.5), None),
+            TrackedPoint2D::new(2, (0.5, 0.5), None),
+        ];
+        points[0].velocity = Some([1., 0.]);
+        points[1].velocity = Some([1., 1.]);
+
+        remapping.add_predicted_positions(&mut points, 250);
+        assert_close(points[0].predicted.unwrap(), (0.75, 0.5));
+        assert_close(points[1].predicted.unwrap(), (1., 0.75));
+        assert_eq!(points[2].predicted, None);
+    }
+
+    #[test]
+    fn test_calibration_replaces_perspective_transform() {
+        let config = BackendConfig {
+            smoothing_use_real_units: false,
+            calibration_enabled: true,
+            // Lens-like distortion: the centre of the floor is pulled towards corner A
+            calibration_points: [
+                ((0., 0.), (0., 0.)),
+                ((4000., 0.), (1., 0.)),
+                ((4000., 4000.), (1., 1.)),
+                ((0., 4000.), (0., 1.)),
+                ((2000., 2000.), (0.4, 0.4)),
+            ]
+            .map(|(source, target)| CalibrationPoint { source, target })
+            .to_vec(),
+            ..Default::default()
+        };
+        let remapping = PositionRemapping::new(&config);
+        assert!(remapping.is_ready());
+        let (x, y) = remapping.transform_point((2000., 2000.)).unwrap();
+        assert!((x - 0.4).abs() < 0.001 && (y - 0.4).abs() < 0.001);
+        let (x, y) = remapping.inverse_transform_point((0.4, 0.4)).unwrap();
+        assert!((x - 2000.).abs() < 0.5 && (y - 2000.).abs() < 0.5);
+
+        // Not enough points; falls back to the (missing) perspective transform
+        let config = BackendConfig {
+            calibration_enabled: true,
+            calibration_points: config.calibration_points[..2].to_vec(),
+            ..Default::default()
+        };
+        assert!(!PositionRemapping::new(&config).is_ready());
+    }
+}
@@ -1,11 +1,13 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant};
 
 use log::debug;
 use serde::{Deserialize, Serialize};
-use tether_agent::tether_compliant_topic::build_publish_topic;
-use tether_agent::TetherAgent;
 
-use crate::tracking::TrackedPoint2D;
+use crate::{
+    clock::{default_clock, SharedClock},
+    tracking::TrackedPoint2D,
+    transport::{Outgoing, Transport},
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Zone {
@@ -17,24 +19,32 @@ pub struct Zone {
     #[serde(default)]
     pub active: bool,
     #[serde(skip)]
-    last_active: Option<SystemTime>,
+    last_active: Option<Instant>,
 }
 
 pub struct PresenceDetectionZones {
     zones: Vec<Zone>,
     timeout: Duration,
+    clock: SharedClock,
 }
 
 impl PresenceDetectionZones {
     pub fn new(zones: &[Zone]) -> Self {
+        PresenceDetectionZones::with_clock(zones, default_clock())
+    }
+
+    /// As per [`PresenceDetectionZones::new`], but using the given Clock as the source of time
+    pub fn with_clock(zones: &[Zone], clock: SharedClock) -> Self {
         PresenceDetectionZones {
             zones: Vec::from(zones),
             timeout: Duration::from_millis(500),
+            clock,
         }
     }
 
     pub fn update_zones(&mut self, points: &[TrackedPoint2D]) -> Vec<Zone> {
         let mut zones_changed = Vec::new();
+        let now = self.clock.now();
 
         for p in points {
             let TrackedPoint2D { x, y, .. } = p;
@@ -43,7 +53,7 @@ impl PresenceDetectionZones {
                 .iter_mut()
                 .filter(|z| *x > z.x && *y > z.y && *x < z.x + z.width && *y < z.y + z.height)
             {
-                zone.last_active = Some(SystemTime::now());
+                zone.last_active = Some(now);
                 if !zone.active {
                     zone.active = true;
                     zones_changed.push(zone.clone());
@@ -53,9 +63,7 @@ impl PresenceDetectionZones {
 
         for zone in self.zones.iter_mut() {
             if let Some(timestamp) = zone.last_active {
-                if zone.active
-                    && timestamp.elapsed().expect("failed to get elapsed time") > self.timeout
-                {
+                if zone.active && now.saturating_duration_since(timestamp) > self.timeout {
                     zone.active = false;
                     zones_changed.push(zone.clone());
                 }
@@ -70,15 +78,57 @@ impl PresenceDetectionZones {
     // }
 }
 
-pub fn publish_presence_change(changed_zone: &Zone, tether_agent: &TetherAgent) {
+pub fn publish_presence_change(changed_zone: &Zone, transport: &mut dyn Transport) {
     debug!("ZONE CHANGED: {:?}", changed_zone);
-    let topic = build_publish_topic(
-        "presenceDetection",
-        &changed_zone.id.to_string(),
-        Some("presence"),
-    );
     let payload = if changed_zone.active { &[1] } else { &[0] };
-    tether_agent
-        .publish_raw(&topic, payload, Some(2), Some(false))
+    transport
+        .send(Outgoing::Presence(changed_zone.id), payload)
         .expect("failed to send presence update");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn zone(id: usize, x: f32, y: f32) -> Zone {
+        Zone {
+            id,
+            x,
+            y,
+            width: 100.,
+            height: 100.,
+            active: false,
+            last_active: None,
+        }
+    }
+
+    #[test]
+    fn test_zone_activates_and_times_out() {
+        let clock = ManualClock::new();
+        let mut presence = PresenceDetectionZones::with_clock(
+            &[zone(0, 0., 0.), zone(1, 500., 0.)],
+            clock.shared(),
+        );
+        let inside_first = [TrackedPoint2D::new(0, (50., 50.), None)];
+
+        let changed = presence.update_zones(&inside_first);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].id, 0);
+        assert!(changed[0].active);
+
+        // Still present: no change
+        clock.advance_ms(400);
+        assert!(presence.update_zones(&inside_first).is_empty());
+
+        // Gone, but not for long enough
+        clock.advance_ms(500);
+        assert!(presence.update_zones(&[]).is_empty());
+
+        clock.advance_ms(1);
+        let changed = presence.update_zones(&[]);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].id, 0);
+        assert!(!changed[0].active);
+    }
+}
@@ -0,0 +1,295 @@
+use log::info;
+
+use crate::{
+    backend_config::BackendConfig,
+    geometry_utils::{bearing, distance},
+    tracking::TrackedPoint2D,
+    Point2D,
+};
+
+use super::{
+    position_remapping::PositionRemapping,
+    smoothing::{apply_empty_list_send_mode, EmptyListSendMode},
+};
+
+/// How far ahead (in seconds) to project a point, when converting its velocity into
+/// a region's coordinate frame
+const VELOCITY_PROJECTION_SECS: f32 = 0.1;
+
+struct Region {
+    name: String,
+    remapping: PositionRemapping,
+    empty_lists_sent: u128,
+}
+
+/// Outputs for the (additional) named regions in the config. These are not tracked
+/// separately: the main smoothed points are converted back into world space and then
+/// remapped into each region, so a person has the same ID in every region (and in the main
+/// output), even when moving from one region to another.
+pub struct RegionTracking {
+    regions: Vec<Region>,
+    settings: RegionOutputSettings,
+}
+
+struct RegionOutputSettings {
+    empty_list_send_mode: EmptyListSendMode,
+    should_calculate_velocity: bool,
+    should_calculate_bearing: bool,
+    should_calculate_range: bool,
+}
+
+impl RegionTracking {
+    /// Returns None if there are no named regions in the config
+    pub fn new(config: &BackendConfig) -> Option<Self> {
+        if config.regions.is_empty() {
+            return None;
+        }
+        info!("Outputs for {} named region(s)", config.regions.len());
+
+        let regions = config
+            .regions
+            .iter()
+            .map(|region| Region {
+                name: region.name.clone(),
+                remapping: PositionRemapping::with_region(
+                    Some(&region.corners),
+                    region.origin_location,
+                    region.use_real_units,
+                    region.ignore_outside_margin,
+                ),
+                empty_lists_sent: 0,
+            })
+            .collect();
+
+        Some(RegionTracking {
+            regions,
+            settings: RegionOutputSettings {
+                empty_list_send_mode: config.smoothing_empty_send_mode,
+                should_calculate_velocity: config.should_calculate_velocity(),
+                should_calculate_bearing: config.enable_bearing,
+                should_calculate_range: config.enable_range,
+            },
+        })
+    }
+
+    /// The given (main) smoothed points that are in each region (by name), in that
+    /// region's coordinate frame. Regions with no points are included (or not) as per
+    /// the empty list send mode.
+    pub fn region_points(
+        &mut self,
+        smoothed_points: &[TrackedPoint2D],
+        main_remapping: &PositionRemapping,
+    ) -> Vec<(String, Vec<TrackedPoint2D>)> {
+        let world_points: Vec<WorldPoint> = smoothed_points
+            .iter()
+            .filter_map(|p| WorldPoint::from_main_frame(p, main_remapping))
+            .collect();
+        let settings = &self.settings;
+
+        self.regions
+            .iter_mut()
+            .filter_map(|region| {
+                let points: Vec<TrackedPoint2D> = world_points
+                    .iter()
+                    .filter_map(|p| remap_point(p, &region.remapping, settings))
+                    .collect();
+                apply_empty_list_send_mode(
+                    points,
+                    settings.empty_list_send_mode,
+                    &mut region.empty_lists_sent,
+                )
+                .map(|points| (region.name.clone(), points))
+            })
+            .collect()
+    }
+}
+
+/// A smoothed point converted back into world space
+struct WorldPoint<'a> {
+    point: &'a TrackedPoint2D,
+    position: Point2D,
+    /// Where the point would be (at its current velocity) a moment from now
+    ahead: Option<Point2D>,
+    /// The point's size, in world units
+    size: Option<f32>,
+}
+
+impl<'a> WorldPoint<'a> {
+    fn from_main_frame(
+        point: &'a TrackedPoint2D,
+        main_remapping: &PositionRemapping,
+    ) -> Option<Self> {
+        // The transforms are not linear, so rather than transforming the velocity vector,
+        // find where the point would be a moment from now
+        let ahead = point.velocity.and_then(|[vx, vy]| {
+            main_remapping.inverse_transform_point((
+                point.x + vx * VELOCITY_PROJECTION_SECS,
+                point.y + vy * VELOCITY_PROJECTION_SECS,
+            ))
+        });
+        let position = main_remapping.inverse_transform_point((point.x, point.y))?;
+        // Likewise for the size: measure it out from the point, along the X axis
+        let size = point.size.and_then(|size| {
+            let (ex, ey) = main_remapping.inverse_transform_point((point.x + size, point.y))?;
+            Some(distance(position.0, position.1, ex, ey))
+        });
+        Some(WorldPoint {
+            point,
+            position,
+            ahead,
+            size,
+        })
+    }
+}
+
+fn remap_point(
+    p: &WorldPoint,
+    remapping: &PositionRemapping,
+    settings: &RegionOutputSettings,
+) -> Option<TrackedPoint2D> {
+    let (x, y) = remapping.transform_point_inside(p.position)?;
+    let size = p.size.and_then(|size| {
+        let (ex, ey) = remapping.transform_point((p.position.0 + size, p.position.1))?;
+        Some(distance(x, y, ex, ey))
+    });
+    let mut remapped = TrackedPoint2D::new(p.point.id, (x, y), size);
+    remapped.key = p.point.key.clone();
+
+    if settings.should_calculate_velocity {
+        remapped.velocity = p.ahead.and_then(|ahead| {
+            let (ax, ay) = remapping.transform_point(ahead)?;
+            Some([
+                (ax - x) / VELOCITY_PROJECTION_SECS,
+                (ay - y) / VELOCITY_PROJECTION_SECS,
+            ])
+        });
+    }
+    if settings.should_calculate_bearing {
+        remapped.bearing = Some(bearing(x, y));
+    }
+    if settings.should_calculate_range {
+        remapped.range = Some(distance(x, y, 0., 0.));
+    }
+
+    Some(remapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend_config::{ConfigRectCornerPoint, CornerPoints, NamedRegion};
+    use crate::systems::position_remapping::OriginLocation;
+
+    /// A rectangle from y=1000 to y=3000, with its near-left corner at the given X position
+    fn corners(x: f32, width: f32) -> CornerPoints {
+        (
+            ConfigRectCornerPoint::new(0, x, 1000.),
+            ConfigRectCornerPoint::new(1, x + width, 1000.),
+            ConfigRectCornerPoint::new(2, x + width, 3000.),
+            ConfigRectCornerPoint::new(3, x, 3000.),
+        )
+    }
+
+    fn region(name: &str, x: f32) -> NamedRegion {
+        NamedRegion {
+            name: name.into(),
+            corners: corners(x, 2000.),
+            origin_location: OriginLocation::Corner,
+            use_real_units: false,
+            ignore_outside_margin: Some(0.),
+        }
+    }
+
+    /// The main ROI, covering both regions
+    fn main_remapping() -> PositionRemapping {
+        PositionRemapping::with_region(
+            Some(&corners(-2000., 4000.)),
+            OriginLocation::Corner,
+            false,
+            Some(0.),
+        )
+    }
+
+    /// A smoothed point (in the main frame) at the given world position, 200mm across
+    fn point(id: usize, world: Point2D) -> TrackedPoint2D {
+        let position = main_remapping().transform_point(world).unwrap();
+        TrackedPoint2D::new(id, position, Some(200. / 4000.))
+    }
+
+    fn config(smoothing_empty_send_mode: EmptyListSendMode) -> BackendConfig {
+        BackendConfig {
+            regions: vec![region("left", -2000.), region("right", 0.)],
+            smoothing_empty_send_mode,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_no_regions() {
+        assert!(RegionTracking::new(&BackendConfig::default()).is_none());
+    }
+
+    #[test]
+    fn test_main_points_in_each_region() {
+        let mut tracking = RegionTracking::new(&config(EmptyListSendMode::Always)).unwrap();
+        let main = main_remapping();
+
+        let points = tracking.region_points(&[point(5, (-1000., 2000.))], &main);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].0, "left");
+        assert_eq!(points[0].1.len(), 1);
+        assert_eq!(points[0].1[0].id, 5);
+        let (x, y) = (points[0].1[0].x, points[0].1[0].y);
+        assert!((x - 0.5).abs() < 0.01 && (y - 0.5).abs() < 0.01);
+        assert_eq!(points[1].0, "right");
+        assert!(points[1].1.is_empty());
+
+        // The same person (and ID), having moved into the other region
+        let points = tracking.region_points(&[point(5, (500., 2000.))], &main);
+        assert!(points[0].1.is_empty());
+        assert_eq!(points[1].1.len(), 1);
+        assert_eq!(points[1].1[0].id, 5);
+        assert!((points[1].1[0].x - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_size_in_region_frame() {
+        let mut tracking = RegionTracking::new(&config(EmptyListSendMode::Always)).unwrap();
+
+        // 200mm is 0.05 of the main frame's width, 0.1 of a region's
+        let points = tracking.region_points(&[point(0, (-1000., 2000.))], &main_remapping());
+        let size = points[0].1[0].size.unwrap();
+        assert!((size - 0.1).abs() < 0.001, "{}", size);
+    }
+
+    #[test]
+    fn test_velocity_in_region_frame() {
+        let config = BackendConfig {
+            enable_velocity: true,
+            ..config(EmptyListSendMode::Always)
+        };
+        let mut tracking = RegionTracking::new(&config).unwrap();
+
+        // 1000mm/s to the right is 0.25/s in the main frame, 0.5/s in a region
+        let mut p = point(0, (-1000., 2000.));
+        p.velocity = Some([0.25, 0.]);
+        let points = tracking.region_points(&[p], &main_remapping());
+        let [vx, vy] = points[0].1[0].velocity.unwrap();
+        assert!((vx - 0.5).abs() < 0.01 && vy.abs() < 0.01, "{} {}", vx, vy);
+    }
+
+    #[test]
+    fn test_empty_list_send_mode_per_region() {
+        let mut tracking = RegionTracking::new(&config(EmptyListSendMode::Once)).unwrap();
+        let main = main_remapping();
+        let names = |points: Vec<(String, Vec<TrackedPoint2D>)>| -> Vec<String> {
+            points.into_iter().map(|(name, _)| name).collect()
+        };
+        let points = [point(0, (-1000., 2000.))];
+        assert_eq!(
+            names(tracking.region_points(&points, &main)),
+            ["left", "right"]
+        );
+        assert_eq!(names(tracking.region_points(&points, &main)), ["left"]);
+    }
+}
@@ -1,15 +1,23 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    collections::HashMap,
+    f32::consts::TAU,
+    time::{Duration, Instant},
+};
 
 use log::debug;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::{
+    clock::{default_clock, SharedClock},
     geometry_utils::{bearing, centroid, distance, distance_points, lerp},
     tracking::TrackedPoint2D,
     Point2D,
 };
 
-use super::{clustering::Cluster2D, position_remapping::OriginLocation};
+use super::{
+    clustering::Cluster2D, occlusion::OcclusionSettings, position_remapping::OriginLocation,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub enum EmptyListSendMode {
@@ -18,13 +26,113 @@ pub enum EmptyListSendMode {
     Always,
 }
 
+/// How smoothed points follow their target (latest tracked) positions
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SmoothingFilter {
+    /// Interpolate by the lerp factor on every update; how smooth this looks depends
+    /// on how often updates happen
+    #[default]
+    Lerp,
+    /// Exponential smoothing with the given time constant: after this long, a point has
+    /// moved ~63% of the way to its target, however often it is updated
+    #[serde(rename_all = "camelCase")]
+    Exponential { time_constant_ms: f32 },
+    /// One Euro filter: heavy smoothing (cutoff frequency `min_cutoff`, in Hz) when
+    /// moving slowly, less lag when moving fast (the cutoff rises by `beta` per unit of speed)
+    #[serde(rename_all = "camelCase")]
+    OneEuro { min_cutoff: f32, beta: f32 },
+}
+
+/// How IDs are picked for new smoothed points (always starting from the ID offset)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdPolicy {
+    /// The lowest ID not currently in use; a point that expires can have its ID
+    /// reused straight away
+    #[default]
+    LowestFree,
+    /// Always a new ID, counting up (for as long as the backend runs)
+    Monotonic,
+    /// The lowest ID that is not in use and has not been released (by an expired or
+    /// merged point) within the cooldown
+    #[serde(rename_all = "camelCase")]
+    Cooldown { cooldown_ms: u64 },
+}
+
+/// Re-identification of tracks that expired (e.g. while occluded) and soon reappeared
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReidentifySettings {
+    /// How long (ms) after expiring a track can still be re-acquired
+    pub window_ms: u64,
+    /// A new track must appear at least this close to the edge of where the lost track
+    /// would be by now (given its last velocity, if known, and its size); same units as
+    /// smoothedTrackedPoints, so this needs to be much smaller when not using real units
+    pub max_distance: f32,
+}
+
+impl ReidentifySettings {
+    /// Defaults suited to smoothing with real units (mm), or not (i.e. normalised); the
+    /// [`Default`] is for real units
+    pub fn for_units(use_real_units: bool) -> Self {
+        ReidentifySettings {
+            window_ms: 3000,
+            max_distance: if use_real_units { 500. } else { 0.1 },
+        }
+    }
+}
+
+impl Default for ReidentifySettings {
+    fn default() -> Self {
+        ReidentifySettings::for_units(true)
+    }
+}
+
+/// As published on "trackReacquired": a track that had expired has reappeared and kept
+/// its ID (and key)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackReacquired {
+    pub id: usize,
+    pub key: String,
+    /// How long (ms) the track was not seen for
+    pub lost_ms: u64,
+    /// Where it was re-acquired
+    pub x: f32,
+    pub y: f32,
+}
+
+/// An expired track, remembered for re-identification
+#[derive(Debug)]
+struct LostTrack {
+    id: usize,
+    key: String,
+    position: Point2D,
+    velocity: Option<[f32; 2]>,
+    size: f32,
+    last_updated: Instant,
+    expired: Instant,
+}
+
+/// Hidden points only start coasting once they have not been seen for this long (ms),
+/// i.e. longer than a typical gap between scans
+const COAST_AFTER_MS: u128 = 200;
+
+/// Cutoff frequency (Hz) for smoothing the speed estimate in the One Euro filter
+const ONE_EURO_DERIVATIVE_CUTOFF: f32 = 1.0;
+
 pub struct SmoothSettings {
     pub id_offset: usize,
+    pub id_policy: IdPolicy,
     pub merge_radius: f32,
     pub enable_auto_merge: bool,
     pub wait_before_active_ms: u128,
     pub expire_ms: u128,
     pub lerp_factor: f32,
+    pub filter: SmoothingFilter,
+    pub reidentify: Option<ReidentifySettings>,
+    /// If set, points that are hidden from every sensor (see
+    /// [`TrackingSmoother::update_occlusion`]) are held instead of expiring
+    pub occlusion: Option<OcclusionSettings>,
     pub empty_list_send_mode: EmptyListSendMode,
     pub origin_mode: OriginLocation,
     pub should_calculate_velocity: bool,
@@ -35,15 +143,21 @@ pub struct SmoothSettings {
 #[derive(Debug)]
 struct SmoothedPoint {
     id: usize,
+    /// Unique for the lifetime of this point, unlike the ID (which may be reused)
+    key: String,
     /// This is the **diameter**
     size: f32,
     current_position: Point2D,
     target_position: Point2D,
     velocity: Option<[f32; 2]>,
+    /// Smoothed rate of change of position, as used by the One Euro filter
+    derivative: [f32; 2],
     distance: Option<f32>,
     ready: bool,
-    first_updated: SystemTime,
-    last_updated: SystemTime,
+    /// Hidden from every sensor, as of the last occlusion update
+    occluded: bool,
+    first_updated: Instant,
+    last_updated: Instant,
     /// A list of raw tracking point **indexes** currently in range of this point
     points_in_range: Vec<usize>,
 }
@@ -51,25 +165,44 @@ struct SmoothedPoint {
 pub struct TrackingSmoother {
     settings: SmoothSettings,
     known_points: Vec<SmoothedPoint>,
+    /// The next ID, for [`IdPolicy::Monotonic`]
+    next_id: usize,
+    /// When IDs were last released, for [`IdPolicy::Cooldown`]
+    released_ids: HashMap<usize, Instant>,
+    /// Recently expired tracks, if re-identification is enabled
+    lost_tracks: Vec<LostTrack>,
+    reacquired: Vec<TrackReacquired>,
     empty_lists_sent: u128,
-    last_updated: SystemTime,
+    last_updated: Instant,
+    clock: SharedClock,
 }
 
 impl TrackingSmoother {
     pub fn new(settings: SmoothSettings) -> Self {
+        TrackingSmoother::with_clock(settings, default_clock())
+    }
+
+    /// As per [`TrackingSmoother::new`], but using the given Clock as the source of time
+    pub fn with_clock(settings: SmoothSettings, clock: SharedClock) -> Self {
         if settings.lerp_factor <= 0. {
             panic!("Smoothing lerp factor must be above 0");
         }
         TrackingSmoother {
+            next_id: settings.id_offset,
             settings,
             known_points: Vec::new(),
+            released_ids: HashMap::new(),
+            lost_tracks: Vec::new(),
+            reacquired: Vec::new(),
             empty_lists_sent: 0,
-            last_updated: SystemTime::now(),
+            last_updated: clock.now(),
+            clock,
         }
     }
 
     /// Add some raw points (clusters, position data, etc.) to the tracking-smoothing system
     pub fn update_tracked_points(&mut self, incoming_clusters: &[Cluster2D]) {
+        let now = self.clock.now();
         let mut marked_points_in_range_indexes: Vec<usize> = Vec::new();
 
         for known_point in self.known_points.iter_mut() {
@@ -100,10 +233,12 @@ impl TrackingSmoother {
             }
             if !clusters_in_my_range.is_empty() {
                 // There were points in range; so update time
-                known_point.last_updated = SystemTime::now();
+                known_point.last_updated = now;
                 // If the SmoothedPoint was not ready till now, check if it's time to mark it "ready"
                 if !known_point.ready
-                    && known_point.first_updated.elapsed().unwrap().as_millis()
+                    && now
+                        .saturating_duration_since(known_point.first_updated)
+                        .as_millis()
                         > self.settings.wait_before_active_ms
                 {
                     known_point.ready = true;
@@ -129,38 +264,42 @@ impl TrackingSmoother {
                 //     .duration_since(UNIX_EPOCH)
                 //     .expect("time error");
                 //
-                let start_index = self.settings.id_offset;
-                let id = {
-                    if self.known_points.is_empty() {
-                        start_index
-                    } else {
-                        let mut i = start_index;
-                        for check in start_index..=self.known_points.len() {
-                            if !self.known_points.iter().any(|p| p.id == check) {
-                                i = check;
-                                break;
-                            } else {
-                                i += 1;
-                            }
-                        }
-                        i
+                let lost_track = self.reacquire_lost_track((x, y), now);
+                let is_reacquired = lost_track.is_some();
+                let (id, key) = match lost_track {
+                    Some(lost) => {
+                        debug!("Re-acquired lost track #{}", lost.id);
+                        self.reacquired.push(TrackReacquired {
+                            id: lost.id,
+                            key: lost.key.clone(),
+                            lost_ms: now.saturating_duration_since(lost.last_updated).as_millis()
+                                as u64,
+                            x,
+                            y,
+                        });
+                        (lost.id, lost.key)
                     }
+                    None => (self.new_id(now), Uuid::new_v4().to_string()),
                 };
 
                 let new_point = SmoothedPoint {
                     id,
+                    key,
                     size: p.size,
                     current_position: (x, y),
                     target_position: (x, y),
-                    first_updated: SystemTime::now(),
-                    last_updated: SystemTime::now(),
+                    first_updated: now,
+                    last_updated: now,
                     velocity: None,
+                    derivative: [0., 0.],
+                    occluded: false,
                     distance: if self.settings.should_calculate_range {
                         Some(distance(x, y, 0., 0.))
                     } else {
                         None
                     },
-                    ready: self.settings.wait_before_active_ms == 0,
+                    // A re-acquired track was already active
+                    ready: self.settings.wait_before_active_ms == 0 || is_reacquired,
                     points_in_range: Vec::new(), // will be cleared next frame, anyway
                 };
                 debug!("Added new, unknown point {:?}", &new_point);
@@ -170,91 +309,188 @@ impl TrackingSmoother {
         }
     }
 
+    /// If re-identification is enabled, take the lost track (if any) that would be
+    /// closest to the given position by now
+    fn reacquire_lost_track(&mut self, position: Point2D, now: Instant) -> Option<LostTrack> {
+        let settings = self.settings.reidentify?;
+        let window = Duration::from_millis(settings.window_ms);
+        self.lost_tracks
+            .retain(|lost| now.saturating_duration_since(lost.expired) <= window);
+
+        let (index, _) = self
+            .lost_tracks
+            .iter()
+            .enumerate()
+            .map(|(index, lost)| {
+                let seconds = now
+                    .saturating_duration_since(lost.last_updated)
+                    .as_secs_f32();
+                let [vx, vy] = lost.velocity.unwrap_or_default();
+                let predicted = (
+                    lost.position.0 + vx * seconds,
+                    lost.position.1 + vy * seconds,
+                );
+                let d = distance_points(&predicted, &position) - lost.size / 2.;
+                (index, d.max(0.))
+            })
+            .filter(|(_, d)| *d <= settings.max_distance)
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+        Some(self.lost_tracks.remove(index))
+    }
+
+    /// Re-acquired tracks since this was last called
+    pub fn take_reacquired(&mut self) -> Vec<TrackReacquired> {
+        std::mem::take(&mut self.reacquired)
+    }
+
+    /// Pick an ID for a new point, as per the ID policy; IDs of lost tracks (that may
+    /// yet be re-acquired) are never used
+    fn new_id(&mut self, now: Instant) -> usize {
+        let start_index = self.settings.id_offset;
+        let is_free = |id: &usize| {
+            !self.known_points.iter().any(|p| p.id == *id)
+                && !self.lost_tracks.iter().any(|lost| lost.id == *id)
+        };
+        match self.settings.id_policy {
+            IdPolicy::LowestFree => (start_index..).find(is_free).unwrap_or(start_index),
+            IdPolicy::Monotonic => {
+                let id = self.next_id;
+                self.next_id += 1;
+                id
+            }
+            IdPolicy::Cooldown { cooldown_ms } => {
+                let cooldown = Duration::from_millis(cooldown_ms);
+                self.released_ids
+                    .retain(|_, released| now.saturating_duration_since(*released) < cooldown);
+                (start_index..)
+                    .find(|id| is_free(id) && !self.released_ids.contains_key(id))
+                    .unwrap_or(start_index)
+            }
+        }
+    }
+
     /// Do time-based smoothing of all known points, and also automatically expire any points
     /// that are "stale". This function should be called as often as possible, not necessarily
     /// only when a new TrackedPoint message comes in.
+    ///
+    /// Every candidate for removal is handled on each call, in this order:
+    /// 1. points which never became "ready" within `wait_before_active_ms`
+    /// 2. points which have not been updated within `expire_ms`
+    /// 3. duplicates: a ready point absorbs every younger point within `merge_radius`
+    ///
+    /// `interval` (ms) is used for [`SmoothingFilter::Lerp`]; the time-based filters use
+    /// the actual time elapsed since the last call.
     pub fn update_smoothing(&mut self, interval: u64) {
-        self.last_updated = SystemTime::now();
+        let now = self.clock.now();
+        let elapsed = now
+            .saturating_duration_since(self.last_updated)
+            .as_secs_f32();
+        self.last_updated = now;
+        let ids_before: Vec<usize> = self.known_points.iter().map(|p| p.id).collect();
+
         // First, remove all points which were waiting too long to become "active"...
-        if let Some(i) = self
-            .known_points
-            .iter()
-            .position(|p| match p.last_updated.elapsed() {
-                Ok(elapsed) => {
-                    if elapsed.as_millis() > self.settings.wait_before_active_ms && !p.ready {
-                        debug!(
-                            "Remove point {:?} waiting too long to become active; {}ms > {} ms",
-                            p,
-                            elapsed.as_millis(),
-                            self.settings.wait_before_active_ms
-                        );
-                        true
-                    } else {
-                        false
-                    }
-                }
-                Err(_) => false,
-            })
-        {
-            // swap_remove is a bit faster than remove,
-            // and we don't care about the order
-            self.known_points.swap_remove(i);
-        }
+        let wait_before_active_ms = self.settings.wait_before_active_ms;
+        self.known_points.retain(|p| {
+            if p.ready {
+                return true;
+            }
+            let elapsed = now.saturating_duration_since(p.last_updated);
+            if elapsed.as_millis() > wait_before_active_ms {
+                debug!(
+                    "Remove point {:?} waiting too long to become active; {}ms > {} ms",
+                    p,
+                    elapsed.as_millis(),
+                    wait_before_active_ms
+                );
+                false
+            } else {
+                true
+            }
+        });
 
-        // Next, remove any duplicate points (within merge radius of each other)...
-        let mut duplicate_index = None;
-        self.known_points
-            .iter()
-            .enumerate()
-            .for_each(|(this_index, this_point)| {
-                if let Some((other_index, other_point)) =
-                    self.known_points
-                        .iter()
-                        .enumerate()
-                        .find(|(other_index, other_point)| {
-                            *other_index != this_index
-                                && other_point.ready
-                                && distance_points(
-                                    &other_point.current_position,
-                                    &this_point.current_position,
-                                ) < self.settings.merge_radius
-                        })
-                {
-                    if other_point.first_updated.gt(&this_point.first_updated) {
-                        duplicate_index = Some(other_index);
-                    } else {
-                        duplicate_index = Some(this_index);
-                    }
+        // Next, remove all points which were active but have now expired...
+        // (unless hidden, and not yet held for too long)
+        let expire_ms = self.settings.expire_ms;
+        let max_hold_ms = self
+            .settings
+            .occlusion
+            .map(|occlusion| occlusion.max_hold_ms as u128);
+        let remember_lost = self.settings.reidentify.is_some();
+        let lost_tracks = &mut self.lost_tracks;
+        self.known_points.retain(|p| {
+            let stale_ms = now.saturating_duration_since(p.last_updated).as_millis();
+            let is_held =
+                p.occluded && max_hold_ms.is_some_and(|hold| stale_ms <= expire_ms + hold);
+            if stale_ms > expire_ms && !is_held {
+                debug!("Remove point expired {:?}", p);
+                if remember_lost && p.ready {
+                    lost_tracks.push(LostTrack {
+                        id: p.id,
+                        key: p.key.clone(),
+                        position: p.current_position,
+                        velocity: p.velocity,
+                        size: p.size,
+                        last_updated: p.last_updated,
+                        expired: now,
+                    });
                 }
-            });
-        if let Some(i) = duplicate_index {
-            self.known_points.swap_remove(i);
-        };
+                false
+            } else {
+                true
+            }
+        });
 
-        // Next, remove all points which were active but have now expired...
-        if let Some(i) = self
-            .known_points
-            .iter()
-            .position(|p| match p.last_updated.elapsed() {
-                Ok(elapsed) => elapsed.as_millis() > self.settings.expire_ms,
-                Err(_) => false,
-            })
-        {
-            debug!("Remove point expired");
-            self.known_points.swap_remove(i);
+        // Next, merge any duplicate points (within merge radius of each other)...
+        self.merge_duplicates();
+
+        if matches!(self.settings.id_policy, IdPolicy::Cooldown { .. }) {
+            for id in ids_before {
+                if !self.known_points.iter().any(|p| p.id == id) {
+                    self.released_ids.insert(id, now);
+                }
+            }
         }
 
-        // Next, smooth (lerp) points towards target positions...
+        // Next, smooth points towards target positions...
+        let seconds = match self.settings.filter {
+            SmoothingFilter::Lerp => interval as f32 / 1000.,
+            _ if elapsed <= 0. => return,
+            _ => elapsed,
+        };
+        let should_coast = self.settings.occlusion.is_some_and(|o| o.coast);
         self.known_points.iter_mut().for_each(|p| {
-            let t = self.settings.lerp_factor;
+            // Hidden points keep the velocity they had when last seen
+            let is_coasting = should_coast && p.occluded;
+            if is_coasting
+                && now.saturating_duration_since(p.last_updated).as_millis() > COAST_AFTER_MS
+            {
+                if let Some([vx, vy]) = p.velocity {
+                    p.target_position.0 += vx * seconds;
+                    p.target_position.1 += vy * seconds;
+                }
+            }
             let (x1, y1) = p.current_position;
             let (x2, y2) = p.target_position;
+            let t = match self.settings.filter {
+                SmoothingFilter::Lerp => self.settings.lerp_factor,
+                SmoothingFilter::Exponential { time_constant_ms } => {
+                    smoothing_factor(seconds, time_constant_ms / 1000.)
+                }
+                SmoothingFilter::OneEuro { min_cutoff, beta } => {
+                    let rate = [(x2 - x1) / seconds, (y2 - y1) / seconds];
+                    let d =
+                        smoothing_factor(seconds, cutoff_time_constant(ONE_EURO_DERIVATIVE_CUTOFF));
+                    p.derivative = [
+                        lerp(p.derivative[0], rate[0], d),
+                        lerp(p.derivative[1], rate[1], d),
+                    ];
+                    let speed = (p.derivative[0].powi(2) + p.derivative[1].powi(2)).sqrt();
+                    smoothing_factor(seconds, cutoff_time_constant(min_cutoff + beta * speed))
+                }
+            };
             let [new_x, new_y] = [lerp(x1, x2, t), lerp(y1, y2, t)];
-            if self.settings.should_calculate_velocity {
-                p.velocity = Some([
-                    (new_x - x1) / interval as f32 * 1000.,
-                    (new_y - y1) / interval as f32 * 1000.,
-                ]);
+            if self.settings.should_calculate_velocity && !is_coasting {
+                p.velocity = Some([(new_x - x1) / seconds, (new_y - y1) / seconds]);
             }
             if self.settings.should_calculate_range {
                 p.distance = Some(distance(x1, y1, 0., 0.));
@@ -263,6 +499,54 @@ impl TrackingSmoother {
         })
     }
 
+    /// Mark which (ready) points are currently hidden from every sensor, given a check on
+    /// their position; this should be called before [`TrackingSmoother::update_smoothing`]
+    /// if occlusion handling is enabled
+    pub fn update_occlusion(&mut self, is_occluded: impl Fn(Point2D) -> bool) {
+        for p in self.known_points.iter_mut() {
+            p.occluded = p.ready && is_occluded(p.current_position);
+        }
+    }
+
+    /// Remove points which duplicate another point, i.e. are closer than `merge_radius`.
+    ///
+    /// Points are considered oldest-first (by `first_updated`, then by list order). Only a
+    /// **ready** point can absorb others; it absorbs every younger point in range, whether or
+    /// not that point is ready. An absorbed point cannot absorb anything itself, so chains of
+    /// nearby points resolve the same way every time.
+    fn merge_duplicates(&mut self) {
+        let mut by_age: Vec<usize> = (0..self.known_points.len()).collect();
+        by_age.sort_by_key(|i| self.known_points[*i].first_updated);
+
+        let mut absorbed = vec![false; self.known_points.len()];
+        for (rank, &this_index) in by_age.iter().enumerate() {
+            let this_point = &self.known_points[this_index];
+            if absorbed[this_index] || !this_point.ready {
+                continue;
+            }
+            for &other_index in &by_age[rank + 1..] {
+                let other_point = &self.known_points[other_index];
+                if !absorbed[other_index]
+                    && distance_points(&this_point.current_position, &other_point.current_position)
+                        < self.settings.merge_radius
+                {
+                    debug!(
+                        "Merge duplicate point #{} into #{}",
+                        other_point.id, this_point.id
+                    );
+                    absorbed[other_index] = true;
+                }
+            }
+        }
+
+        let mut index = 0;
+        self.known_points.retain(|_| {
+            let keep = !absorbed[index];
+            index += 1;
+            keep
+        });
+    }
+
     pub fn get_active_smoothed_points(&mut self) -> Option<Vec<TrackedPoint2D>> {
         let known_points: Vec<TrackedPoint2D> = self
             .known_points
@@ -270,6 +554,7 @@ impl TrackingSmoother {
             .filter(|p| p.ready)
             .map(|p| {
                 let mut tp = TrackedPoint2D::new(p.id, p.current_position, Some(p.size));
+                tp.key = Some(p.key.clone());
                 tp.velocity = p.velocity;
                 tp.range = p.distance;
                 if self.settings.should_calculate_bearing {
@@ -280,37 +565,543 @@ impl TrackingSmoother {
             })
             .collect();
 
-        let points_count = known_points.len();
+        apply_empty_list_send_mode(
+            known_points,
+            self.settings.empty_list_send_mode,
+            &mut self.empty_lists_sent,
+        )
+    }
+
+    pub fn get_elapsed(&self) -> Duration {
+        self.clock.elapsed_since(self.last_updated)
+    }
+}
 
-        let points = match self.settings.empty_list_send_mode {
-            EmptyListSendMode::Always => Some(known_points),
-            EmptyListSendMode::Once => {
-                if !known_points.is_empty() || known_points.is_empty() && self.empty_lists_sent < 1
-                {
-                    Some(known_points)
-                } else {
-                    None
-                }
+/// How far (0 to 1) to move towards a target after the given time, for exponential
+/// smoothing with the given time constant (both in seconds)
+pub(crate) fn smoothing_factor(seconds: f32, time_constant: f32) -> f32 {
+    if time_constant <= 0. {
+        1.
+    } else {
+        1. - (-seconds / time_constant).exp()
+    }
+}
+
+/// The time constant (seconds) of a low-pass filter with the given cutoff frequency (Hz)
+fn cutoff_time_constant(cutoff: f32) -> f32 {
+    if cutoff <= 0. {
+        f32::INFINITY
+    } else {
+        1. / (TAU * cutoff)
+    }
+}
+
+/// Decide whether to send a list of points (or anything else) according to the given
+/// mode, where `empty_lists_sent` counts consecutive empty lists so far (and is updated here)
+pub fn apply_empty_list_send_mode<T>(
+    points: Vec<T>,
+    mode: EmptyListSendMode,
+    empty_lists_sent: &mut u128,
+) -> Option<Vec<T>> {
+    let points_count = points.len();
+
+    let points = match mode {
+        EmptyListSendMode::Always => Some(points),
+        EmptyListSendMode::Once => {
+            if !points.is_empty() || points.is_empty() && *empty_lists_sent < 1 {
+                Some(points)
+            } else {
+                None
             }
-            EmptyListSendMode::Never => {
-                if known_points.is_empty() {
-                    None
-                } else {
-                    Some(known_points)
-                }
+        }
+        EmptyListSendMode::Never => {
+            if points.is_empty() {
+                None
+            } else {
+                Some(points)
             }
+        }
+    };
+
+    if points_count == 0 {
+        *empty_lists_sent += 1; // count
+    } else {
+        *empty_lists_sent = 0; // reset
+    }
+
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn settings(wait_before_active_ms: u128, expire_ms: u128) -> SmoothSettings {
+        SmoothSettings {
+            id_offset: 0,
+            id_policy: IdPolicy::LowestFree,
+            merge_radius: 100.,
+            enable_auto_merge: false,
+            wait_before_active_ms,
+            expire_ms,
+            lerp_factor: 1.0,
+            filter: SmoothingFilter::Lerp,
+            reidentify: None,
+            occlusion: None,
+            empty_list_send_mode: EmptyListSendMode::Always,
+            origin_mode: OriginLocation::Corner,
+            should_calculate_velocity: false,
+            should_calculate_bearing: false,
+            should_calculate_range: false,
+        }
+    }
+
+    fn smoother_with_clock(settings: SmoothSettings) -> (TrackingSmoother, ManualClock) {
+        let clock = ManualClock::new();
+        (
+            TrackingSmoother::with_clock(settings, clock.shared()),
+            clock,
+        )
+    }
+
+    fn cluster(id: usize, x: f32, y: f32) -> Cluster2D {
+        Cluster2D {
+            id,
+            x,
+            y,
+            size: 50.,
+        }
+    }
+
+    fn active_ids(smoother: &mut TrackingSmoother) -> Vec<usize> {
+        let mut ids: Vec<usize> = smoother
+            .get_active_smoothed_points()
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.id())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_all_expired_points_removed_in_one_update() {
+        let (mut smoother, clock) = smoother_with_clock(settings(0, 50));
+        smoother.update_tracked_points(&[
+            cluster(0, 0., 0.),
+            cluster(1, 1000., 0.),
+            cluster(2, 2000., 0.),
+            cluster(3, 3000., 0.),
+        ]);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![0, 1, 2, 3]);
+
+        clock.advance_ms(51);
+        smoother.update_smoothing(16);
+        assert!(active_ids(&mut smoother).is_empty());
+    }
+
+    #[test]
+    fn test_only_stale_points_expire() {
+        let (mut smoother, clock) = smoother_with_clock(settings(0, 100));
+        smoother.update_tracked_points(&[
+            cluster(0, 0., 0.),
+            cluster(1, 1000., 0.),
+            cluster(2, 2000., 0.),
+        ]);
+
+        clock.advance_ms(60);
+        // Keep only the middle point "alive"
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.)]);
+
+        clock.advance_ms(60);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![1]);
+    }
+
+    #[test]
+    fn test_all_never_activated_points_removed_in_one_update() {
+        let (mut smoother, clock) = smoother_with_clock(settings(30, 10_000));
+        let clusters = [
+            cluster(0, 0., 0.),
+            cluster(1, 1000., 0.),
+            cluster(2, 2000., 0.),
+        ];
+        smoother.update_tracked_points(&clusters);
+
+        clock.advance_ms(31);
+        smoother.update_smoothing(16);
+
+        // If the waiting points had survived, they would become ready now; instead
+        // they must be re-created from scratch (and therefore not yet be ready)
+        smoother.update_tracked_points(&clusters);
+        smoother.update_smoothing(16);
+        assert!(active_ids(&mut smoother).is_empty());
+    }
+
+    #[test]
+    fn test_multiple_duplicates_merged_into_oldest() {
+        let mut smoother = TrackingSmoother::new(settings(0, 10_000));
+        // Three pairs of points, each pair just outside merge radius
+        smoother.update_tracked_points(&[
+            cluster(0, 0., 0.),
+            cluster(1, 150., 0.),
+            cluster(2, 5000., 0.),
+            cluster(3, 5150., 0.),
+            cluster(4, 10000., 0.),
+            cluster(5, 10150., 0.),
+        ]);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![0, 1, 2, 3, 4, 5]);
+
+        // A single cluster between each pair pulls both points of the pair together
+        smoother.update_tracked_points(&[
+            cluster(0, 75., 0.),
+            cluster(1, 5075., 0.),
+            cluster(2, 10075., 0.),
+        ]);
+        // (merging happens before smoothing, so this takes two updates)
+        smoother.update_smoothing(16);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn test_chain_of_duplicates_merged_consistently() {
+        let mut smoother = TrackingSmoother::new(settings(0, 10_000));
+        // Three new points in a row, each within merge radius of its neighbour, but the
+        // outer two are not within merge radius of each other
+        smoother.update_tracked_points(&[
+            cluster(0, 0., 0.),
+            cluster(1, 80., 0.),
+            cluster(2, 160., 0.),
+        ]);
+        smoother.update_smoothing(16);
+
+        // The oldest point (first in list) absorbs its neighbour; the absorbed point
+        // cannot absorb the third, which is out of range of the survivor
+        assert_eq!(active_ids(&mut smoother), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_expiry_is_exact() {
+        let (mut smoother, clock) = smoother_with_clock(settings(0, 100));
+        smoother.update_tracked_points(&[cluster(0, 0., 0.)]);
+
+        clock.advance_ms(100);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![0]);
+
+        clock.advance_ms(1);
+        smoother.update_smoothing(16);
+        assert!(active_ids(&mut smoother).is_empty());
+    }
+
+    #[test]
+    fn test_point_becomes_active_after_waiting() {
+        let (mut smoother, clock) = smoother_with_clock(settings(100, 10_000));
+        let clusters = [cluster(0, 0., 0.)];
+
+        smoother.update_tracked_points(&clusters);
+        smoother.update_smoothing(16);
+        assert!(active_ids(&mut smoother).is_empty());
+
+        // Keep updating, but not yet for long enough
+        for _ in 0..5 {
+            clock.advance_ms(20);
+            smoother.update_tracked_points(&clusters);
+            smoother.update_smoothing(16);
+            assert!(active_ids(&mut smoother).is_empty());
+        }
+
+        clock.advance_ms(20);
+        smoother.update_tracked_points(&clusters);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![0]);
+    }
+
+    #[test]
+    fn test_elapsed_follows_clock() {
+        let (mut smoother, clock) = smoother_with_clock(settings(0, 100));
+        smoother.update_smoothing(16);
+        assert_eq!(smoother.get_elapsed().as_millis(), 0);
+        clock.advance_ms(16);
+        assert_eq!(smoother.get_elapsed().as_millis(), 16);
+    }
+
+    /// Run a single point towards a target moving along x (as a function of elapsed ms)
+    /// for the given time, updating every `step_ms`; returns the final (target, position)
+    fn follow(
+        filter: SmoothingFilter,
+        step_ms: u64,
+        total_ms: u64,
+        target: impl Fn(u64) -> f32,
+    ) -> (f32, f32) {
+        let (mut smoother, clock) = smoother_with_clock(SmoothSettings {
+            filter,
+            lerp_factor: 0.2,
+            ..settings(0, 10_000)
+        });
+        smoother.update_tracked_points(&[cluster(0, 0., 0.)]);
+        let mut elapsed = 0;
+        while elapsed < total_ms {
+            elapsed += step_ms;
+            clock.advance_ms(step_ms);
+            smoother.update_tracked_points(&[cluster(0, target(elapsed), 0.)]);
+            // The nominal interval is deliberately wrong; it is ignored by time-based filters
+            smoother.update_smoothing(16);
+        }
+        let points = smoother.get_active_smoothed_points().unwrap();
+        (target(elapsed), points[0].x)
+    }
+
+    #[test]
+    fn test_exponential_smoothing_is_independent_of_update_rate() {
+        let filter = SmoothingFilter::Exponential {
+            time_constant_ms: 100.,
         };
+        // A target that jumps (once) by 150
+        let jump = |_| 150.;
+        let (_, fast) = follow(filter, 10, 100, jump);
+        let (_, slow) = follow(filter, 50, 100, jump);
+        // ~63% of the way there after one time constant, either way
+        assert!((fast - 94.8).abs() < 1., "{}", fast);
+        assert!((slow - fast).abs() < 0.01, "{} vs {}", slow, fast);
+
+        // Unlike lerping
+        let (_, fast) = follow(SmoothingFilter::Lerp, 10, 100, jump);
+        let (_, slow) = follow(SmoothingFilter::Lerp, 50, 100, jump);
+        assert!((slow - fast).abs() > 10., "{} vs {}", slow, fast);
+    }
+
+    #[test]
+    fn test_one_euro_lags_less_when_moving_fast() {
+        // Moving steadily at 1000/s
+        let moving = |ms| ms as f32;
+        let lag = |beta| {
+            let (target, position) = follow(
+                SmoothingFilter::OneEuro {
+                    min_cutoff: 1.,
+                    beta,
+                },
+                20,
+                1000,
+                moving,
+            );
+            target - position
+        };
+        let (plain, adaptive) = (lag(0.), lag(0.01));
+        assert!(
+            adaptive > 0. && adaptive < plain / 2.,
+            "{} vs {}",
+            adaptive,
+            plain
+        );
+    }
+
+    /// Two points (IDs 0 and 1), the first of which expires; returns the smoother with
+    /// only point 1 left
+    fn smoother_after_expiry(id_policy: IdPolicy) -> (TrackingSmoother, ManualClock) {
+        let (mut smoother, clock) = smoother_with_clock(SmoothSettings {
+            id_policy,
+            ..settings(0, 50)
+        });
+        smoother.update_tracked_points(&[cluster(0, 0., 0.), cluster(1, 1000., 0.)]);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![0, 1]);
 
-        if points_count == 0 {
-            self.empty_lists_sent += 1; // count
-        } else {
-            self.empty_lists_sent = 0; // reset
+        clock.advance_ms(60);
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.)]);
+        smoother.update_smoothing(16);
+        assert_eq!(active_ids(&mut smoother), vec![1]);
+        (smoother, clock)
+    }
+
+    #[test]
+    fn test_id_policies() {
+        let (mut smoother, _clock) = smoother_after_expiry(IdPolicy::LowestFree);
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.), cluster(1, 5000., 0.)]);
+        assert_eq!(active_ids(&mut smoother), vec![0, 1]);
+
+        let (mut smoother, _clock) = smoother_after_expiry(IdPolicy::Monotonic);
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.), cluster(1, 5000., 0.)]);
+        assert_eq!(active_ids(&mut smoother), vec![1, 2]);
+
+        let (mut smoother, clock) = smoother_after_expiry(IdPolicy::Cooldown { cooldown_ms: 100 });
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.), cluster(1, 5000., 0.)]);
+        assert_eq!(active_ids(&mut smoother), vec![1, 2]);
+        // Once the cooldown is over, the lowest free ID is used again
+        clock.advance_ms(100);
+        smoother.update_tracked_points(&[
+            cluster(0, 1000., 0.),
+            cluster(1, 5000., 0.),
+            cluster(2, 9000., 0.),
+        ]);
+        assert_eq!(active_ids(&mut smoother), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_keys_are_stable_and_never_reused() {
+        let key = |smoother: &mut TrackingSmoother, id: usize| {
+            smoother
+                .get_active_smoothed_points()
+                .unwrap()
+                .into_iter()
+                .find(|p| p.id == id)
+                .and_then(|p| p.key)
+                .unwrap()
+        };
+        let (mut smoother, clock) = smoother_with_clock(settings(0, 50));
+        smoother.update_tracked_points(&[cluster(0, 0., 0.), cluster(1, 1000., 0.)]);
+        smoother.update_smoothing(16);
+        let (first, other) = (key(&mut smoother, 0), key(&mut smoother, 1));
+        assert_ne!(first, other);
+        assert_eq!(first.len(), 36);
+
+        clock.advance_ms(20);
+        smoother.update_tracked_points(&[cluster(0, 10., 0.), cluster(1, 1000., 0.)]);
+        smoother.update_smoothing(16);
+        assert_eq!(key(&mut smoother, 0), first);
+
+        // ID 0 expires and is reused, with a new key
+        clock.advance_ms(60);
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.)]);
+        smoother.update_smoothing(16);
+        smoother.update_tracked_points(&[cluster(0, 1000., 0.), cluster(1, 5000., 0.)]);
+        assert_ne!(key(&mut smoother, 0), first);
+    }
+
+    /// A point (ID 0) walking along x at 1000/s for 100ms, which then expires; returns
+    /// its key
+    fn lose_walking_point(smoother: &mut TrackingSmoother, clock: &ManualClock) -> String {
+        for step in 0..=5 {
+            if step > 0 {
+                clock.advance_ms(20);
+            }
+            smoother.update_tracked_points(&[cluster(0, step as f32 * 20., 0.)]);
+            smoother.update_smoothing(20);
         }
+        let key = smoother.get_active_smoothed_points().unwrap()[0]
+            .key
+            .clone()
+            .unwrap();
+        clock.advance_ms(60);
+        smoother.update_smoothing(20);
+        assert!(active_ids(smoother).is_empty());
+        key
+    }
 
-        points
+    fn reidentifying_smoother() -> (TrackingSmoother, ManualClock) {
+        smoother_with_clock(SmoothSettings {
+            should_calculate_velocity: true,
+            reidentify: Some(ReidentifySettings {
+                window_ms: 1000,
+                max_distance: 100.,
+            }),
+            ..settings(0, 50)
+        })
     }
 
-    pub fn get_elapsed(&self) -> Duration {
-        self.last_updated.elapsed().unwrap_or_default()
+    #[test]
+    fn test_lost_track_reacquired_near_predicted_position() {
+        let (mut smoother, clock) = reidentifying_smoother();
+        let key = lose_walking_point(&mut smoother, &clock);
+
+        // 200ms after last being seen at x=100, it should be at x=300; a stranger
+        // appearing elsewhere does not get the lost ID
+        clock.advance_ms(140);
+        smoother.update_tracked_points(&[cluster(0, 2000., 0.), cluster(1, 340., 0.)]);
+        let points = smoother.get_active_smoothed_points().unwrap();
+        let reacquired = points.iter().find(|p| p.x == 340.).unwrap();
+        assert_eq!((reacquired.id, reacquired.key.as_ref()), (0, Some(&key)));
+        assert_eq!(points.iter().find(|p| p.x == 2000.).unwrap().id, 1);
+
+        assert_eq!(
+            smoother.take_reacquired(),
+            [TrackReacquired {
+                id: 0,
+                key,
+                lost_ms: 200,
+                x: 340.,
+                y: 0.
+            }]
+        );
+        assert!(smoother.take_reacquired().is_empty());
+    }
+
+    #[test]
+    fn test_lost_track_forgotten_after_window() {
+        let (mut smoother, clock) = reidentifying_smoother();
+        let key = lose_walking_point(&mut smoother, &clock);
+
+        clock.advance_ms(1001);
+        smoother.update_tracked_points(&[cluster(0, 1100., 0.)]);
+        let points = smoother.get_active_smoothed_points().unwrap();
+        assert_eq!(points[0].id, 0);
+        assert_ne!(points[0].key.as_ref(), Some(&key));
+        assert!(smoother.take_reacquired().is_empty());
+    }
+
+    /// A point (ID 0) walking along x at 1000/s for 100ms, then hidden
+    fn hide_walking_point(smoother: &mut TrackingSmoother, clock: &ManualClock) {
+        for step in 0..=5 {
+            if step > 0 {
+                clock.advance_ms(20);
+            }
+            smoother.update_tracked_points(&[cluster(0, step as f32 * 20., 0.)]);
+            smoother.update_smoothing(20);
+        }
+        smoother.update_occlusion(|_| true);
+    }
+
+    fn occlusion_smoother(coast: bool) -> (TrackingSmoother, ManualClock) {
+        smoother_with_clock(SmoothSettings {
+            should_calculate_velocity: true,
+            occlusion: Some(OcclusionSettings {
+                track_radius: 250.,
+                max_hold_ms: 500,
+                coast,
+            }),
+            ..settings(0, 50)
+        })
+    }
+
+    #[test]
+    fn test_occluded_point_held_until_max_hold() {
+        let (mut smoother, clock) = occlusion_smoother(false);
+        hide_walking_point(&mut smoother, &clock);
+
+        clock.advance_ms(550);
+        smoother.update_smoothing(20);
+        let points = smoother.get_active_smoothed_points().unwrap();
+        assert_eq!((points[0].id, points[0].x), (0, 100.));
+
+        clock.advance_ms(1);
+        smoother.update_smoothing(20);
+        assert!(active_ids(&mut smoother).is_empty());
+
+        // Points which are not hidden expire as normal
+        let (mut smoother, clock) = occlusion_smoother(false);
+        hide_walking_point(&mut smoother, &clock);
+        smoother.update_occlusion(|_| false);
+        clock.advance_ms(51);
+        smoother.update_smoothing(20);
+        assert!(active_ids(&mut smoother).is_empty());
+    }
+
+    #[test]
+    fn test_occluded_point_coasts_at_last_velocity() {
+        let (mut smoother, clock) = occlusion_smoother(true);
+        hide_walking_point(&mut smoother, &clock);
+
+        // Held for the first 200ms, then moving at 1000/s
+        for _ in 0..15 {
+            clock.advance_ms(20);
+            smoother.update_smoothing(20);
+        }
+        let points = smoother.get_active_smoothed_points().unwrap();
+        assert!((points[0].x - 200.).abs() < 1., "x = {}", points[0].x);
+        assert_eq!(points[0].y, 0.);
     }
 }
@@ -1,12 +1,13 @@
-use log::{debug, error, info};
-use tether_agent::{ChannelDefinition, ChannelOptionsBuilder, TetherAgent};
-
-use crate::{
-    backend_config::BackendConfig,
-    systems::{clustering::Cluster2D, Systems},
-    Point2D,
+use anyhow::Result;
+use log::warn;
+use tether_agent::{
+    tether_compliant_topic::{build_publish_topic, TetherOrCustomTopic},
+    ChannelDefinition, ChannelOptionsBuilder, TetherAgent,
 };
 
+use crate::encoding::decode_any;
+use crate::transport::{Incoming, Outgoing, Transport};
+
 pub struct Outputs {
     pub config_output: ChannelDefinition,
     pub clusters_output: ChannelDefinition,
@@ -14,6 +15,11 @@ pub struct Outputs {
     pub smoothed_tracking_output: ChannelDefinition,
     pub smoothed_remapped_output: ChannelDefinition,
     pub movement_output: ChannelDefinition,
+    pub behaviour_output: ChannelDefinition,
+    pub track_reacquired_output: ChannelDefinition,
+    pub groups_output: ChannelDefinition,
+    pub crowd_stats_output: ChannelDefinition,
+    pub heatmap_output: ChannelDefinition,
 }
 
 impl Outputs {
@@ -52,6 +58,34 @@ impl Outputs {
             .build(tether_agent)
             .expect("failed to create Output Plug");
 
+        // Also events
+        let track_reacquired_output = ChannelOptionsBuilder::create_sender("trackReacquired")
+            .qos(Some(1))
+            .build(tether_agent)
+            .expect("failed to create Output Plug");
+
+        // Behaviour changes are events, so should not be missed
+        let behaviour_output = ChannelOptionsBuilder::create_sender("behaviour")
+            .qos(Some(1))
+            .build(tether_agent)
+            .expect("failed to create Output Plug");
+
+        let groups_output = ChannelOptionsBuilder::create_sender("groups")
+            .qos(Some(0))
+            .build(tether_agent)
+            .expect("failed to create Output Plug");
+
+        let crowd_stats_output = ChannelOptionsBuilder::create_sender("crowdStats")
+            .build(tether_agent)
+            .expect("failed to create Output Plug");
+
+        // Heatmap output; retained, since it is only published occasionally
+        let heatmap_output = ChannelOptionsBuilder::create_sender("heatmap")
+            .qos(Some(1))
+            .retain(Some(true))
+            .build(tether_agent)
+            .expect("failed to create Output Plug");
+
         Outputs {
             config_output,
             tracking_output,
@@ -59,6 +93,11 @@ impl Outputs {
             smoothed_tracking_output,
             smoothed_remapped_output,
             movement_output,
+            behaviour_output,
+            track_reacquired_output,
+            groups_output,
+            crowd_stats_output,
+            heatmap_output,
         }
     }
 }
@@ -100,142 +139,110 @@ impl Inputs {
     }
 }
 
-pub fn handle_scans_message(
-    serial: &str,
-    scans: &[Point2D],
-    config: &mut BackendConfig,
-    tether_agent: &TetherAgent,
-    systems: &mut Systems,
-    outputs: &Outputs,
-    config_file_path: &str,
-) {
-    let Systems {
-        clustering_system,
-        position_remapping,
-        automask_samplers,
-        smoothing_system,
-        ..
-    } = systems;
-
-    let Outputs {
-        config_output,
-        clusters_output,
-        tracking_output,
-        ..
-    } = outputs;
-
-    // If an unknown device was found (and added), re-publish the Device config
-    if let Some(()) = config.check_or_create_device(serial, config.default_min_distance_threshold) {
-        config
-            .save_and_republish(tether_agent, config_output, config_file_path)
-            .expect("failed to save and republish config");
+/// A [`Transport`] using a Tether Agent, i.e. MQTT; payloads are encoded by the caller, and
+/// scans may arrive in any of the supported [`Encoding`](crate::encoding::Encoding)s
+pub struct TetherTransport {
+    tether_agent: TetherAgent,
+    inputs: Inputs,
+    outputs: Outputs,
+}
+
+impl TetherTransport {
+    pub fn new(mut tether_agent: TetherAgent) -> Self {
+        let inputs = Inputs::new(&mut tether_agent);
+        let outputs = Outputs::new(&mut tether_agent);
+        TetherTransport {
+            tether_agent,
+            inputs,
+            outputs,
+        }
     }
 
-    if let Some(device) = config.get_device(serial) {
-        clustering_system.update_from_scan(scans, device);
-        let clusters = clustering_system.clusters();
+    pub fn tether_agent(&self) -> &TetherAgent {
+        &self.tether_agent
+    }
 
-        if !config.skip_some_outputs {
-            let payload = rmp_serde::to_vec(&clusters).expect("failed to serialize clusters");
-            tether_agent
-                .send(clusters_output, Some(&payload))
-                .expect("failed to publish clusters");
+    fn channel(&self, output: &Outgoing) -> Option<&ChannelDefinition> {
+        let outputs = &self.outputs;
+        match output {
+            Outgoing::Config => Some(&outputs.config_output),
+            Outgoing::Clusters => Some(&outputs.clusters_output),
+            Outgoing::TrackedPoints => Some(&outputs.tracking_output),
+            Outgoing::SmoothedTrackedPoints => Some(&outputs.smoothed_tracking_output),
+            Outgoing::SmoothedRemappedPoints => Some(&outputs.smoothed_remapped_output),
+            Outgoing::Movement => Some(&outputs.movement_output),
+            Outgoing::Behaviour => Some(&outputs.behaviour_output),
+            Outgoing::TrackReacquired => Some(&outputs.track_reacquired_output),
+            Outgoing::Groups => Some(&outputs.groups_output),
+            Outgoing::CrowdStats => Some(&outputs.crowd_stats_output),
+            Outgoing::Heatmap => Some(&outputs.heatmap_output),
+            Outgoing::Presence(_) | Outgoing::RegionSmoothedTrackedPoints(_) => None,
         }
+    }
+}
 
-        if position_remapping.is_ready() {
-            let transformed_clusters: Vec<Cluster2D> =
-                position_remapping.transform_clusters(clusters);
+impl Transport for TetherTransport {
+    fn receive(&mut self) -> Option<Incoming> {
+        let (topic, message) = self.tether_agent.check_messages()?;
+
+        if self.inputs.scans_input.matches(&topic) {
+            let serial_number = match &topic {
+                TetherOrCustomTopic::Tether(t) => t.id(),
+                TetherOrCustomTopic::Custom(s) => {
+                    panic!(
+                        "The topic \"{}\" is not expected for Lidar scan messages",
+                        &s
+                    );
+                }
+            };
 
-            // Normal (unsmoothed) tracked points...
-            let filtered_clusters =
-                position_remapping.filter_clusters_inside(&transformed_clusters);
+            let scans: Vec<(f32, f32)> = match decode_any(&message) {
+                Ok((scans, _)) => scans,
+                Err(e) => {
+                    warn!("Dropping scans that could not be decoded: {}", e);
+                    return None;
+                }
+            };
 
-            smoothing_system.update_tracked_points(&filtered_clusters);
+            return Some(Incoming::Scans {
+                serial: String::from(serial_number.unwrap()),
+                samples: scans,
+            });
+        }
 
-            if !config.skip_some_outputs {
-                let raw_points: Vec<Point2D> =
-                    filtered_clusters.iter().map(|c| (c.x, c.y)).collect();
+        if self.inputs.save_config_input.matches(&topic) {
+            return Some(Incoming::SaveConfig(message));
+        }
 
-                let payload =
-                    rmp_serde::to_vec(&raw_points).expect("failed to serialize tracked points");
-                tether_agent
-                    .send(tracking_output, Some(&payload))
-                    .expect("failed to publish tracked points");
-            }
+        if self.inputs.request_automask_input.matches(&topic) {
+            return Some(Incoming::RequestAutoMask(message));
         }
 
-        if let Some(sampler) = automask_samplers.get_mut(serial) {
-            if !sampler.is_complete() {
-                if let Some(new_mask) = sampler.add_samples(scans) {
-                    debug!("Sufficient samples for masking device {}", serial);
-                    match config.update_device_masking(new_mask, serial) {
-                        Ok(()) => {
-                            info!("Updated masking for device {}", serial);
-                            config
-                                .save_and_republish(tether_agent, config_output, config_file_path)
-                                .expect("failed save and republish config");
-                            sampler.angles_with_thresholds.clear();
-                        }
-                        Err(e) => {
-                            error!("Error updating masking for device {}: {}", serial, e);
-                        }
-                    }
-                }
+        None
+    }
+
+    fn send(&mut self, output: Outgoing, payload: &[u8]) -> Result<()> {
+        match &output {
+            Outgoing::Presence(zone_id) => {
+                let topic = build_publish_topic(
+                    "presenceDetection",
+                    &zone_id.to_string(),
+                    Some("presence"),
+                );
+                self.tether_agent
+                    .publish_raw(&topic, payload, Some(2), Some(false))
+            }
+            Outgoing::RegionSmoothedTrackedPoints(region_name) => {
+                // Regions can be added at any time, so there is no pre-defined channel
+                let topic =
+                    build_publish_topic(self.tether_agent.role(), output.name(), Some(region_name));
+                self.tether_agent
+                    .publish_raw(&topic, payload, Some(0), Some(false))
+            }
+            _ => {
+                let channel = self.channel(&output).expect("no channel for output");
+                self.tether_agent.send(channel, Some(payload))
             }
         }
     }
 }
-
-// pub fn handle_external_tracking_message(
-//     serial: &str,
-//     points: &[Point2D],
-//     config: &mut BackendConfig,
-//     tether_agent: &TetherAgent,
-//     systems: &mut Systems,
-//     outputs: &Outputs,
-//     config_file_path: &str,
-// ) {
-//     let Systems {
-//         clustering_system,
-//         perspective_transformer,
-//         smoothing_system,
-//         ..
-//     } = systems;
-
-//     let Outputs {
-//         config_output,
-//         clusters_output,
-//         tracking_output,
-//         ..
-//     } = outputs;
-
-//     // If an unknown device was found (and added), re-publish the Device config
-//     if let Some(()) = config.check_or_create_external_tracker(serial) {
-//         config
-//             .save_and_republish(tether_agent, config_output, config_file_path)
-//             .expect("failed to save and republish config");
-//     }
-
-//     if let Some(tracker) = config.get_external_tracker(serial) {
-//         clustering_system.update_from_external_tracker(points, tracker);
-//         let clusters = clustering_system.clusters();
-//         tether_agent
-//             .encode_and_publish(clusters_output, clusters)
-//             .expect("failed to publish clusters");
-
-//         if perspective_transformer.is_ready() {
-//             let points: Vec<Point2D> = clusters
-//                 .iter()
-//                 .map(|c| perspective_transformer.transform(&(c.x, c.y)).unwrap())
-//                 .collect();
-
-//             if let Ok(tracked_points) = perspective_transformer.filter_points_inside(&points) {
-//                 // Normal (unsmoothed) tracked points...
-//                 tether_agent
-//                     .encode_and_publish(tracking_output, &tracked_points)
-//                     .expect("failed to publish tracked points");
-//                 smoothing_system.update_tracked_points(&tracked_points);
-//             }
-//         }
-//     }
-// }
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::Point2D;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TrackedPoint2D {
     pub id: usize,
@@ -13,6 +15,12 @@ pub struct TrackedPoint2D {
     pub bearing: Option<f32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub range: Option<f32>,
+    /// Where this point is expected to be after the prediction lookahead, given its velocity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predicted: Option<Point2D>,
+    /// Unique (UUID) key for this track, which unlike the ID is never reused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -42,10 +50,21 @@ impl TrackedPoint2D {
             velocity: None,
             bearing: None,
             range: None,
+            predicted: None,
+            key: None,
         }
     }
 
     pub fn id(&self) -> usize {
         self.id
     }
+
+    /// Identifies the track for as long as it exists: its key, if it has one, since IDs are
+    /// reused once tracks expire
+    pub fn track_key(&self) -> String {
+        match &self.key {
+            Some(key) => key.clone(),
+            None => format!("#{}", self.id),
+        }
+    }
 }
@@ -0,0 +1,62 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+use crate::Point2D;
+
+use super::{Incoming, Outgoing, Transport};
+
+/// A Transport which simply queues messages in memory: push incoming messages in, and
+/// inspect (or take) whatever was sent.
+#[derive(Default, Debug)]
+pub struct MemoryTransport {
+    incoming: VecDeque<Incoming>,
+    sent: Vec<(Outgoing, Vec<u8>)>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+
+    pub fn push(&mut self, message: Incoming) {
+        self.incoming.push_back(message);
+    }
+
+    pub fn push_scans(&mut self, serial: &str, samples: &[Point2D]) {
+        self.push(Incoming::Scans {
+            serial: String::from(serial),
+            samples: Vec::from(samples),
+        });
+    }
+
+    /// Everything sent so far, oldest first
+    pub fn sent(&self) -> &[(Outgoing, Vec<u8>)] {
+        &self.sent
+    }
+
+    /// Everything sent so far, oldest first; clears the list
+    pub fn take_sent(&mut self) -> Vec<(Outgoing, Vec<u8>)> {
+        std::mem::take(&mut self.sent)
+    }
+
+    /// The most recent payload sent on the given output, if any
+    pub fn last_sent(&self, output: Outgoing) -> Option<&[u8]> {
+        self.sent
+            .iter()
+            .rev()
+            .find(|(o, _)| *o == output)
+            .map(|(_, payload)| payload.as_slice())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn receive(&mut self) -> Option<Incoming> {
+        self.incoming.pop_front()
+    }
+
+    fn send(&mut self, output: Outgoing, payload: &[u8]) -> Result<()> {
+        self.sent.push((output, Vec::from(payload)));
+        Ok(())
+    }
+}
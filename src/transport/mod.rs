@@ -0,0 +1,204 @@
+//! The backend talks to the outside world via a [`Transport`]: it receives scans and
+//! commands, and publishes (already-encoded) outputs. The usual implementation is
+//! [`crate::tether_interface::TetherTransport`]; [`memory::MemoryTransport`] needs no broker
+//! at all, so the consolidation pipeline can be embedded in other applications and tested.
+
+pub mod memory;
+pub mod websocket;
+
+use anyhow::Result;
+use log::warn;
+
+use crate::Point2D;
+
+/// Messages that the backend can receive
+#[derive(Debug, Clone)]
+pub enum Incoming {
+    /// Scan samples (angle, distance) from the LIDAR device with the given serial number
+    Scans {
+        serial: String,
+        samples: Vec<Point2D>,
+    },
+    /// An encoded [`crate::backend_config::BackendConfig`] to apply and save, as sent on
+    /// "saveLidarConfig"
+    SaveConfig(Vec<u8>),
+    /// An encoded [`crate::systems::automasking::AutoMaskMessage`], as sent on
+    /// "requestAutoMask"
+    RequestAutoMask(Vec<u8>),
+}
+
+/// Everything the backend can publish
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Outgoing {
+    /// The complete backend config; "provideLidarConfig"
+    Config,
+    /// "clusters"
+    Clusters,
+    /// Transformed but unsmoothed points; "trackedPoints"
+    TrackedPoints,
+    /// "smoothedTrackedPoints"
+    SmoothedTrackedPoints,
+    /// "smoothedRemappedPoints"
+    SmoothedRemappedPoints,
+    /// "movement"
+    Movement,
+    /// A change in presence for the zone with the given ID
+    Presence(usize),
+    /// Changes in behaviour of tracked points (see
+    /// [`crate::systems::behaviour::BehaviourChange`]); "behaviour"
+    Behaviour,
+    /// A lost track was re-acquired (see
+    /// [`crate::systems::smoothing::TrackReacquired`]); "trackReacquired"
+    TrackReacquired,
+    /// Groups of tracked points (see [`crate::systems::groups::Group`]); "groups"
+    Groups,
+    /// Aggregate measures of the crowd (see [`crate::systems::crowd_stats::CrowdStats`]);
+    /// "crowdStats"
+    CrowdStats,
+    /// Accumulated occupancy (see [`crate::systems::heatmap::HeatmapGrid`]); "heatmap"
+    Heatmap,
+    /// Smoothed points for the named region (see [`crate::backend_config::NamedRegion`])
+    RegionSmoothedTrackedPoints(String),
+}
+
+impl Outgoing {
+    /// The name of the output (Tether plug/channel), as used for per-output settings
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outgoing::Config => "provideLidarConfig",
+            Outgoing::Clusters => "clusters",
+            Outgoing::TrackedPoints => "trackedPoints",
+            Outgoing::SmoothedTrackedPoints => "smoothedTrackedPoints",
+            Outgoing::SmoothedRemappedPoints => "smoothedRemappedPoints",
+            Outgoing::Movement => "movement",
+            Outgoing::Behaviour => "behaviour",
+            Outgoing::TrackReacquired => "trackReacquired",
+            Outgoing::Groups => "groups",
+            Outgoing::CrowdStats => "crowdStats",
+            Outgoing::Heatmap => "heatmap",
+            Outgoing::Presence(_) => "presence",
+            Outgoing::RegionSmoothedTrackedPoints(_) => "regionSmoothedTrackedPoints",
+        }
+    }
+}
+
+pub trait Transport {
+    /// Return the next incoming message, if any; this should never block
+    fn receive(&mut self) -> Option<Incoming>;
+
+    /// Publish an (already encoded) payload on the given output
+    fn send(&mut self, output: Outgoing, payload: &[u8]) -> Result<()>;
+
+    /// Called with messages that were received by another transport in a [`Fanout`],
+    /// e.g. so that scans can be mirrored; does nothing by default
+    fn observe(&mut self, _incoming: &Incoming) {}
+}
+
+/// Combines a primary transport with any number of mirrors: messages are received from
+/// all of them, and everything is sent to all of them. Failures to send on a mirror are
+/// only logged, so that (for example) a broken WebSocket connection never stops Tether
+/// output.
+pub struct Fanout {
+    /// The primary transport first, then mirrors
+    transports: Vec<Box<dyn Transport>>,
+}
+
+impl Fanout {
+    pub fn new(primary: Box<dyn Transport>) -> Self {
+        Fanout {
+            transports: vec![primary],
+        }
+    }
+
+    pub fn add_mirror(&mut self, mirror: Box<dyn Transport>) {
+        self.transports.push(mirror);
+    }
+}
+
+impl Transport for Fanout {
+    fn receive(&mut self) -> Option<Incoming> {
+        for index in 0..self.transports.len() {
+            if let Some(incoming) = self.transports[index].receive() {
+                for (other, transport) in self.transports.iter_mut().enumerate() {
+                    if other != index {
+                        transport.observe(&incoming);
+                    }
+                }
+                return Some(incoming);
+            }
+        }
+        None
+    }
+
+    fn send(&mut self, output: Outgoing, payload: &[u8]) -> Result<()> {
+        let (primary, mirrors) = self.transports.split_first_mut().expect("no primary");
+        for mirror in mirrors {
+            if let Err(e) = mirror.send(output.clone(), payload) {
+                warn!(
+                    "Failed to send {} on mirror transport: {}",
+                    output.name(),
+                    e
+                );
+            }
+        }
+        primary.send(output, payload)
+    }
+
+    fn observe(&mut self, incoming: &Incoming) {
+        for transport in self.transports.iter_mut() {
+            transport.observe(incoming);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{memory::MemoryTransport, *};
+
+    /// Records what it observes and sends; never receives anything
+    #[derive(Default, Clone)]
+    struct Recorder {
+        observed: Rc<RefCell<Vec<String>>>,
+        sent: Rc<RefCell<Vec<Outgoing>>>,
+    }
+
+    impl Transport for Recorder {
+        fn receive(&mut self) -> Option<Incoming> {
+            None
+        }
+
+        fn send(&mut self, output: Outgoing, _payload: &[u8]) -> Result<()> {
+            self.sent.borrow_mut().push(output);
+            Ok(())
+        }
+
+        fn observe(&mut self, incoming: &Incoming) {
+            self.observed.borrow_mut().push(format!("{:?}", incoming));
+        }
+    }
+
+    #[test]
+    fn test_fanout() {
+        let mut primary = MemoryTransport::new();
+        primary.push_scans("a", &[(0., 100.)]);
+        let mut mirror = MemoryTransport::new();
+        mirror.push(Incoming::SaveConfig(vec![1, 2, 3]));
+        let recorder = Recorder::default();
+
+        let mut fanout = Fanout::new(Box::new(primary));
+        fanout.add_mirror(Box::new(mirror));
+        fanout.add_mirror(Box::new(recorder.clone()));
+
+        // The primary is checked first, then the mirrors
+        assert!(matches!(fanout.receive(), Some(Incoming::Scans { .. })));
+        assert!(matches!(fanout.receive(), Some(Incoming::SaveConfig(_))));
+        assert!(fanout.receive().is_none());
+        assert_eq!(recorder.observed.borrow().len(), 2);
+        assert!(recorder.observed.borrow()[0].starts_with("Scans"));
+
+        fanout.send(Outgoing::Movement, &[0]).unwrap();
+        assert_eq!(*recorder.sent.borrow(), vec![Outgoing::Movement]);
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tungstenite::{
+    handshake::{server::NoCallback, MidHandshake},
+    protocol::WebSocketConfig,
+    HandshakeError, Message, ServerHandshake, WebSocket,
+};
+
+use crate::{encoding::decode_any, Point2D};
+
+use super::{Incoming, Outgoing, Transport};
+
+/// How long a newly-connected client may take to complete the WebSocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Most that may be buffered (bytes) for a client that is not keeping up, before it is dropped
+const MAX_WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+/// A client that is not keeping up is also dropped after this many sends in a row that
+/// could not be completed
+const MAX_BLOCKED_SENDS: usize = 500;
+
+/// Every message, in either direction, is a JSON object with the Tether plug name as
+/// "topic", e.g. `{ "topic": "requestAutoMask", "payload": { "type": "new" } }`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WebSocketMessage {
+    pub topic: String,
+    /// Only used for "scans" (the device serial number), "presence" (the zone ID) and
+    /// "regionSmoothedTrackedPoints" (the region name)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<String>,
+    pub payload: Value,
+}
+
+/// A client that has connected, but not yet completed the WebSocket handshake
+struct PendingHandshake {
+    handshake: MidHandshake<ServerHandshake<TcpStream, NoCallback>>,
+    address: SocketAddr,
+    started: Instant,
+}
+
+/// A connected client, and how many sends in a row to it have not been completed
+struct Client {
+    socket: WebSocket<TcpStream>,
+    blocked_sends: usize,
+}
+
+/// A Transport which hosts a local WebSocket server: every output (and, via
+/// [`Transport::observe`], every scan) is streamed to all connected clients as JSON,
+/// and clients can send "saveLidarConfig" and "requestAutoMask" commands.
+///
+/// Usually this runs alongside the Tether transport, using [`super::Fanout`].
+pub struct WebSocketTransport {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    /// Handshakes are driven without blocking, a little on every [`Transport::receive`]
+    pending: Vec<PendingHandshake>,
+    /// The last config sent, so that clients get it as soon as they connect
+    last_config: Option<String>,
+}
+
+impl WebSocketTransport {
+    pub fn bind(address: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        info!(
+            "WebSocket server listening on ws://{}",
+            listener.local_addr()?
+        );
+        Ok(WebSocketTransport {
+            listener,
+            clients: Vec::new(),
+            pending: Vec::new(),
+            last_config: None,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn accept_clients(&mut self) {
+        for pending in std::mem::take(&mut self.pending) {
+            if pending.started.elapsed() > HANDSHAKE_TIMEOUT {
+                warn!("WebSocket handshake with {} timed out", pending.address);
+                continue;
+            }
+            self.continue_handshake(
+                pending.handshake.handshake(),
+                pending.address,
+                pending.started,
+            );
+        }
+
+        while let Ok((stream, address)) = self.listener.accept() {
+            // Accepted streams do not necessarily inherit the listener's non-blocking mode
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("Failed to set up WebSocket client {}: {}", address, e);
+                continue;
+            }
+            let config = WebSocketConfig {
+                max_write_buffer_size: MAX_WRITE_BUFFER_SIZE,
+                ..Default::default()
+            };
+            self.continue_handshake(
+                tungstenite::accept_with_config(stream, Some(config)),
+                address,
+                Instant::now(),
+            );
+        }
+    }
+
+    /// Add the client if the handshake is complete, or keep it for later if it would block
+    fn continue_handshake(
+        &mut self,
+        result: Result<
+            WebSocket<TcpStream>,
+            HandshakeError<ServerHandshake<TcpStream, NoCallback>>,
+        >,
+        address: SocketAddr,
+        started: Instant,
+    ) {
+        match result {
+            Ok(mut client) => {
+                info!("WebSocket client connected from {}", address);
+                if let Some(config) = &self.last_config {
+                    if let Err(e) = client.send(Message::text(config.clone())) {
+                        warn!("Failed to send config to new WebSocket client: {}", e);
+                        return;
+                    }
+                }
+                self.clients.push(Client {
+                    socket: client,
+                    blocked_sends: 0,
+                });
+            }
+            Err(HandshakeError::Interrupted(handshake)) => self.pending.push(PendingHandshake {
+                handshake,
+                address,
+                started,
+            }),
+            Err(HandshakeError::Failure(e)) => {
+                warn!("WebSocket handshake with {} failed: {}", address, e)
+            }
+        }
+    }
+
+    fn broadcast(&mut self, message: &WebSocketMessage) -> Result<()> {
+        let text = serde_json::to_string(message)?;
+        if message.topic == Outgoing::Config.name() {
+            self.last_config = Some(text.clone());
+        }
+        // Messages that could not be sent straight away stay in the client's write buffer,
+        // which is limited, so clients that stop reading are eventually dropped
+        self.clients.retain_mut(
+            |client| match client.socket.send(Message::text(text.clone())) {
+                Ok(()) => {
+                    client.blocked_sends = 0;
+                    true
+                }
+                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                    client.blocked_sends += 1;
+                    if client.blocked_sends > MAX_BLOCKED_SENDS {
+                        warn!("Dropping WebSocket client which is not keeping up");
+                        return false;
+                    }
+                    true
+                }
+                Err(tungstenite::Error::WriteBufferFull(_)) => {
+                    warn!("Dropping WebSocket client which is not keeping up");
+                    false
+                }
+                Err(e) => {
+                    debug!("Dropping WebSocket client: {}", e);
+                    false
+                }
+            },
+        );
+        Ok(())
+    }
+}
+
+/// Convert a client command into the equivalent incoming (Tether) message
+fn parse_command(text: &str) -> Result<Incoming> {
+    let message: WebSocketMessage = serde_json::from_str(text)?;
+    let payload = serde_json::to_vec(&message.payload)?;
+    match message.topic.as_str() {
+        "saveLidarConfig" => Ok(Incoming::SaveConfig(payload)),
+        "requestAutoMask" => Ok(Incoming::RequestAutoMask(payload)),
+        other => Err(anyhow!("Unsupported WebSocket command topic \"{}\"", other)),
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn receive(&mut self) -> Option<Incoming> {
+        self.accept_clients();
+
+        let mut incoming = None;
+        self.clients.retain_mut(|client| {
+            if incoming.is_some() {
+                return true;
+            }
+            match client.socket.read() {
+                Ok(Message::Text(text)) => {
+                    match parse_command(&text) {
+                        Ok(command) => incoming = Some(command),
+                        Err(e) => warn!("Ignoring WebSocket message: {}", e),
+                    }
+                    true
+                }
+                Ok(Message::Close(_)) => false,
+                Ok(_) => true,
+                Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => true,
+                Err(e) => {
+                    debug!("Dropping WebSocket client: {}", e);
+                    false
+                }
+            }
+        });
+        incoming
+    }
+
+    fn send(&mut self, output: Outgoing, payload: &[u8]) -> Result<()> {
+        if self.clients.is_empty() && output != Outgoing::Config {
+            return Ok(());
+        }
+        let message = match output {
+            Outgoing::Presence(zone_id) => WebSocketMessage {
+                topic: output.name().into(),
+                id: Some(zone_id.to_string()),
+                payload: Value::Bool(payload.first() == Some(&1)),
+            },
+            _ => {
+                let (payload, _encoding) = decode_any::<Value>(payload)?;
+                WebSocketMessage {
+                    topic: output.name().into(),
+                    id: match &output {
+                        Outgoing::RegionSmoothedTrackedPoints(region_name) => {
+                            Some(region_name.clone())
+                        }
+                        _ => None,
+                    },
+                    payload,
+                }
+            }
+        };
+        self.broadcast(&message)
+    }
+
+    fn observe(&mut self, incoming: &Incoming) {
+        if let Incoming::Scans { serial, samples } = incoming {
+            if self.clients.is_empty() {
+                return;
+            }
+            let message = WebSocketMessage {
+                topic: "scans".into(),
+                id: Some(serial.clone()),
+                payload: serde_json::to_value::<&[Point2D]>(samples)
+                    .expect("failed to serialise scans"),
+            };
+            if let Err(e) = self.broadcast(&message) {
+                warn!("Failed to stream scans: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tungstenite::connect;
+
+    use super::*;
+    use crate::encoding::{encode, Encoding};
+
+    fn next_text(client: &mut WebSocket<tungstenite::stream::MaybeTlsStream<TcpStream>>) -> Value {
+        match client.read().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        match parse_command(r#"{ "topic": "requestAutoMask", "payload": { "type": "new" } }"#)
+            .unwrap()
+        {
+            Incoming::RequestAutoMask(payload) => {
+                let (value, encoding) = decode_any::<Value>(&payload).unwrap();
+                assert_eq!(encoding, Encoding::Json);
+                assert_eq!(value["type"], "new");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_command(r#"{ "topic": "scans", "payload": [] }"#).is_err());
+        assert!(parse_command("not json").is_err());
+    }
+
+    #[test]
+    fn test_silent_connection_does_not_block() {
+        let mut server = WebSocketTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+
+        // Connects, but never sends a handshake request
+        let _silent = TcpStream::connect(address).unwrap();
+        let started = Instant::now();
+        while server.pending.is_empty() {
+            assert!(server.receive().is_none());
+        }
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // Others can still connect meanwhile
+        let connecting = thread::spawn(move || connect(format!("ws://{}", address)).unwrap().0);
+        while server.client_count() == 0 {
+            assert!(server.receive().is_none());
+            thread::sleep(Duration::from_millis(1));
+        }
+        let _client = connecting.join().unwrap();
+        assert_eq!(server.pending.len(), 1);
+
+        // ...and the silent one is given up on eventually
+        thread::sleep(HANDSHAKE_TIMEOUT);
+        server.receive();
+        assert!(server.pending.is_empty());
+        assert_eq!(server.client_count(), 1);
+    }
+
+    #[test]
+    fn test_client_that_never_reads_is_dropped() {
+        let mut server = WebSocketTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+        let connecting = thread::spawn(move || connect(format!("ws://{}", address)).unwrap().0);
+        while server.client_count() == 0 {
+            server.receive();
+            thread::sleep(Duration::from_millis(1));
+        }
+        let _client = connecting.join().unwrap();
+
+        let samples: Vec<Point2D> = (0..2000).map(|i| (i as f32, 1000.)).collect();
+        let scans = Incoming::Scans {
+            serial: "abc".into(),
+            samples,
+        };
+        for _ in 0..10000 {
+            server.observe(&scans);
+            if server.client_count() == 0 {
+                return;
+            }
+        }
+        panic!("client was never dropped");
+    }
+
+    #[test]
+    fn test_stream_and_commands() {
+        let mut server = WebSocketTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = server.local_addr().unwrap();
+
+        // The config is cached for clients that connect later
+        server
+            .send(
+                Outgoing::Config,
+                &encode(&serde_json::json!({ "devices": [] }), Encoding::MessagePack).unwrap(),
+            )
+            .unwrap();
+
+        let connecting = thread::spawn(move || connect(format!("ws://{}", address)).unwrap().0);
+        while server.client_count() == 0 {
+            assert!(server.receive().is_none());
+            thread::sleep(Duration::from_millis(1));
+        }
+        let mut client = connecting.join().unwrap();
+
+        let config = next_text(&mut client);
+        assert_eq!(config["topic"], "provideLidarConfig");
+        assert_eq!(config["payload"]["devices"], serde_json::json!([]));
+
+        server
+            .send(
+                Outgoing::Movement,
+                &encode(&(0.5, -0.5), Encoding::MessagePack).unwrap(),
+            )
+            .unwrap();
+        server.send(Outgoing::Presence(3), &[1]).unwrap();
+        server.observe(&Incoming::Scans {
+            serial: "abc".into(),
+            samples: vec![(90., 1000.)],
+        });
+
+        let movement = next_text(&mut client);
+        assert_eq!(movement["topic"], "movement");
+        assert_eq!(movement["payload"], serde_json::json!([0.5, -0.5]));
+        let presence = next_text(&mut client);
+        assert_eq!(presence["id"], "3");
+        assert_eq!(presence["payload"], true);
+        let scans = next_text(&mut client);
+        assert_eq!(scans["topic"], "scans");
+        assert_eq!(scans["id"], "abc");
+        assert_eq!(scans["payload"], serde_json::json!([[90., 1000.]]));
+
+        client
+            .send(Message::text(
+                r#"{ "topic": "requestAutoMask", "payload": { "type": "clear" } }"#,
+            ))
+            .unwrap();
+        let command = loop {
+            if let Some(incoming) = server.receive() {
+                break incoming;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        assert!(matches!(command, Incoming::RequestAutoMask(_)));
+    }
+}
@@ -0,0 +1,272 @@
+//! [TUIO 1.1](https://www.tuio.org/?specification) output: smoothed tracked points as
+//! `/tuio/2Dcur` cursors, for interactive floor projection (and similar) software.
+
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use anyhow::Result;
+
+use crate::{
+    clock::{default_clock, SharedClock},
+    osc::{OscArg, OscMessage, OscSender},
+    systems::position_remapping::PositionRemapping,
+    tracking::TrackedPoint2D,
+};
+
+const PROFILE: &str = "/tuio/2Dcur";
+
+pub struct TuioSender {
+    osc: OscSender,
+    source: String,
+    frame_sequence: i32,
+    last_frame: Option<Instant>,
+    /// Last known speed per session ID, used to calculate motion acceleration
+    last_speeds: HashMap<usize, f32>,
+    clock: SharedClock,
+}
+
+impl TuioSender {
+    pub fn new(target: SocketAddr, source: &str) -> Result<Self> {
+        TuioSender::with_clock(target, source, default_clock())
+    }
+
+    /// As per [`TuioSender::new`], but timing frames with the given Clock (e.g. the same one
+    /// as the tracking systems)
+    pub fn with_clock(target: SocketAddr, source: &str, clock: SharedClock) -> Result<Self> {
+        Ok(TuioSender {
+            osc: OscSender::new(target, "")?,
+            source: String::from(source),
+            frame_sequence: 0,
+            last_frame: None,
+            last_speeds: HashMap::new(),
+            clock,
+        })
+    }
+
+    /// Send one TUIO frame (source, alive, set..., fseq) for the currently active points.
+    ///
+    /// TUIO positions must be normalised [0;1], so points (whether normalised or in real
+    /// units) are normalised to the ROI of the given remapping.
+    pub fn send_frame(&mut self, points: &[TrackedPoint2D], remapping: &PositionRemapping) {
+        let messages = self.next_frame(points, remapping);
+        self.osc.send_bundle(&messages);
+    }
+
+    /// Build the messages for the next frame, timed by the Clock
+    pub fn next_frame(
+        &mut self,
+        points: &[TrackedPoint2D],
+        remapping: &PositionRemapping,
+    ) -> Vec<OscMessage> {
+        let now = self.clock.now();
+        let elapsed_secs = self
+            .last_frame
+            .map(|t| now.saturating_duration_since(t).as_secs_f32())
+            .unwrap_or_default();
+        self.last_frame = Some(now);
+
+        self.frame_messages(points, remapping, elapsed_secs)
+    }
+
+    /// Build the messages for the next frame, `elapsed_secs` after the previous one
+    pub fn frame_messages(
+        &mut self,
+        points: &[TrackedPoint2D],
+        remapping: &PositionRemapping,
+        elapsed_secs: f32,
+    ) -> Vec<OscMessage> {
+        self.frame_sequence = self.frame_sequence.wrapping_add(1);
+
+        let mut messages = vec![
+            OscMessage::new(
+                PROFILE,
+                vec![
+                    OscArg::String("source".into()),
+                    OscArg::String(self.source.clone()),
+                ],
+            ),
+            OscMessage::new(
+                PROFILE,
+                std::iter::once(OscArg::String("alive".into()))
+                    .chain(points.iter().map(|p| OscArg::Int(p.id as i32)))
+                    .collect(),
+            ),
+        ];
+
+        let mut speeds = HashMap::new();
+        for p in points {
+            let (x, y) = remapping.normalise_point((p.x, p.y));
+            // Normalising is linear, so a vector is normalised relative to the origin
+            let (vx, vy) = match p.velocity {
+                Some([vx, vy]) => {
+                    let (ox, oy) = remapping.normalise_point((0., 0.));
+                    let (nx, ny) = remapping.normalise_point((vx, vy));
+                    (nx - ox, ny - oy)
+                }
+                None => (0., 0.),
+            };
+            let speed = (vx * vx + vy * vy).sqrt();
+            let acceleration = match self.last_speeds.get(&p.id) {
+                Some(last_speed) if elapsed_secs > 0. => (speed - last_speed) / elapsed_secs,
+                _ => 0.,
+            };
+            speeds.insert(p.id, speed);
+
+            messages.push(OscMessage::new(
+                PROFILE,
+                vec![
+                    OscArg::String("set".into()),
+                    OscArg::Int(p.id as i32),
+                    OscArg::Float(x),
+                    OscArg::Float(y),
+                    OscArg::Float(vx),
+                    OscArg::Float(vy),
+                    OscArg::Float(acceleration),
+                ],
+            ));
+        }
+        self.last_speeds = speeds;
+
+        messages.push(OscMessage::new(
+            PROFILE,
+            vec![
+                OscArg::String("fseq".into()),
+                OscArg::Int(self.frame_sequence),
+            ],
+        ));
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend_config::ConfigRectCornerPoint, clock::ManualClock,
+        systems::position_remapping::OriginLocation,
+    };
+
+    fn sender() -> TuioSender {
+        TuioSender::new("127.0.0.1:3333".parse().unwrap(), "test").unwrap()
+    }
+
+    fn point(id: usize, x: f32, y: f32, velocity: [f32; 2]) -> TrackedPoint2D {
+        let mut p = TrackedPoint2D::new(id, (x, y), None);
+        p.velocity = Some(velocity);
+        p
+    }
+
+    fn normalised() -> PositionRemapping {
+        PositionRemapping::with_region(None, OriginLocation::Corner, false, None)
+    }
+
+    fn args(message: &OscMessage) -> &[OscArg] {
+        assert_eq!(message.address, PROFILE);
+        &message.args
+    }
+
+    #[test]
+    fn test_frame_structure() {
+        let mut tuio = sender();
+        let messages = tuio.frame_messages(
+            &[point(2, 0.5, 0.25, [0.1, 0.]), point(7, 0., 1., [0., 0.])],
+            &normalised(),
+            0.,
+        );
+        assert_eq!(messages.len(), 5);
+        assert_eq!(
+            args(&messages[0]),
+            &[
+                OscArg::String("source".into()),
+                OscArg::String("test".into())
+            ]
+        );
+        assert_eq!(
+            args(&messages[1]),
+            &[
+                OscArg::String("alive".into()),
+                OscArg::Int(2),
+                OscArg::Int(7)
+            ]
+        );
+        assert_eq!(
+            args(&messages[2]),
+            &[
+                OscArg::String("set".into()),
+                OscArg::Int(2),
+                OscArg::Float(0.5),
+                OscArg::Float(0.25),
+                OscArg::Float(0.1),
+                OscArg::Float(0.),
+                OscArg::Float(0.)
+            ]
+        );
+        assert_eq!(
+            args(&messages[4]),
+            &[OscArg::String("fseq".into()), OscArg::Int(1)]
+        );
+
+        // Empty frames still have alive + fseq, and the sequence increments
+        let messages = tuio.frame_messages(&[], &normalised(), 0.);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(args(&messages[1]), &[OscArg::String("alive".into())]);
+        assert_eq!(
+            args(&messages[2]),
+            &[OscArg::String("fseq".into()), OscArg::Int(2)]
+        );
+    }
+
+    #[test]
+    fn test_acceleration() {
+        let mut tuio = sender();
+        tuio.frame_messages(&[point(1, 0., 0., [0.1, 0.])], &normalised(), 0.);
+        let messages = tuio.frame_messages(&[point(1, 0., 0., [0.3, 0.])], &normalised(), 0.5);
+        match args(&messages[2])[6] {
+            OscArg::Float(m) => assert!((m - 0.4).abs() < 0.0001),
+            _ => panic!("expected float"),
+        }
+    }
+
+    #[test]
+    fn test_frames_timed_by_clock() {
+        let clock = ManualClock::new();
+        let mut tuio =
+            TuioSender::with_clock("127.0.0.1:3333".parse().unwrap(), "test", clock.shared())
+                .unwrap();
+        tuio.next_frame(&[point(1, 0., 0., [0.1, 0.])], &normalised());
+        clock.advance_ms(500);
+        let messages = tuio.next_frame(&[point(1, 0., 0., [0.3, 0.])], &normalised());
+        match args(&messages[2])[6] {
+            OscArg::Float(m) => assert!((m - 0.4).abs() < 0.0001),
+            _ => panic!("expected float"),
+        }
+    }
+
+    #[test]
+    fn test_real_units_are_normalised() {
+        let mut tuio = sender();
+        // A 4000x2000 ROI, in mm from its centre
+        let remapping = PositionRemapping::with_region(
+            Some(&(
+                ConfigRectCornerPoint::new(0, 0., 0.),
+                ConfigRectCornerPoint::new(1, 4000., 0.),
+                ConfigRectCornerPoint::new(2, 4000., 2000.),
+                ConfigRectCornerPoint::new(3, 0., 2000.),
+            )),
+            OriginLocation::Centre,
+            true,
+            None,
+        );
+        let messages = tuio.frame_messages(&[point(0, 0., 500., [400., 200.])], &remapping, 0.);
+        let values: Vec<f32> = args(&messages[2])[2..6]
+            .iter()
+            .map(|arg| match arg {
+                OscArg::Float(v) => *v,
+                _ => panic!("expected float"),
+            })
+            .collect();
+        for (value, expected) in values.iter().zip([0.5, 0.75, 0.1, 0.1]) {
+            assert!((value - expected).abs() < 0.0001, "{:?}", values);
+        }
+    }
+}
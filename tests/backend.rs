@@ -0,0 +1,823 @@
+//! Full backend behaviour (as per `lidar2d-backend`), using an in-memory Transport
+
+mod common;
+
+use std::f32::consts::TAU;
+
+use common::{person_samples, roi_config};
+use tether_lidar2d_consolidation::{
+    backend::{handle_incoming, update_smoothed_outputs},
+    backend_config::{BackendConfig, ConfigRectCornerPoint, NamedRegion},
+    clock::ManualClock,
+    encoding::Encoding,
+    systems::{
+        automasking::AutoMaskMessage,
+        behaviour::{Behaviour, BehaviourChange, BehaviourSettings},
+        clustering::LegPairSettings,
+        crowd_stats::{CrowdStats, CrowdStatsSettings},
+        groups::{Group, GroupSettings},
+        heatmap::{HeatmapGrid, HeatmapSettings},
+        movement::MovementMode,
+        occlusion::OcclusionSettings,
+        position_remapping::{FrameUnits, OriginLocation, RemappedFrame},
+        presence::Zone,
+        smoothing::{ReidentifySettings, TrackReacquired},
+        Systems,
+    },
+    tracking::TrackedPoint2D,
+    transport::{
+        memory::MemoryTransport, websocket::WebSocketTransport, Incoming, Outgoing, Transport,
+    },
+    Point2D,
+};
+
+struct Backend {
+    config: BackendConfig,
+    transport: MemoryTransport,
+    systems: Systems,
+    clock: ManualClock,
+    config_path: String,
+}
+
+impl Backend {
+    fn new(name: &str, config: BackendConfig) -> Self {
+        let clock = ManualClock::new();
+        let systems = Systems::with_clock(&config, clock.shared());
+        Backend {
+            config,
+            transport: MemoryTransport::new(),
+            systems,
+            clock,
+            config_path: std::env::temp_dir()
+                .join(format!(
+                    "lidar2d-backend-{}-{}.json",
+                    name,
+                    std::process::id()
+                ))
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+
+    /// Handle all queued messages, then advance time and update smoothing once
+    fn step(&mut self, ms: u64) {
+        while let Some(message) = self.transport.receive() {
+            handle_incoming(
+                message,
+                &mut self.config,
+                &mut self.transport,
+                &mut self.systems,
+                &self.config_path,
+            );
+        }
+        self.clock.advance_ms(ms);
+        update_smoothed_outputs(&self.config, &mut self.transport, &mut self.systems);
+    }
+
+    /// Smoothed points as (id, x, y). These are encoded as MessagePack arrays, which omit
+    /// any optional fields that are not enabled, so they are decoded generically here.
+    fn last_smoothed(&self) -> Option<Vec<(u64, f64, f64)>> {
+        self.transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .map(|payload| {
+                let points: Vec<Vec<serde_json::Value>> = rmp_serde::from_slice(payload).unwrap();
+                points
+                    .iter()
+                    .map(|p| {
+                        (
+                            p[0].as_u64().unwrap(),
+                            p[1].as_f64().unwrap(),
+                            p[2].as_f64().unwrap(),
+                        )
+                    })
+                    .collect()
+            })
+    }
+}
+
+impl Drop for Backend {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.config_path);
+    }
+}
+
+fn config_with_roi() -> BackendConfig {
+    BackendConfig {
+        smoothing_lerp_factor: 1.0,
+        smoothing_wait_before_active_ms: 0,
+        ..roi_config()
+    }
+}
+
+#[test]
+fn test_unknown_device_is_added_and_config_published() {
+    let mut backend = Backend::new("unknown-device", config_with_roi());
+    backend
+        .transport
+        .push_scans("new-device", &person_samples((0., 3000.)));
+    backend.step(20);
+
+    assert_eq!(backend.config.devices().len(), 1);
+    let published: BackendConfig =
+        rmp_serde::from_slice(backend.transport.last_sent(Outgoing::Config).unwrap()).unwrap();
+    assert_eq!(published.devices()[0].serial, "new-device");
+    assert!(backend.transport.last_sent(Outgoing::Clusters).is_some());
+    assert!(backend
+        .transport
+        .last_sent(Outgoing::TrackedPoints)
+        .is_some());
+}
+
+#[test]
+fn test_scans_produce_smoothed_points() {
+    let mut backend = Backend::new("smoothed", config_with_roi());
+    for _ in 0..5 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((0., 3000.)));
+        backend.step(20);
+    }
+    let points = backend.last_smoothed().unwrap();
+    assert_eq!(points.len(), 1);
+    // Default origin is the centre of the ROI
+    let (_id, x, y) = points[0];
+    assert!(x.abs() < 1. && y.abs() < 1.);
+}
+
+#[test]
+fn test_no_smoothed_output_before_interval() {
+    let mut backend = Backend::new("interval", config_with_roi());
+    backend
+        .transport
+        .push_scans("a", &person_samples((0., 3000.)));
+    backend.step(1);
+    assert!(backend.last_smoothed().is_none());
+    backend.step(20);
+    assert!(backend.last_smoothed().is_some());
+}
+
+#[test]
+fn test_skip_some_outputs() {
+    let config = BackendConfig {
+        skip_some_outputs: true,
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("skip", config);
+    backend
+        .transport
+        .push_scans("a", &person_samples((0., 3000.)));
+    backend.step(20);
+    assert!(backend.transport.last_sent(Outgoing::Clusters).is_none());
+    assert!(backend
+        .transport
+        .last_sent(Outgoing::TrackedPoints)
+        .is_none());
+    assert!(backend.last_smoothed().is_some());
+}
+
+#[test]
+fn test_presence_zone_changes_are_published() {
+    let mut config = config_with_roi();
+    let zone: Zone =
+        serde_json::from_str(r#"{ "id": 3, "x": -500, "y": -500, "width": 1000, "height": 1000 }"#)
+            .unwrap();
+    config.zones = Some(vec![zone]);
+    let mut backend = Backend::new("presence", config);
+
+    backend
+        .transport
+        .push_scans("a", &person_samples((0., 3000.)));
+    backend.step(20);
+    backend.step(20);
+    assert_eq!(
+        backend.transport.last_sent(Outgoing::Presence(3)),
+        Some([1].as_slice())
+    );
+
+    // Expire the point (and then the zone)
+    for _ in 0..200 {
+        backend.step(20);
+    }
+    assert_eq!(
+        backend.transport.last_sent(Outgoing::Presence(3)),
+        Some([0].as_slice())
+    );
+}
+
+#[test]
+fn test_save_config_message_applies_and_republishes() {
+    let mut backend = Backend::new("save", config_with_roi());
+    let new_config = BackendConfig {
+        smoothing_merge_radius: 321.,
+        ..config_with_roi()
+    };
+    backend.transport.push(Incoming::SaveConfig(
+        rmp_serde::to_vec(&new_config).unwrap(),
+    ));
+    backend.step(1);
+
+    assert_eq!(backend.config.smoothing_merge_radius, 321.);
+    let published: BackendConfig =
+        rmp_serde::from_slice(backend.transport.last_sent(Outgoing::Config).unwrap()).unwrap();
+    assert_eq!(published.smoothing_merge_radius, 321.);
+    let saved = std::fs::read_to_string(&backend.config_path).unwrap();
+    assert!(saved.contains("321"));
+}
+
+#[test]
+fn test_automask_request_builds_mask() {
+    let config = BackendConfig {
+        automask_scans_required: 3,
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("automask", config);
+    backend.transport.push_scans("a", &[(0., 1000.)]);
+    backend.step(1);
+
+    backend.transport.push(Incoming::RequestAutoMask(
+        rmp_serde::to_vec(&AutoMaskMessage {
+            r#type: "new".into(),
+        })
+        .unwrap(),
+    ));
+    for _ in 0..3 {
+        backend.transport.push_scans("a", &[(0., 1000.)]);
+    }
+    backend.step(1);
+
+    let mask = backend
+        .config
+        .get_device("a")
+        .unwrap()
+        .scan_mask_thresholds
+        .as_ref();
+    assert_eq!(mask.unwrap().get("0"), Some(&950.));
+}
+
+#[test]
+fn test_json_outputs_and_commands() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("json", config);
+    backend
+        .transport
+        .push_scans("a", &person_samples((0., 3000.)));
+    backend.step(20);
+
+    let points: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(points.len(), 1);
+    let published: BackendConfig =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::Config).unwrap()).unwrap();
+    assert_eq!(published.output_encoding, Encoding::Json);
+
+    // Commands may be sent as JSON, too
+    backend
+        .transport
+        .push(Incoming::RequestAutoMask(br#"{"type":"clear"}"#.to_vec()));
+    let mut new_config = published;
+    new_config.smoothing_merge_radius = 222.;
+    backend.transport.push(Incoming::SaveConfig(
+        serde_json::to_vec(&new_config).unwrap(),
+    ));
+    backend.step(1);
+    assert_eq!(backend.config.smoothing_merge_radius, 222.);
+}
+
+#[test]
+fn test_smoothed_remapped_points() {
+    let mut backend = Backend::new("remapped-none", config_with_roi());
+    backend
+        .transport
+        .push_scans("a", &person_samples((0., 3000.)));
+    backend.step(20);
+    assert!(backend.last_smoothed().is_some());
+    assert!(backend
+        .transport
+        .last_sent(Outgoing::SmoothedRemappedPoints)
+        .is_none());
+
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        smoothed_remapped_frame: Some(RemappedFrame {
+            origin: OriginLocation::Corner,
+            flip_x: true,
+            flip_y: false,
+            units: FrameUnits::Metres,
+            scale: 1.,
+        }),
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("remapped", config);
+    backend
+        .transport
+        .push_scans("a", &person_samples((1000., 2000.)));
+    backend.step(20);
+
+    let points: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedRemappedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(points.len(), 1);
+    // 1m from the right-hand side (flipped X) and 1m from the near side
+    assert!((points[0].x - 1.).abs() < 0.1, "{:?}", points[0]);
+    assert!((points[0].y - 1.).abs() < 0.1, "{:?}", points[0]);
+}
+
+#[test]
+fn test_named_regions_publish_their_own_points() {
+    let stage = |name: &str, x: f32| NamedRegion {
+        name: name.into(),
+        corners: (
+            ConfigRectCornerPoint::new(0, x, 1000.),
+            ConfigRectCornerPoint::new(1, x + 2000., 1000.),
+            ConfigRectCornerPoint::new(2, x + 2000., 5000.),
+            ConfigRectCornerPoint::new(3, x, 5000.),
+        ),
+        origin_location: OriginLocation::Corner,
+        use_real_units: true,
+        ignore_outside_margin: Some(0.),
+    };
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        regions: vec![stage("stageLeft", -2000.), stage("stageRight", 0.)],
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("regions", config);
+    for _ in 0..3 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((1000., 2000.)));
+        backend.step(20);
+    }
+
+    let region_points = |backend: &Backend, name: &str| -> Vec<TrackedPoint2D> {
+        serde_json::from_slice(
+            backend
+                .transport
+                .last_sent(Outgoing::RegionSmoothedTrackedPoints(name.into()))
+                .unwrap(),
+        )
+        .unwrap()
+    };
+    assert!(region_points(&backend, "stageLeft").is_empty());
+    let right = region_points(&backend, "stageRight");
+    assert_eq!(right.len(), 1);
+    assert!((right[0].x - 1000.).abs() < 50., "{:?}", right[0]);
+    assert!((right[0].y - 1000.).abs() < 50., "{:?}", right[0]);
+
+    // Someone else arrives on the other stage; everyone has the same ID (and key) in
+    // the region streams as in the main stream
+    for _ in 0..3 {
+        let mut samples = person_samples((1000., 2000.));
+        samples.extend(person_samples((-1000., 4000.)));
+        backend.transport.push_scans("a", &samples);
+        backend.step(20);
+    }
+    let main: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(main.len(), 2);
+    for name in ["stageLeft", "stageRight"] {
+        let points = region_points(&backend, name);
+        assert_eq!(points.len(), 1);
+        let same = main.iter().find(|p| p.id == points[0].id).unwrap();
+        assert_eq!(same.key, points[0].key);
+        assert!(same.key.is_some());
+    }
+    assert_ne!(
+        region_points(&backend, "stageLeft")[0].id,
+        region_points(&backend, "stageRight")[0].id
+    );
+}
+
+#[test]
+fn test_heatmap_accumulates_and_is_published_periodically() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        heatmap: Some(HeatmapSettings {
+            columns: 4,
+            rows: 4,
+            publish_interval: 100,
+        }),
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("heatmap", config);
+    for _ in 0..10 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((1000., 2000.)));
+        backend.step(20);
+    }
+    let published = backend
+        .transport
+        .sent()
+        .iter()
+        .filter(|(output, _)| *output == Outgoing::Heatmap)
+        .count();
+    assert_eq!(published, 2);
+
+    let heatmap: HeatmapGrid =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::Heatmap).unwrap()).unwrap();
+    assert_eq!((heatmap.columns, heatmap.rows), (4, 4));
+    // 3/4 of the way across the ROI, 1/4 of the way in
+    assert!(heatmap.get(3, 1) > 0.);
+    assert_eq!(heatmap.max(), heatmap.get(3, 1));
+}
+
+#[test]
+fn test_crowd_stats() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        enable_velocity: true,
+        crowd_stats: Some(CrowdStatsSettings {
+            flow_columns: 2,
+            flow_rows: 2,
+            publish_interval: 100,
+            rate_window: 60000,
+        }),
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("crowd-stats", config);
+    // Walk to the right, in the near-right quarter of the ROI
+    for i in 0..10 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((500. + i as f32 * 20., 2000.)));
+        backend.step(20);
+    }
+
+    let stats: CrowdStats =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::CrowdStats).unwrap()).unwrap();
+    assert_eq!(stats.headcount, 1);
+    // 4m x 4m ROI
+    assert!((stats.density - 1. / 16.).abs() < 0.001);
+    assert_eq!(stats.flow_field.cells.len(), 4);
+    let [vx, _vy] = stats.flow_field.cells[1].unwrap();
+    assert!(vx > 0.);
+    assert!(stats.flow_field.cells[0].is_none());
+}
+
+#[test]
+fn test_movement_is_averaged_without_enabling_velocity() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        enable_average_movement: true,
+        average_movement_interval: 100,
+        average_movement_mode: MovementMode::Mean,
+        ..config_with_roi()
+    };
+    assert!(!config.enable_velocity);
+    let mut backend = Backend::new("movement", config);
+    // Two people walking to the right at the same speed
+    for i in 0..10 {
+        let x = i as f32 * 20.;
+        let mut samples = person_samples((-1000. + x, 2000.));
+        samples.extend(person_samples((1000. + x, 3000.)));
+        backend.transport.push_scans("a", &samples);
+        backend.step(20);
+    }
+
+    let (x, y): Point2D =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::Movement).unwrap()).unwrap();
+    // ~1000mm/s each; the mean, not the sum
+    assert!(x > 500. && x < 1500., "{}", x);
+    assert!(y.abs() < 100., "{}", y);
+}
+
+#[test]
+fn test_behaviour_changes_are_published() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        behaviour: Some(BehaviourSettings {
+            window_ms: 200,
+            ..Default::default()
+        }),
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("behaviour", config);
+    for _ in 0..20 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((0., 3000.)));
+        backend.step(20);
+    }
+    let changes: Vec<BehaviourChange> =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::Behaviour).unwrap()).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].behaviour, Some(Behaviour::Stationary));
+    assert_eq!(changes[0].previous, None);
+
+    // Only changes are published
+    let published = |backend: &Backend| {
+        backend
+            .transport
+            .sent()
+            .iter()
+            .filter(|(output, _)| *output == Outgoing::Behaviour)
+            .count()
+    };
+    assert_eq!(published(&backend), 1);
+
+    // Once the point expires
+    for _ in 0..200 {
+        backend.step(20);
+    }
+    assert_eq!(published(&backend), 2);
+    let changes: Vec<BehaviourChange> =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::Behaviour).unwrap()).unwrap();
+    assert_eq!(changes[0].behaviour, None);
+}
+
+#[test]
+fn test_people_walking_together_form_a_group() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        groups: Some(GroupSettings {
+            min_duration_ms: 100,
+            ..Default::default()
+        }),
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("groups", config);
+    // Two people walking side by side, and someone standing still nearby
+    for i in 0..20 {
+        let x = i as f32 * 20.;
+        let mut samples = person_samples((-1000. + x, 2000.));
+        samples.extend(person_samples((-1000. + x, 2600.)));
+        samples.extend(person_samples((200., 2300.)));
+        backend.transport.push_scans("a", &samples);
+        backend.step(20);
+    }
+
+    let groups: Vec<Group> =
+        serde_json::from_slice(backend.transport.last_sent(Outgoing::Groups).unwrap()).unwrap();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].members.len(), 2);
+
+    // The centroid of the two walking together
+    let smoothed: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    let members: Vec<&TrackedPoint2D> = smoothed
+        .iter()
+        .filter(|p| groups[0].members.contains(&p.id))
+        .collect();
+    assert_eq!(members.len(), 2);
+    assert!((groups[0].x - (members[0].x + members[1].x) / 2.).abs() < 1.);
+    assert!((groups[0].y - (members[0].y + members[1].y) / 2.).abs() < 1.);
+}
+
+#[test]
+fn test_predicted_positions_lead_smoothed_points() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        prediction_lookahead_ms: 100,
+        ..config_with_roi()
+    };
+    assert!(!config.enable_velocity);
+    let mut backend = Backend::new("prediction", config);
+    // Walking to the right at ~1000mm/s
+    for i in 0..10 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((i as f32 * 20., 3000.)));
+        backend.step(20);
+    }
+
+    let points: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    let (px, py) = points[0].predicted.unwrap();
+    // ~100mm ahead
+    assert!(px - points[0].x > 50. && px - points[0].x < 150., "{}", px);
+    assert!((py - points[0].y).abs() < 10., "{}", py);
+}
+
+#[test]
+fn test_briefly_lost_track_keeps_its_id() {
+    let config = BackendConfig {
+        output_encoding: Encoding::Json,
+        smoothing_expire_ms: 200,
+        smoothing_reidentify: Some(ReidentifySettings::default()),
+        ..config_with_roi()
+    };
+    let mut backend = Backend::new("reidentify", config);
+    let step_with = |backend: &mut Backend, samples: &[Point2D]| {
+        backend.transport.push_scans("a", samples);
+        backend.step(20);
+    };
+    // Someone else arrives first (ID 0), then the person who will be lost (ID 1)
+    for _ in 0..5 {
+        step_with(&mut backend, &person_samples((-1500., 4500.)));
+    }
+    for _ in 0..5 {
+        let mut samples = person_samples((-1500., 4500.));
+        samples.extend(person_samples((1000., 2000.)));
+        step_with(&mut backend, &samples);
+    }
+
+    // Occluded for longer than the expiry time; meanwhile someone new arrives
+    for _ in 0..20 {
+        step_with(&mut backend, &person_samples((-1500., 4500.)));
+    }
+    for _ in 0..5 {
+        let mut samples = person_samples((-1500., 4500.));
+        samples.extend(person_samples((-1500., 1500.)));
+        step_with(&mut backend, &samples);
+    }
+    assert!(backend
+        .transport
+        .last_sent(Outgoing::TrackReacquired)
+        .is_none());
+
+    // Reappears where they were lost
+    let mut samples = person_samples((-1500., 4500.));
+    samples.extend(person_samples((-1500., 1500.)));
+    samples.extend(person_samples((1000., 2000.)));
+    step_with(&mut backend, &samples);
+
+    let reacquired: Vec<TrackReacquired> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::TrackReacquired)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(reacquired.len(), 1);
+    assert_eq!(reacquired[0].id, 1);
+    assert!(reacquired[0].lost_ms >= 400, "{}", reacquired[0].lost_ms);
+
+    let points: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    let mut ids: Vec<usize> = points.iter().map(|p| p.id).collect();
+    ids.sort();
+    // The newcomer did not take the lost ID
+    assert_eq!(ids, [0, 1, 2]);
+}
+
+#[test]
+fn test_occluded_person_is_held() {
+    let run = |occlusion: Option<OcclusionSettings>| {
+        let config = BackendConfig {
+            output_encoding: Encoding::Json,
+            smoothing_expire_ms: 200,
+            occlusion,
+            ..config_with_roi()
+        };
+        let mut backend = Backend::new("occlusion", config);
+        let step_with = |backend: &mut Backend, samples: &[Point2D]| {
+            backend.transport.push_scans("a", samples);
+            backend.step(20);
+        };
+        for _ in 0..5 {
+            let mut samples = person_samples((0., 1500.));
+            samples.extend(person_samples((0., 3000.)));
+            step_with(&mut backend, &samples);
+        }
+        // The person further away is now hidden behind the nearer one, for longer
+        // than the expiry time
+        for _ in 0..30 {
+            step_with(&mut backend, &person_samples((0., 1500.)));
+        }
+        let points: Vec<TrackedPoint2D> = serde_json::from_slice(
+            backend
+                .transport
+                .last_sent(Outgoing::SmoothedTrackedPoints)
+                .unwrap(),
+        )
+        .unwrap();
+        (backend, points.len())
+    };
+
+    assert_eq!(run(None).1, 1);
+
+    let (mut backend, count) = run(Some(OcclusionSettings::default()));
+    assert_eq!(count, 2);
+
+    // Once the nearer person moves out of the way, and nobody is there, it expires
+    for _ in 0..15 {
+        backend
+            .transport
+            .push_scans("a", &person_samples((1500., 1500.)));
+        backend.step(20);
+    }
+    let points: Vec<TrackedPoint2D> = serde_json::from_slice(
+        backend
+            .transport
+            .last_sent(Outgoing::SmoothedTrackedPoints)
+            .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(points.len(), 1);
+}
+
+#[test]
+fn test_leg_pairs_are_tracked_as_one_person() {
+    // Two legs, each a small cluster, ~300mm apart
+    let legs = |x: f32| {
+        let leg = |cx: f32| {
+            (0..8).map(move |i| {
+                let t = i as f32 / 8. * TAU;
+                let (x, y) = (cx + 50. * t.sin(), 3000. + 50. * t.cos());
+                (x.atan2(y).to_degrees(), (x * x + y * y).sqrt())
+            })
+        };
+        leg(x - 150.).chain(leg(x + 150.)).collect::<Vec<Point2D>>()
+    };
+    let run = |leg_pairing: Option<LegPairSettings>| {
+        let config = BackendConfig {
+            clustering_neighbourhood_radius: 100.,
+            clustering_leg_pairing: leg_pairing,
+            ..config_with_roi()
+        };
+        let mut backend = Backend::new("legs", config);
+        for i in 0..5 {
+            backend.transport.push_scans("a", &legs(i as f32 * 20.));
+            backend.step(20);
+        }
+        backend.last_smoothed().unwrap().len()
+    };
+
+    assert_eq!(run(None), 2);
+    assert_eq!(run(Some(LegPairSettings::default())), 1);
+}
+
+#[test]
+fn test_invalid_config_from_websocket_is_ignored() {
+    let mut transport = WebSocketTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let address = transport.local_addr().unwrap();
+    let mut config = config_with_roi();
+    let mut systems = Systems::new(&config);
+    let config_path = std::env::temp_dir()
+        .join(format!(
+            "lidar2d-backend-websocket-{}.json",
+            std::process::id()
+        ))
+        .to_string_lossy()
+        .to_string();
+
+    let connecting =
+        std::thread::spawn(move || tungstenite::connect(format!("ws://{}", address)).unwrap().0);
+    while transport.client_count() == 0 {
+        assert!(transport.receive().is_none());
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    let mut client = connecting.join().unwrap();
+
+    let handle_next = |transport: &mut WebSocketTransport,
+                       config: &mut BackendConfig,
+                       systems: &mut Systems| loop {
+        if let Some(message) = transport.receive() {
+            handle_incoming(message, config, transport, systems, &config_path);
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+
+    client
+        .send(tungstenite::Message::text(
+            r#"{"topic":"saveLidarConfig","payload":{"bogus":1}}"#,
+        ))
+        .unwrap();
+    handle_next(&mut transport, &mut config, &mut systems);
+    assert!(config.region_of_interest().is_some());
+
+    // Still running, and still handling commands
+    client
+        .send(tungstenite::Message::text(
+            r#"{"topic":"requestAutoMask","payload":{"type":"clear"}}"#,
+        ))
+        .unwrap();
+    handle_next(&mut transport, &mut config, &mut systems);
+    assert_eq!(transport.client_count(), 1);
+
+    let _ = std::fs::remove_file(&config_path);
+}
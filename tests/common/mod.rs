@@ -0,0 +1,34 @@
+//! Fixtures shared by the integration tests
+
+use std::f32::consts::TAU;
+
+use tether_lidar2d_consolidation::{
+    backend_config::{BackendConfig, ConfigRectCornerPoint},
+    Point2D,
+};
+
+/// Default config, plus a 4000x4000 ROI in front of a lidar at the origin
+pub fn roi_config() -> BackendConfig {
+    BackendConfig {
+        region_of_interest: Some((
+            ConfigRectCornerPoint::new(0, -2000., 1000.),
+            ConfigRectCornerPoint::new(1, 2000., 1000.),
+            ConfigRectCornerPoint::new(2, 2000., 5000.),
+            ConfigRectCornerPoint::new(3, -2000., 5000.),
+        )),
+        ..Default::default()
+    }
+}
+
+/// Samples (angle, distance) as seen from a lidar at the origin, for a "person"
+/// (circle of points) centred on the given world position
+pub fn person_samples(centre: Point2D) -> Vec<Point2D> {
+    let (cx, cy) = centre;
+    (0..16)
+        .map(|i| {
+            let t = i as f32 / 16. * TAU;
+            let (x, y) = (cx + 100. * t.sin(), cy + 100. * t.cos());
+            (x.atan2(y).to_degrees(), (x * x + y * y).sqrt())
+        })
+        .collect()
+}
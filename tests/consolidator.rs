@@ -0,0 +1,188 @@
+//! Using the Consolidator facade, without any I/O
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{person_samples, roi_config};
+use tether_lidar2d_consolidation::{
+    backend_config::BackendConfig,
+    consolidator::Consolidator,
+    systems::{crowd_stats::CrowdStatsSettings, heatmap::HeatmapSettings, presence::Zone},
+    Point2D,
+};
+
+fn config() -> BackendConfig {
+    BackendConfig {
+        smoothing_lerp_factor: 1.0,
+        smoothing_wait_before_active_ms: 50,
+        smoothing_expire_ms: 500,
+        zones: Some(vec![serde_json::from_str::<Zone>(
+            r#"{ "id": 1, "x": -500, "y": -500, "width": 1000, "height": 1000 }"#,
+        )
+        .unwrap()]),
+        ..roi_config()
+    }
+}
+
+/// Push a scan and tick, `count` times, every 20ms from `start`; returns the last time used
+fn run(consolidator: &mut Consolidator, start: Instant, count: u64, people: &[Point2D]) -> Instant {
+    let mut now = start;
+    for _ in 0..count {
+        now += Duration::from_millis(20);
+        let samples: Vec<Point2D> = people.iter().flat_map(|p| person_samples(*p)).collect();
+        if !samples.is_empty() {
+            consolidator.push_scan("a", &samples, now);
+        }
+        consolidator.tick(now);
+    }
+    now
+}
+
+#[test]
+fn test_new_device_flags_config_change() {
+    let mut consolidator = Consolidator::new(config());
+    assert!(!consolidator.take_config_changed());
+
+    consolidator.push_scan("a", &person_samples((0., 3000.)), Instant::now());
+    assert_eq!(consolidator.config().devices().len(), 1);
+    assert!(consolidator.take_config_changed());
+    assert!(!consolidator.take_config_changed());
+
+    assert_eq!(consolidator.clusters().len(), 1);
+    assert_eq!(consolidator.tracked_points().len(), 1);
+}
+
+#[test]
+fn test_smoothed_points_and_zone_events() {
+    let mut consolidator = Consolidator::new(config());
+    let start = Instant::now();
+
+    let now = run(&mut consolidator, start, 1, &[(0., 3000.)]);
+    assert!(consolidator.smoothed_points().is_empty());
+
+    let now = run(&mut consolidator, now, 5, &[(0., 3000.)]);
+    let points = consolidator.smoothed_points();
+    assert_eq!(points.len(), 1);
+    assert!(points[0].x.abs() < 1. && points[0].y.abs() < 1.);
+
+    let events = consolidator.zone_events();
+    assert_eq!(events.len(), 1);
+    assert!(events[0].active);
+    assert!(consolidator.zone_events().is_empty());
+
+    // Leave; the point expires and then the zone becomes inactive
+    run(&mut consolidator, now, 60, &[]);
+    assert!(consolidator.smoothed_points().is_empty());
+    let events = consolidator.zone_events();
+    assert_eq!(events.len(), 1);
+    assert!(!events[0].active);
+}
+
+#[test]
+fn test_time_only_moves_with_timestamps() {
+    let mut consolidator = Consolidator::new(config());
+    let start = Instant::now();
+    run(&mut consolidator, start, 10, &[(0., 3000.)]);
+    assert_eq!(consolidator.smoothed_points().len(), 1);
+
+    // However long we wait in real time, the point does not expire until told so
+    std::thread::sleep(Duration::from_millis(10));
+    consolidator.tick(start + Duration::from_millis(200));
+    assert_eq!(consolidator.smoothed_points().len(), 1);
+
+    consolidator.tick(start + Duration::from_millis(1000));
+    assert!(consolidator.smoothed_points().is_empty());
+}
+
+#[test]
+fn test_same_outputs_as_backend() {
+    let mut consolidator = Consolidator::new(BackendConfig {
+        crowd_stats: Some(CrowdStatsSettings::default()),
+        heatmap: Some(HeatmapSettings::default()),
+        prediction_lookahead_ms: 100,
+        ..config()
+    });
+    assert!(consolidator.crowd_stats().is_none());
+    run(&mut consolidator, Instant::now(), 60, &[(0., 3000.)]);
+
+    assert_eq!(consolidator.crowd_stats().unwrap().headcount, 1);
+    let seconds: f32 = consolidator.heatmap().unwrap().cells.iter().sum();
+    assert!(seconds > 1., "{}", seconds);
+    assert!(consolidator.smoothed_points()[0].predicted.is_some());
+    // Not enabled, so not calculated
+    assert_eq!(consolidator.movement(), (0., 0.));
+}
+
+#[test]
+fn test_timestamps_from_before_creation() {
+    // e.g. replaying a recording, made before the Consolidator existed
+    let start = Instant::now();
+    std::thread::sleep(Duration::from_millis(50));
+    let mut consolidator = Consolidator::new(config());
+
+    let now = run(&mut consolidator, start, 1, &[(0., 3000.)]);
+    assert!(consolidator.smoothed_points().is_empty());
+    // Active after waiting 50ms (from the first timestamp, not from creation)
+    let now = run(&mut consolidator, now, 3, &[(0., 3000.)]);
+    assert_eq!(consolidator.smoothed_points().len(), 1);
+
+    // ...and expires 500ms after last being seen
+    consolidator.tick(now + Duration::from_millis(500));
+    assert_eq!(consolidator.smoothed_points().len(), 1);
+    consolidator.tick(now + Duration::from_millis(501));
+    assert!(consolidator.smoothed_points().is_empty());
+}
+
+#[test]
+fn test_apply_config() {
+    let mut consolidator = Consolidator::new(BackendConfig::default());
+    let now = run(&mut consolidator, Instant::now(), 10, &[(0., 3000.)]);
+    // No ROI, so nothing tracked
+    assert!(consolidator.smoothed_points().is_empty());
+
+    consolidator.apply_config(config());
+    run(&mut consolidator, now, 10, &[(0., 3000.)]);
+    assert_eq!(consolidator.smoothed_points().len(), 1);
+}
+
+#[test]
+fn test_automask() {
+    let mut consolidator = Consolidator::new(BackendConfig {
+        automask_scans_required: 2,
+        ..config()
+    });
+    let now = Instant::now();
+    consolidator.push_scan("a", &[(0., 1000.)], now);
+    consolidator.take_config_changed();
+
+    consolidator.request_automask("new").unwrap();
+    consolidator.push_scan("a", &[(0., 1000.)], now);
+    consolidator.push_scan("a", &[(0., 1000.)], now);
+    assert!(consolidator.take_config_changed());
+    let device = consolidator.config().get_device("a").unwrap();
+    assert!(device.scan_mask_thresholds.is_some());
+
+    consolidator.request_automask("clear").unwrap();
+    assert!(consolidator.take_config_changed());
+    assert!(consolidator.request_automask("other").is_err());
+}
+
+#[test]
+fn test_automask_requested_before_first_scan() {
+    let mut config = BackendConfig {
+        automask_scans_required: 2,
+        ..config()
+    };
+    config.check_or_create_device("a", 20.);
+    let mut consolidator = Consolidator::new(config);
+
+    consolidator.request_automask("new").unwrap();
+    consolidator.take_config_changed();
+    let now = Instant::now();
+    consolidator.push_scan("a", &[(0., 1000.)], now);
+    consolidator.push_scan("a", &[(0., 1000.)], now);
+    assert!(consolidator.take_config_changed());
+    let device = consolidator.config().get_device("a").unwrap();
+    assert!(device.scan_mask_thresholds.is_some());
+}
@@ -0,0 +1,158 @@
+//! End-to-end tests: synthetic scans in, smoothed tracked points out
+
+mod common;
+
+use common::{person_samples, roi_config};
+use tether_lidar2d_consolidation::{
+    backend_config::BackendConfig,
+    clock::ManualClock,
+    systems::{position_remapping::OriginLocation, smoothing::EmptyListSendMode, Systems},
+    tracking::TrackedPoint2D,
+    Point2D,
+};
+
+const SERIAL: &str = "lidar";
+const TICK_MS: u64 = 16;
+
+fn config() -> BackendConfig {
+    let mut config = BackendConfig {
+        origin_location: OriginLocation::Corner,
+        smoothing_empty_send_mode: EmptyListSendMode::Always,
+        smoothing_wait_before_active_ms: 100,
+        smoothing_expire_ms: 500,
+        smoothing_lerp_factor: 0.5,
+        enable_velocity: true,
+        ..roi_config()
+    };
+    config.check_or_create_device(SERIAL, 20.);
+    config
+}
+
+/// What the backend does for each scan message, minus the I/O
+fn process_scan(systems: &mut Systems, config: &BackendConfig, samples: &[Point2D]) {
+    let device = config.get_device(SERIAL).unwrap();
+    systems.clustering_system.update_from_scan(samples, device);
+    let transformed = systems
+        .position_remapping
+        .transform_clusters(systems.clustering_system.clusters());
+    let inside = systems
+        .position_remapping
+        .filter_clusters_inside(&transformed);
+    systems.smoothing_system.update_tracked_points(&inside);
+}
+
+fn tick(
+    systems: &mut Systems,
+    config: &BackendConfig,
+    clock: &ManualClock,
+    people: &[Point2D],
+) -> Vec<TrackedPoint2D> {
+    clock.advance_ms(TICK_MS);
+    if !people.is_empty() {
+        let samples: Vec<Point2D> = people.iter().flat_map(|p| person_samples(*p)).collect();
+        process_scan(systems, config, &samples);
+    }
+    systems.smoothing_system.update_smoothing(TICK_MS);
+    systems
+        .smoothing_system
+        .get_active_smoothed_points()
+        .unwrap_or_default()
+}
+
+fn assert_near(p: &TrackedPoint2D, expected: Point2D) {
+    assert!(
+        (p.x - expected.0).abs() < 1.0 && (p.y - expected.1).abs() < 1.0,
+        "({}, {}) != {:?}",
+        p.x,
+        p.y,
+        expected
+    );
+}
+
+#[test]
+fn test_single_person_is_tracked_in_roi_coordinates() {
+    let config = config();
+    let clock = ManualClock::new();
+    let mut systems = Systems::with_clock(&config, clock.shared());
+
+    // Not active until the wait time has passed
+    let points = tick(&mut systems, &config, &clock, &[(0., 3000.)]);
+    assert!(points.is_empty());
+
+    let mut points = Vec::new();
+    for _ in 0..50 {
+        points = tick(&mut systems, &config, &clock, &[(0., 3000.)]);
+    }
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].id, 0);
+    // World (0, 3000) is the centre of a 4000x4000 ROI with its corner A at (-2000, 1000)
+    assert_near(&points[0], (2000., 2000.));
+    let [vx, vy] = points[0].velocity.unwrap();
+    assert!(vx.abs() < 1. && vy.abs() < 1.);
+}
+
+#[test]
+fn test_moving_person_is_smoothed() {
+    let config = config();
+    let clock = ManualClock::new();
+    let mut systems = Systems::with_clock(&config, clock.shared());
+
+    for _ in 0..20 {
+        tick(&mut systems, &config, &clock, &[(-1000., 3000.)]);
+    }
+    // Jump (within the smoothing match distance), and expect to lag behind
+    let points = tick(&mut systems, &config, &clock, &[(-900., 3000.)]);
+    assert_eq!(points.len(), 1);
+    assert!(points[0].x > 1000. && points[0].x < 1100.);
+    assert!(points[0].velocity.unwrap()[0] > 0.);
+
+    let mut points = Vec::new();
+    for _ in 0..30 {
+        points = tick(&mut systems, &config, &clock, &[(-900., 3000.)]);
+    }
+    assert_near(&points[0], (1100., 2000.));
+}
+
+#[test]
+fn test_people_outside_roi_are_ignored() {
+    let config = config();
+    let clock = ManualClock::new();
+    let mut systems = Systems::with_clock(&config, clock.shared());
+
+    let mut points = Vec::new();
+    for _ in 0..20 {
+        points = tick(
+            &mut systems,
+            &config,
+            &clock,
+            &[(1000., 2000.), (3000., 3000.)],
+        );
+    }
+    assert_eq!(points.len(), 1);
+    assert_near(&points[0], (3000., 1000.));
+}
+
+#[test]
+fn test_several_people_arrive_and_leave() {
+    let config = config();
+    let clock = ManualClock::new();
+    let mut systems = Systems::with_clock(&config, clock.shared());
+    let people = [(-1500., 2000.), (0., 3000.), (1500., 4000.)];
+
+    let mut points = Vec::new();
+    for _ in 0..20 {
+        points = tick(&mut systems, &config, &clock, &people);
+    }
+    let mut ids: Vec<usize> = points.iter().map(|p| p.id).collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 1, 2]);
+
+    // Everybody leaves at once; all points expire together
+    let expire_ticks = config.smoothing_expire_ms as u64 / TICK_MS;
+    for _ in 0..expire_ticks {
+        points = tick(&mut systems, &config, &clock, &[]);
+    }
+    assert_eq!(points.len(), 3);
+    points = tick(&mut systems, &config, &clock, &[]);
+    assert!(points.is_empty());
+}